@@ -0,0 +1,103 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How serious an alert is, based on which level of its rule was exceeded.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum AlertSeverity {
+    Warning,
+    Critical
+}
+impl Display for AlertSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Warning => "warning",
+                Self::Critical => "critical"
+            }
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum AlertState {
+    /// A level is exceeded, but not for long enough to fire yet.
+    Pending,
+    Firing,
+    /// The value returned within the levels after firing.
+    Resolved
+}
+impl Display for AlertState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Pending => "pending",
+                Self::Firing => "firing",
+                Self::Resolved => "resolved"
+            }
+        )
+    }
+}
+
+/// The alert raised by a rule for a specific series.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Alert {
+    /// The name of the rule that raised the alert
+    pub rule: String,
+    /// The series that exceeded the rule, keyed as `measurement.field{tag=value,...}`
+    pub series: String,
+    pub severity: AlertSeverity,
+    pub state: AlertState,
+    /// The most recent value of the series
+    pub value: f64,
+    /// When a level was first exceeded
+    pub since: DateTime<Utc>,
+    /// When the alert started firing
+    pub fired: Option<DateTime<Utc>>,
+    /// When the alert was resolved
+    pub resolved: Option<DateTime<Utc>>
+}
+impl Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} '{}' on {} = {:.2} (since {}", self.state, self.severity, &self.rule, &self.series, self.value, self.since)?;
+        if let Some(resolved) = self.resolved {
+            write!(f, ", resolved {resolved}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A period where a series deviated from the baseline learned from its own history.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Anomaly {
+    /// The series that deviated, keyed as `measurement.field{tag=value,...}`
+    pub series: String,
+    /// When the series first deviated
+    pub start: DateTime<Utc>,
+    /// When the series returned to its baseline, if it has
+    pub end: Option<DateTime<Utc>>,
+    /// The value that deviated the most
+    pub value: f64,
+    /// The mean of the baseline when `value` was recorded
+    pub expected: f64,
+    /// The standard deviation of the baseline when `value` was recorded
+    pub deviation: f64,
+    /// How many standard deviations `value` was from `expected`. This is negative when the value was below it.
+    pub score: f64,
+    /// How many snapshots deviated
+    pub samples: u64
+}
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = {:.2}, expected {:.2} ± {:.2} ({:+.1}σ) from {}", &self.series, self.value, self.expected, self.deviation, self.score, self.start)?;
+        match self.end {
+            Some(end) => write!(f, " to {end}"),
+            None => write!(f, " (ongoing)")
+        }
+    }
+}
@@ -2,19 +2,562 @@ use serde::{Serialize, Deserialize};
 
 use lazy_static::lazy_static;
 
-use crate::{loc::CLIENTS_PORT, metric::Utilization};
+use crate::{alert::{Alert, AlertSeverity, AlertState}, loc::{CLIENTS_PORT, DAEMON_HISTORY_DIR, PROMETHEUS_PORT}, metric::{MetricFamily, Resolution, Utilization}};
 use exdisj::io::config::ConfigurationProvider;
 
 use std::fmt::Display;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DaemonConfig {
     pub max_console: u8,
     pub max_hosts: u8,
     pub hosts_port: u16,
+    /// In seconds, how frequently a snapshot of the metrics is recorded.
     pub metric_freq: u64,
+    /// Which metric families are collected, and how frequently.
+    #[serde(default)]
+    pub collectors: CollectorsConfig,
+    /// The cgroups (relative to the root of the cgroup v2 hierarchy) whose resource usage is collected. Each one is reported along with its direct children, so `system.slice` covers every systemd service.
+    #[serde(default = "default_cgroups")]
+    pub cgroups: Vec<String>,
+    /// Settings for specific plugins in the plugin directory. Plugins without an entry are run with the default settings.
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    /// The HTTP endpoint that exposes the latest snapshot to Prometheus.
+    #[serde(default)]
+    pub prometheus: PrometheusConfig,
+    /// The time series databases that every snapshot is pushed to.
+    #[serde(default)]
+    pub exporters: Vec<ExporterConfig>,
+    /// Where snapshots evicted from memory are kept, and for how long.
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// The downsampled series kept alongside the raw history.
+    #[serde(default)]
+    pub rollups: RollupConfig,
+    /// The thresholds that every snapshot is checked against.
+    #[serde(default = "default_alert_rules")]
+    pub alerts: Vec<AlertRule>,
+    /// Where alerts are delivered when they change state.
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    /// Which series are compared against their own baselines, and how.
+    #[serde(default)]
+    pub anomalies: AnomalyConfig,
+    /// How the time until filesystems & memory are full is projected.
+    #[serde(default)]
+    pub forecast: ForecastConfig,
+}
+/// The settings for a specific metric family.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CollectorConfig {
+    #[serde(default = "default_collector_enabled")]
+    pub enabled: bool,
+    /// In seconds, how frequently the family is sampled. When this is not set, the family is sampled for every snapshot.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// In seconds, how long a collection may take before it is abandoned & reported as timed out.
+    #[serde(default = "default_collector_timeout")]
+    pub timeout: u64
+}
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_collector_enabled(),
+            interval: None,
+            timeout: default_collector_timeout()
+        }
+    }
+}
+impl CollectorConfig {
+    pub fn every(interval: u64) -> Self {
+        Self {
+            interval: Some(interval),
+            ..Default::default()
+        }
+    }
+}
+fn default_collector_enabled() -> bool {
+    true
+}
+fn default_collector_timeout() -> u64 {
+    5
+}
+
+/// The settings for every metric family. Families not listed in the configuration file use their defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct CollectorsConfig {
+    pub cpu: CollectorConfig,
+    pub memory: CollectorConfig,
+    pub storage: CollectorConfig,
+    pub disk_io: CollectorConfig,
+    pub network: CollectorConfig,
+    pub sensors: CollectorConfig,
+    pub cgroups: CollectorConfig,
+    pub pressure: CollectorConfig,
+    pub plugins: CollectorConfig
+}
+impl Default for CollectorsConfig {
+    fn default() -> Self {
+        Self {
+            cpu: CollectorConfig::default(),
+            memory: CollectorConfig::default(),
+            // Walking every mount is comparatively expensive, and rarely changes quickly.
+            storage: CollectorConfig::every(60),
+            disk_io: CollectorConfig::default(),
+            network: CollectorConfig::default(),
+            sensors: CollectorConfig::default(),
+            cgroups: CollectorConfig::default(),
+            pressure: CollectorConfig::default(),
+            plugins: CollectorConfig::default()
+        }
+    }
+}
+impl CollectorsConfig {
+    pub fn get(&self, family: MetricFamily) -> &CollectorConfig {
+        match family {
+            MetricFamily::Cpu => &self.cpu,
+            MetricFamily::Memory => &self.memory,
+            MetricFamily::Storage => &self.storage,
+            MetricFamily::DiskIo => &self.disk_io,
+            MetricFamily::Network => &self.network,
+            MetricFamily::Sensors => &self.sensors,
+            MetricFamily::Cgroups => &self.cgroups,
+            MetricFamily::Pressure => &self.pressure,
+            MetricFamily::Plugins => &self.plugins
+        }
+    }
+    pub fn get_mut(&mut self, family: MetricFamily) -> &mut CollectorConfig {
+        match family {
+            MetricFamily::Cpu => &mut self.cpu,
+            MetricFamily::Memory => &mut self.memory,
+            MetricFamily::Storage => &mut self.storage,
+            MetricFamily::DiskIo => &mut self.disk_io,
+            MetricFamily::Network => &mut self.network,
+            MetricFamily::Sensors => &mut self.sensors,
+            MetricFamily::Cgroups => &mut self.cgroups,
+            MetricFamily::Pressure => &mut self.pressure,
+            MetricFamily::Plugins => &mut self.plugins
+        }
+    }
+}
+
+fn default_cgroups() -> Vec<String> {
+    vec!["system.slice".to_string()]
+}
+
+/// Determines how regisd runs a plugin.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PluginMode {
+    /// The plugin is started on every metric interval, and must exit after writing its output.
+    #[default]
+    Interval,
+    /// The plugin is started once and kept alive. It is sent a request on every metric interval, and must respond with a single line.
+    Persistent
+}
+
+/// The settings for a specific plugin.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PluginConfig {
+    /// The file name of the plugin, within the plugin directory.
+    pub name: String,
+    #[serde(default = "default_plugin_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub mode: PluginMode,
+    /// In seconds, how long the plugin has to respond before it is killed.
+    #[serde(default = "default_plugin_timeout")]
+    pub timeout: u64
+}
+impl PluginConfig {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            enabled: default_plugin_enabled(),
+            mode: PluginMode::default(),
+            timeout: default_plugin_timeout()
+        }
+    }
+}
+fn default_plugin_enabled() -> bool {
+    true
+}
+fn default_plugin_timeout() -> u64 {
+    5
+}
+/// The settings for the Prometheus metrics endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct PrometheusConfig {
+    pub enabled: bool,
+    /// The address the endpoint listens on. Unless an allowlist or token is set, this must be a loopback address.
+    pub bind: IpAddr,
+    pub port: u16,
+    /// The addresses allowed to scrape the endpoint. When this is empty, any address that can reach it is allowed.
+    pub allowlist: Vec<IpAddr>,
+    /// When set, scrapers must send this as a bearer token.
+    pub token: Option<String>
+}
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: PROMETHEUS_PORT,
+            allowlist: vec![],
+            token: None
+        }
+    }
+}
+impl PrometheusConfig {
+    /// Determines if a scraper at `addr` may read the endpoint.
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|x| x == addr || x.to_canonical() == addr.to_canonical())
+    }
+
+    /// Determines if the endpoint would be open to any host that can reach it, with neither an allowlist nor a token restricting it.
+    pub fn is_unrestricted(&self) -> bool {
+        self.allowlist.is_empty() && self.token.is_none() && !self.bind.is_loopback()
+    }
+}
+
+/// The settings for the on-disk metric history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// The directory holding the segment files.
+    pub dir: PathBuf,
+    /// In bytes, how large a segment file may grow before a new one is started.
+    pub segment_size: u64,
+    /// In bytes, the most history that is kept. The oldest segments are removed first.
+    pub max_size: u64,
+    /// In seconds, how long history is kept. Segments are removed once all of their snapshots are older than this.
+    pub max_age: Option<u64>
+}
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from(DAEMON_HISTORY_DIR),
+            segment_size: 8 * 1024 * 1024,
+            max_size: 256 * 1024 * 1024,
+            max_age: Some(7 * 24 * 60 * 60)
+        }
+    }
+}
+
+/// The settings for the rollups, which summarize every series at coarser resolutions. They are stored within the history directory, but their size is limited separately from the history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct RollupConfig {
+    pub enabled: bool,
+    /// In seconds, how long the one minute rollups are kept.
+    pub minute_retention: u64,
+    /// In seconds, how long the one hour rollups are kept.
+    pub hour_retention: u64,
+    /// In bytes, the most one minute rollups that are kept. The oldest segments are removed first.
+    pub minute_max_size: u64,
+    /// In bytes, the most one hour rollups that are kept. The oldest segments are removed first.
+    pub hour_max_size: u64
+}
+impl Default for RollupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            minute_retention: 7 * 24 * 60 * 60,
+            hour_retention: 365 * 24 * 60 * 60,
+            minute_max_size: 64 * 1024 * 1024,
+            hour_max_size: 32 * 1024 * 1024
+        }
+    }
 }
+impl RollupConfig {
+    /// In seconds, how long the rollups of a specific resolution are kept.
+    pub fn retention(&self, resolution: Resolution) -> Option<u64> {
+        match resolution {
+            Resolution::Raw => None,
+            Resolution::Minute => Some(self.minute_retention),
+            Resolution::Hour => Some(self.hour_retention)
+        }
+    }
+
+    /// In bytes, the most rollups of a specific resolution that are kept.
+    pub fn max_size(&self, resolution: Resolution) -> Option<u64> {
+        match resolution {
+            Resolution::Raw => None,
+            Resolution::Minute => Some(self.minute_max_size),
+            Resolution::Hour => Some(self.hour_max_size)
+        }
+    }
+}
+
+/// The format & transport used to push snapshots to a time series database.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExportProtocol {
+    /// InfluxDB line protocol, sent as an HTTP write request.
+    InfluxHttp,
+    /// InfluxDB line protocol, sent as UDP datagrams.
+    InfluxUdp,
+    /// Graphite plaintext, sent over TCP.
+    Graphite
+}
+
+/// The settings for a specific export sink.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExporterConfig {
+    /// A unique name for the sink, used in the logs and for its spill queue.
+    pub name: String,
+    pub protocol: ExportProtocol,
+    /// The `host:port` of the sink.
+    pub address: String,
+    /// For InfluxDB over HTTP, the path & query of the write request, such as `/api/v2/write?org=ops&bucket=regis`. The precision is always nanoseconds.
+    #[serde(default = "default_influx_path")]
+    pub path: String,
+    /// For InfluxDB over HTTP, the API token sent with each request.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// For Graphite, the prefix of every metric path.
+    #[serde(default = "default_graphite_prefix")]
+    pub prefix: String,
+    /// How many snapshots are sent together.
+    #[serde(default = "default_export_batch")]
+    pub batch: usize,
+    /// In bytes, how much unsent data is kept on disk while the sink is unreachable. The oldest data is discarded first.
+    #[serde(default = "default_spill_limit")]
+    pub spill_limit: u64
+}
+impl ExporterConfig {
+    pub fn new(name: String, protocol: ExportProtocol, address: String) -> Self {
+        Self {
+            name,
+            protocol,
+            address,
+            path: default_influx_path(),
+            token: None,
+            prefix: default_graphite_prefix(),
+            batch: default_export_batch(),
+            spill_limit: default_spill_limit()
+        }
+    }
+}
+fn default_influx_path() -> String {
+    "/write?db=regis&precision=ns".to_string()
+}
+fn default_graphite_prefix() -> String {
+    "regis".to_string()
+}
+fn default_export_batch() -> usize {
+    5
+}
+fn default_spill_limit() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// Determines which side of a level an alert rule fires on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AlertComparison {
+    /// The rule fires when the value rises above a level.
+    #[default]
+    Above,
+    /// The rule fires when the value falls below a level.
+    Below
+}
+impl AlertComparison {
+    /// Determines if `value` is past `level`.
+    pub fn exceeds(&self, value: f64, level: f64) -> bool {
+        match self {
+            Self::Above => value > level,
+            Self::Below => value < level
+        }
+    }
+    /// Determines if `value` is still past `level`, once it has been exceeded. The value must return `hysteresis` beyond the level to clear it.
+    pub fn holds(&self, value: f64, level: f64, hysteresis: f64) -> bool {
+        match self {
+            Self::Above => value > level - hysteresis,
+            Self::Below => value < level + hysteresis
+        }
+    }
+}
+
+/// A threshold on a metric series. Every series matched by the selector is checked separately, on every snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    /// A unique name for the rule.
+    pub name: String,
+    /// The series to check, named as `measurement.field` and optionally narrowed by tags, such as `storage.used_percent{mount=/}`.
+    pub selector: String,
+    #[serde(default)]
+    pub comparison: AlertComparison,
+    /// The level that raises a warning.
+    #[serde(default)]
+    pub warn: Option<f64>,
+    /// The level that raises a critical alert.
+    #[serde(default)]
+    pub critical: Option<f64>,
+    /// In seconds, how long a level must be exceeded before the alert fires.
+    #[serde(default, rename = "for")]
+    pub for_secs: u64,
+    /// How far the value must return past a level before that level is cleared, so that a value hovering around a level does not keep firing & resolving.
+    #[serde(default)]
+    pub hysteresis: f64
+}
+impl AlertRule {
+    pub fn new(name: String, selector: String, warn: Option<f64>, critical: Option<f64>) -> Self {
+        Self {
+            name,
+            selector,
+            comparison: AlertComparison::default(),
+            warn,
+            critical,
+            for_secs: 0,
+            hysteresis: 0.0
+        }
+    }
+}
+fn default_alert_rules() -> Vec<AlertRule> {
+    let mut result: Vec<AlertRule> = [("cpu", "cpu.usage{core=all}"), ("memory", "memory.used_percent")]
+        .into_iter()
+        .map(|(name, selector)| {
+            AlertRule {
+                for_secs: 60,
+                hysteresis: 5.0,
+                ..AlertRule::new(name.to_string(), selector.to_string(), Some(70.0), Some(90.0))
+            }
+        })
+        .collect();
+
+    // Fires when a filesystem is projected to be full within two weeks (or three days, for critical).
+    result.push(
+        AlertRule {
+            comparison: AlertComparison::Below,
+            hysteresis: 1.0,
+            ..AlertRule::new("disk-full".to_string(), "storage.days_to_full".to_string(), Some(14.0), Some(3.0))
+        }
+    );
+
+    result
+}
+
+/// The destination of alert notifications.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NotifierTarget {
+    /// POSTs the alert as JSON to a plain HTTP URL, such as `http://hooks.local:8080/alerts`. When a secret is set, the body is signed with HMAC-SHA256, and the signature is sent in the `X-Regis-Signature` header.
+    Webhook { url: String, secret: Option<String> },
+    /// Sends an email through an SMTP relay at `server` (`host:port`). The relay must accept mail without TLS or authentication, such as a local MTA.
+    Email { server: String, from: String, to: Vec<String> },
+    /// Runs a local program, with the alert in `REGIS_*` environment variables.
+    Exec { program: PathBuf, args: Vec<String> }
+}
+
+/// The settings for a specific notification sink.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NotifierConfig {
+    /// A unique name for the sink, used in the logs.
+    pub name: String,
+    pub target: NotifierTarget,
+    /// The alert states that are delivered.
+    #[serde(default = "default_notify_states")]
+    pub states: Vec<AlertState>,
+    /// The least severe alerts that are delivered.
+    #[serde(default = "default_notify_severity")]
+    pub min_severity: AlertSeverity,
+    /// How many times a failed delivery is retried before it is discarded.
+    #[serde(default = "default_notify_retries")]
+    pub retries: u32,
+    /// The most notifications delivered in an hour. Any more are discarded, so that a flood of alerts does not flood the sink.
+    #[serde(default = "default_notify_rate")]
+    pub max_per_hour: u32
+}
+impl NotifierConfig {
+    pub fn new(name: String, target: NotifierTarget) -> Self {
+        Self {
+            name,
+            target,
+            states: default_notify_states(),
+            min_severity: default_notify_severity(),
+            retries: default_notify_retries(),
+            max_per_hour: default_notify_rate()
+        }
+    }
+
+    /// Determines if an alert should be delivered to this sink.
+    pub fn accepts(&self, alert: &Alert) -> bool {
+        self.states.contains(&alert.state) && alert.severity >= self.min_severity
+    }
+}
+fn default_notify_states() -> Vec<AlertState> {
+    vec![AlertState::Firing, AlertState::Resolved]
+}
+fn default_notify_severity() -> AlertSeverity {
+    AlertSeverity::Warning
+}
+fn default_notify_retries() -> u32 {
+    5
+}
+fn default_notify_rate() -> u32 {
+    30
+}
+
+/// The settings for anomaly detection, which compares every value of a series to a baseline learned from its own history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    /// The series that baselines are kept for, selected like the series of an alert rule. Every series is covered when this is empty.
+    pub selectors: Vec<String>,
+    /// In snapshots, the half-life of the baselines. Larger values make the baselines adapt to lasting changes more slowly.
+    pub half_life: u64,
+    /// How many standard deviations away from its baseline a value must be to be anomalous.
+    pub sigma: f64,
+    /// How many snapshots a baseline must learn from before values are compared against it.
+    pub warmup: u64,
+    /// Keeps a separate baseline for every hour of the day (in local time), so that a daily cycle is not anomalous.
+    pub hourly: bool
+}
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            selectors: vec![
+                "cpu.usage{core=all}".to_string(),
+                "load.one".to_string(),
+                "memory.used_percent".to_string(),
+                "disk.utilization".to_string(),
+                "network.rx_bytes_per_second".to_string(),
+                "network.tx_bytes_per_second".to_string()
+            ],
+            half_life: 1200,
+            sigma: 4.0,
+            warmup: 600,
+            hourly: false
+        }
+    }
+}
+
+/// The settings for capacity forecasts. The projected days until full are available to alert rules as the `storage.days_to_full` & `memory.days_to_full` series.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ForecastConfig {
+    pub enabled: bool,
+    /// In seconds, how much of the history the trends are fitted to.
+    pub window: u64,
+    /// The least confidence (from 0 to 1) of a forecast for its days until full to be used by alert rules. Less certain forecasts are treated as not filling.
+    pub min_confidence: f64
+}
+impl Default for ForecastConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 7 * 24 * 60 * 60,
+            min_confidence: 0.5
+        }
+    }
+}
+
 impl Default for DaemonConfig {
     fn default() -> Self {
         Self {
@@ -22,6 +565,17 @@ impl Default for DaemonConfig {
             max_hosts: 6,
             hosts_port: CLIENTS_PORT,
             metric_freq: 3,
+            collectors: CollectorsConfig::default(),
+            cgroups: default_cgroups(),
+            plugins: vec![],
+            prometheus: PrometheusConfig::default(),
+            exporters: vec![],
+            history: HistoryConfig::default(),
+            rollups: RollupConfig::default(),
+            alerts: default_alert_rules(),
+            notifiers: vec![],
+            anomalies: AnomalyConfig::default(),
+            forecast: ForecastConfig::default(),
         }
     }
 }
@@ -1,6 +1,7 @@
 pub mod loc;
 pub mod msg;
 pub mod metric;
+pub mod alert;
 pub mod usr;
 pub mod config;
 pub mod err;
@@ -4,6 +4,11 @@ pub const DAEMON_CONFIG_PATH: &str = "/etc/regis/regisd/config.json";
 pub const DAEMON_AUTH_DIR: &str = "/etc/regis/regisd/auth/";
 pub const DAEMON_AUTH_USERS_PATH: &str = "/etc/regis/regisd/auth/users.json";
 pub const DAEMON_AUTH_KEY_PATH: &str = "/etc/regis/regisd/auth/key";
+pub const DAEMON_PLUGIN_DIR: &str = "/etc/regis/plugins/";
+pub const DAEMON_SPILL_DIR: &str = "/etc/regis/regisd/spill/";
+pub const DAEMON_HISTORY_DIR: &str = "/etc/regis/regisd/history/";
+pub const DAEMON_ALERTS_PATH: &str = "/etc/regis/regisd/alerts.json";
+pub const DAEMON_ANOMALIES_PATH: &str = "/etc/regis/regisd/anomalies.json";
 pub const PID_PATH: &str = "/etc/regis/regisd/pid";
 pub const COMM_DIR: &str = "/run/regis/";
 pub const COMM_PATH: &str = "/run/regis/regis.sock";
@@ -11,6 +16,8 @@ pub const COMM_PATH: &str = "/run/regis/regis.sock";
 /// Represents the default hosts port used by regis.
 pub const CLIENTS_PORT: u16 = 1026;
 pub const BROADCAST_PORT: u16 = 1027;
+/// The default port of the Prometheus metrics endpoint.
+pub const PROMETHEUS_PORT: u16 = 1028;
 
 use std::path::PathBuf;
 use std::env;
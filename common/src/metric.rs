@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Display};
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
@@ -6,15 +7,31 @@ pub use exdisj::io::metric::{Utilization, BinaryNumber, BinaryScale, PrettyPrint
 
 pub trait Metric: PartialEq + Debug + Clone + Serialize { }
 
+/// An exact number of bytes (or bytes per second), shown as a binary number. The count is kept as is, so that exporters & analysis can use plain values.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct ByteCount(pub u64);
+impl Display for ByteCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&BinaryNumber::parse(self.0), f)
+    }
+}
+impl ByteCount {
+    /// The number of bytes, as a plain value.
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64
+    }
+}
+
 /// Stores the information about a specific memory section. 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize, Default)]
 pub struct MemoryMetric {
     pub device: String,
-    pub total: BinaryNumber,
-    pub free: BinaryNumber,
-    pub available: BinaryNumber,
-    pub buff: BinaryNumber,
-    pub cached: BinaryNumber
+    pub total: ByteCount,
+    pub free: ByteCount,
+    pub available: ByteCount,
+    pub buff: ByteCount,
+    pub cached: ByteCount
 }
 impl Metric for MemoryMetric {}
 
@@ -26,37 +43,93 @@ pub struct StorageMetric {
     /// The mount point
     pub mount: String,
     /// The total size
-    pub size: BinaryNumber,
+    pub size: ByteCount,
     /// The used space
-    pub used: BinaryNumber,
+    pub used: ByteCount,
     /// How much space is availiable
-    pub availiable: BinaryNumber,
+    pub availiable: ByteCount,
     /// The utilization of the drive
     pub capacity: Utilization,
 }
 impl Metric for StorageMetric {}
 
+/// Stores the throughput and latency of a specific block device over a sampling interval.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct DiskIoMetric {
+    /// The name of the block device
+    pub device: String,
+    /// Completed reads per second
+    pub read_iops: f64,
+    /// Completed writes per second
+    pub write_iops: f64,
+    /// Bytes read per second
+    pub read_rate: ByteCount,
+    /// Bytes written per second
+    pub write_rate: ByteCount,
+    /// The average time (in milliseconds) a read took, including queueing
+    pub read_await: f64,
+    /// The average time (in milliseconds) a write took, including queueing
+    pub write_await: f64,
+    /// The percentage of time the device had IO in progress
+    pub utilization: f64
+}
+impl Metric for DiskIoMetric {}
+
+/// Stores the percentage (0-100) of time a processor spent in each state over a sampling interval.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize, Default)]
+pub struct CpuUsage {
+    /// Time spent on user processes
+    pub user: f64,
+    /// Time spent on system processes
+    pub system: f64,
+    /// Time spent on elevated (niced) processes
+    pub nice: f64,
+    /// Time spent unused
+    pub idle: f64,
+    /// Time spent waiting for IO
+    pub iowait: f64,
+    /// Time spent servicing hardware interrupts
+    pub irq: f64,
+    /// Time spent servicing software interrupts
+    pub softirq: f64,
+    /// Time stolen by the hypervisor for other virtual environments
+    pub steal: f64
+}
+
+/// Stores the utilization of a specific processor core.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct CpuCoreMetric {
+    /// The index of the core, as reported by the OS
+    pub id: u32,
+    /// The utilization of the core
+    pub usage: CpuUsage
+}
+
+/// Stores the 1, 5, and 15 minute system load averages.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize, Default)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64
+}
+
 /// Stores the information about CPU utilization
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct CpuMetric {
-    /// How much of the processor is used for user processes
-    pub user: Utilization,
-    /// How much of the processor is used for system processes
-    pub system: Utilization,
-    /// How much of the processor is used for elevated processes
-    pub nice: Utilization,
-    /// How much of the processor is being unused.
-    pub idle: Utilization,
-    /// The time the processor spends waiting for IO
-    pub waiting: u16,
-    /// The time the processor spends handling virtual environments 
-    pub steal: u16
+    /// The utilization across all cores
+    pub total: CpuUsage,
+    /// The utilization of each core
+    pub cores: Vec<CpuCoreMetric>,
+    /// The load averages, if they are availiable
+    pub load: Option<LoadAverage>
 }
 impl Metric for CpuMetric {}
 
 /// Stores the information for either the receive or transmitting section of the network. 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct NetworkMetricSection {
+    /// How many bytes were transfered
+    pub bytes: u64,
     /// How mahy packets were OK
     pub ok: u64,
     /// How many packets had errors
@@ -68,11 +141,12 @@ pub struct NetworkMetricSection {
 }
 impl TryFrom<Vec<u64>> for NetworkMetricSection {
     type Error = ();
-    /// Attempts to build this structure from raw values. It returns error if there is not at least 4 elements. 
+    /// Attempts to build this structure from raw values, in the order bytes, ok, err, drop, overrun. It returns error if there is not at least 5 elements. 
     fn try_from(value: Vec<u64>) -> Result<Self, Self::Error> {
         let mut iter = value.into_iter();
         Ok(
             Self {
+                bytes: iter.next().ok_or(())?,
                 ok: iter.next().ok_or(())?,
                 err: iter.next().ok_or(())?,
                 drop: iter.next().ok_or(())?,
@@ -81,6 +155,30 @@ impl TryFrom<Vec<u64>> for NetworkMetricSection {
         )
     }
 }
+impl NetworkMetricSection {
+    /// Determines the transfer rate since a previous reading of the same section. If the counters went backwards (the link was reset), this returns `None`.
+    pub fn rate_since(&self, prev: &Self, elapsed_secs: f64) -> Option<NetworkRate> {
+        if elapsed_secs <= 0.0 || self.bytes < prev.bytes || self.ok < prev.ok {
+            return None;
+        }
+
+        Some(
+            NetworkRate {
+                bytes: ByteCount(((self.bytes - prev.bytes) as f64 / elapsed_secs) as u64),
+                packets: (self.ok - prev.ok) as f64 / elapsed_secs
+            }
+        )
+    }
+}
+
+/// The transfer rate of either the receive or transmitting section of a link, between two snapshots.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct NetworkRate {
+    /// Bytes per second
+    pub bytes: ByteCount,
+    /// Packets per second
+    pub packets: f64
+}
 
 /// Represents a snapshot of network activity through one specific link.
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
@@ -92,17 +190,477 @@ pub struct NetworkMetric {
     /// The receiving information
     pub rx: NetworkMetricSection,
     /// The sending information 
-    pub tx: NetworkMetricSection
+    pub tx: NetworkMetricSection,
+    /// The receiving rate since the previous snapshot, if there was one
+    pub rx_rate: Option<NetworkRate>,
+    /// The sending rate since the previous snapshot, if there was one
+    pub tx_rate: Option<NetworkRate>
 }
 impl Metric for NetworkMetric { }
+impl NetworkMetric {
+    /// Fills in the receiving & sending rates, using the same link from a previous snapshot.
+    pub fn compute_rates(&mut self, prev: &Self, elapsed_secs: f64) {
+        self.rx_rate = self.rx.rate_since(&prev.rx, elapsed_secs);
+        self.tx_rate = self.tx.rate_since(&prev.tx, elapsed_secs);
+    }
+}
+
+/// The kind of reading provided by a hardware sensor.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum SensorKind {
+    /// Measured in degrees Celsius
+    Temperature,
+    /// Measured in RPM
+    Fan,
+    /// Measured in volts
+    Voltage
+}
+impl Display for SensorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Temperature => "C",
+                Self::Fan => "RPM",
+                Self::Voltage => "V"
+            }
+        )
+    }
+}
+
+/// Represents a single reading from a hardware sensor.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct SensorMetric {
+    /// The name of the chip the sensor is on
+    pub chip: String,
+    /// The device the chip belongs to (such as `0000:00:18.3`), or its hwmon directory (such as `hwmon0`) if it has no device. This tells apart chips with the same name.
+    pub device: String,
+    /// The label of the sensor, or the raw name (such as `temp1`) if it has no label
+    pub label: String,
+    /// What the sensor measures
+    pub kind: SensorKind,
+    /// The current reading, in the units of the kind
+    pub value: f64,
+    /// The critical threshold of the sensor, if one is reported
+    pub critical: Option<f64>
+}
+impl Metric for SensorMetric {}
+
+/// The resource that a pressure stall measurement is for.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PressureResource {
+    Cpu,
+    Memory,
+    Io
+}
+impl Display for PressureResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Cpu => "CPU",
+                Self::Memory => "Memory",
+                Self::Io => "IO"
+            }
+        )
+    }
+}
+
+/// The share of time that tasks were stalled on a resource.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize, Default)]
+pub struct PressureValues {
+    /// The percentage of time stalled over the last 10 seconds
+    pub avg10: f64,
+    /// The percentage of time stalled over the last 60 seconds
+    pub avg60: f64,
+    /// The percentage of time stalled over the last 300 seconds
+    pub avg300: f64,
+    /// The total time stalled (in microseconds) since boot
+    pub total: u64
+}
+
+/// Stores the pressure stall information (PSI) of a specific resource.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct PressureMetric {
+    pub resource: PressureResource,
+    /// The time at least one task was stalled on the resource
+    pub some: PressureValues,
+    /// The time all non-idle tasks were stalled on the resource at once. Older kernels do not report this for the CPU.
+    pub full: Option<PressureValues>
+}
+impl Metric for PressureMetric {}
 
+/// Stores the resource usage of a specific cgroup (v2), such as a systemd slice, service, or container.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct CgroupMetric {
+    /// The path of the cgroup, relative to the root of the hierarchy
+    pub path: String,
+    /// The CPU usage of the cgroup over the sampling interval, as a percentage of one core
+    pub cpu_usage: f64,
+    /// The memory currently used by the cgroup
+    pub memory: ByteCount,
+    /// The memory limit of the cgroup, if one is set
+    pub memory_max: Option<ByteCount>,
+    /// Bytes read per second, across every device
+    pub read_rate: ByteCount,
+    /// Bytes written per second, across every device
+    pub write_rate: ByteCount,
+    /// How many processes are in the cgroup
+    pub pids: u64
+}
+impl Metric for CgroupMetric {}
+
+/// A named value reported by an external collector plugin.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct CustomMetric {
+    /// The name of the plugin that reported the value
+    pub plugin: String,
+    /// The name of the series, as given by the plugin
+    pub name: String,
+    pub value: f64
+}
+impl Metric for CustomMetric {}
+
+/// Represents a snapshot of a specific running process.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessMetric {
+    /// The process ID
+    pub pid: u32,
+    /// The name of the user that owns the process
+    pub user: String,
+    /// The command line used to start the process
+    pub command: String,
+    /// The resident memory used by the process
+    pub rss: ByteCount,
+    /// The state of the process (R, S, D, Z, etc.), as reported by the OS
+    pub state: char,
+    /// How many threads the process has
+    pub threads: u64,
+    /// The CPU usage of the process, as a percentage of one core. This can exceed 100 for multi-threaded processes.
+    pub cpu: f64
+}
+impl Metric for ProcessMetric {}
+
+/// A group of metrics that is collected together, and can be configured separately.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum MetricFamily {
+    Cpu,
+    Memory,
+    Storage,
+    DiskIo,
+    Network,
+    Sensors,
+    Cgroups,
+    Pressure,
+    Plugins
+}
+impl Display for MetricFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Cpu => "cpu",
+                Self::Memory => "memory",
+                Self::Storage => "storage",
+                Self::DiskIo => "disk io",
+                Self::Network => "network",
+                Self::Sensors => "sensors",
+                Self::Cgroups => "cgroups",
+                Self::Pressure => "pressure",
+                Self::Plugins => "plugins"
+            }
+        )
+    }
+}
+impl MetricFamily {
+    pub const ALL: [Self; 9] = [Self::Cpu, Self::Memory, Self::Storage, Self::DiskIo, Self::Network, Self::Sensors, Self::Cgroups, Self::Pressure, Self::Plugins];
+
+    /// Finds a family by its name, ignoring case. Spaces may be left out, so `diskio` is the same as `disk io`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.replace(' ', "").to_lowercase();
+        Self::ALL.into_iter()
+            .find(|x| x.to_string().replace(' ', "") == name)
+    }
+
+    /// Whether the family is measured as the change since its previous collection (CPU usage, disk throughput & cgroup CPU usage). Only the metrics task collects these, since a collection out of turn would shorten the interval its next one is measured over.
+    pub fn is_sampled(&self) -> bool {
+        matches!(self, Self::Cpu | Self::DiskIo | Self::Cgroups)
+    }
+}
+
+/// The outcome of the most recent collection of a metric family.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum CollectorState {
+    Ok,
+    /// The collection did not finish before its deadline, or the previous collection is still running.
+    TimedOut,
+    Error(String)
+}
+impl Display for CollectorState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::Error(e) => write!(f, "error: {e}")
+        }
+    }
+}
+
+/// Describes the most recent collection of a metric family.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct CollectorStatus {
+    pub family: MetricFamily,
+    pub state: CollectorState,
+    /// When the collection started
+    pub time: DateTime<Utc>,
+    /// How long the collection took (or how long it was waited on), in milliseconds
+    pub duration: f64
+}
+
+/// Snapshots are kept on disk and sent between versions, so families missing from older snapshots are left empty.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct CollectedMetrics {
     pub time: DateTime<Utc>,
     pub memory: Vec<MemoryMetric>,
     pub storage: Vec<StorageMetric>,
+    pub disk_io: Vec<DiskIoMetric>,
     pub cpu: Option<CpuMetric>,
     pub network: Vec<NetworkMetric>,
+    pub sensors: Vec<SensorMetric>,
+    pub cgroups: Vec<CgroupMetric>,
+    pub pressure: Vec<PressureMetric>,
+    pub custom: Vec<CustomMetric>,
+    /// The status of each family, as of its most recent collection.
+    pub collectors: Vec<CollectorStatus>,
+}
+impl CollectedMetrics {
+    /// Replaces the values of a specific family with the ones in `source`.
+    pub fn replace_family(&mut self, family: MetricFamily, source: Self) {
+        match family {
+            MetricFamily::Cpu => self.cpu = source.cpu,
+            MetricFamily::Memory => self.memory = source.memory,
+            MetricFamily::Storage => self.storage = source.storage,
+            MetricFamily::DiskIo => self.disk_io = source.disk_io,
+            MetricFamily::Network => self.network = source.network,
+            MetricFamily::Sensors => self.sensors = source.sensors,
+            MetricFamily::Cgroups => self.cgroups = source.cgroups,
+            MetricFamily::Pressure => self.pressure = source.pressure,
+            MetricFamily::Plugins => self.custom = source.custom
+        }
+    }
+
+    /// Copies the values & status of each of `families` from `source`, replacing their current ones.
+    pub fn copy_families(&mut self, families: &[MetricFamily], source: &Self) {
+        for family in families.iter().copied() {
+            match family {
+                MetricFamily::Cpu => self.cpu = source.cpu.clone(),
+                MetricFamily::Memory => self.memory = source.memory.clone(),
+                MetricFamily::Storage => self.storage = source.storage.clone(),
+                MetricFamily::DiskIo => self.disk_io = source.disk_io.clone(),
+                MetricFamily::Network => self.network = source.network.clone(),
+                MetricFamily::Sensors => self.sensors = source.sensors.clone(),
+                MetricFamily::Cgroups => self.cgroups = source.cgroups.clone(),
+                MetricFamily::Pressure => self.pressure = source.pressure.clone(),
+                MetricFamily::Plugins => self.custom = source.custom.clone()
+            }
+
+            if let Some(status) = source.collectors.iter().find(|x| x.family == family) {
+                self.set_status(status.clone());
+            }
+        }
+    }
+
+    /// Removes the values & status of every family not in `families`.
+    pub fn retain_families(&mut self, families: &[MetricFamily]) {
+        for family in MetricFamily::ALL {
+            if !families.contains(&family) {
+                self.replace_family(family, Self::default());
+            }
+        }
+        self.collectors.retain(|x| families.contains(&x.family));
+    }
+
+    /// Records the status of a family, replacing its previous status.
+    pub fn set_status(&mut self, status: CollectorStatus) {
+        match self.collectors.iter_mut().find(|x| x.family == status.family) {
+            Some(v) => *v = status,
+            None => {
+                self.collectors.push(status);
+                self.collectors.sort_by_key(|x| x.family);
+            }
+        }
+    }
+}
+
+/// The granularity of stored metrics.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub enum Resolution {
+    /// Every snapshot, at the metric frequency.
+    Raw,
+    /// One minute rollups.
+    Minute,
+    /// One hour rollups.
+    Hour
+}
+impl Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Raw => "raw",
+                Self::Minute => "1m",
+                Self::Hour => "1h"
+            }
+        )
+    }
+}
+impl Resolution {
+    pub const ROLLUPS: [Self; 2] = [Self::Minute, Self::Hour];
+
+    /// In seconds, the time covered by each point. Raw points are `metric_freq` apart.
+    pub fn period(&self, metric_freq: u64) -> u64 {
+        match self {
+            Self::Raw => metric_freq.max(1),
+            Self::Minute => 60,
+            Self::Hour => 60 * 60
+        }
+    }
+
+    /// The finest resolution that covers `span` seconds in at most `max_points` points. The coarsest resolution is used if none fit.
+    pub fn for_span(span: u64, max_points: usize, metric_freq: u64) -> Self {
+        let max_points = max_points.max(1) as u64;
+        [Self::Raw, Self::Minute]
+            .into_iter()
+            .find(|x| span.div_ceil(x.period(metric_freq)) <= max_points)
+            .unwrap_or(Self::Hour)
+    }
+}
+
+/// The summary of a numeric series over a rollup period.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    /// The most recent value within the period
+    pub last: f64,
+    /// How many values were summarized
+    pub count: u64
+}
+impl Aggregate {
+    pub fn new(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            avg: value,
+            last: value,
+            count: 1
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.avg += (value - self.avg) / self.count as f64;
+        self.last = value;
+    }
+
+    /// Includes the values summarized by `other`, which must cover a later time.
+    pub fn merge(&mut self, other: &Self) {
+        let count = self.count + other.count;
+        if count == 0 {
+            return;
+        }
+
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.avg = (self.avg * self.count as f64 + other.avg * other.count as f64) / count as f64;
+        self.last = other.last;
+        self.count = count;
+    }
+}
+
+/// The summary of every numeric series over a specific period, such as a single minute.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RollupPoint {
+    /// The start of the period
+    pub time: DateTime<Utc>,
+    /// In seconds, the length of the period
+    pub period: u64,
+    /// The summary of each series, keyed as `measurement.field{tag=value,...}`, such as `cpu.user{core=all}`
+    pub series: BTreeMap<String, Aggregate>
+}
+impl RollupPoint {
+    pub fn new(time: DateTime<Utc>, period: u64) -> Self {
+        Self {
+            time,
+            period,
+            series: BTreeMap::new()
+        }
+    }
+
+    /// Includes a value of a series.
+    pub fn add(&mut self, key: &str, value: f64) {
+        match self.series.get_mut(key) {
+            Some(v) => v.add(value),
+            None => {
+                self.series.insert(key.to_string(), Aggregate::new(value));
+            }
+        }
+    }
+
+    /// Includes the summaries of `other`, which must cover a later time.
+    pub fn merge(&mut self, other: &Self) {
+        for (key, value) in &other.series {
+            match self.series.get_mut(key) {
+                Some(v) => v.merge(value),
+                None => {
+                    self.series.insert(key.clone(), *value);
+                }
+            }
+        }
+    }
+}
+
+/// The projected growth of a series towards its capacity, from a trend fitted over its recent history.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Forecast {
+    /// The series that was projected, keyed as `measurement.field{tag=value,...}`, such as `storage.used_percent{device=/dev/sda1,mount=/}`
+    pub series: String,
+    /// When the forecast was made
+    pub time: DateTime<Utc>,
+    /// The most recent percentage of the capacity in use
+    pub current: f64,
+    /// In percentage points per day, how quickly the usage is growing. This is negative when it is shrinking.
+    pub rate: f64,
+    /// When the capacity is projected to be used up, if the usage is growing
+    pub full_at: Option<DateTime<Utc>>,
+    /// How well the trend fits the history, from 0 (not at all) to 1 (exactly)
+    pub confidence: f64,
+    /// How many points the trend was fitted to
+    pub samples: usize
+}
+impl Forecast {
+    /// How many days after the forecast the capacity is projected to be used up, if the usage is growing.
+    pub fn days_to_full(&self) -> Option<f64> {
+        self.full_at.map(|x| (x - self.time).num_seconds() as f64 / 86400.0)
+    }
+}
+impl Display for Forecast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:.1}% used, {:+.2}%/day, ", &self.series, self.current, self.rate)?;
+        match (self.days_to_full(), self.full_at) {
+            (Some(days), Some(full_at)) => write!(f, "full in {days:.1} days ({full_at})")?,
+            _ => write!(f, "not filling")?
+        }
+        write!(f, ", confidence {:.0}% over {} points", self.confidence * 100.0, self.samples)
+    }
 }
 
 const TAB1: &str = "\t";
@@ -118,12 +676,30 @@ impl Display for CollectedMetricsFormatter<'_> {
         if !self.0.storage.is_empty() {
             Self::fmt_storage(f, &self.0.storage)?;
         }
+        if !self.0.disk_io.is_empty() {
+            Self::fmt_disk_io(f, &self.0.disk_io)?;
+        }
         if let Some(cpu) = self.0.cpu.as_ref() {
             Self::fmt_cpu(f, cpu)?;
         }
         if !self.0.network.is_empty() {
             Self::fmt_network(f, &self.0.network)?;
         }
+        if !self.0.sensors.is_empty() {
+            Self::fmt_sensors(f, &self.0.sensors)?;
+        }
+        if !self.0.pressure.is_empty() {
+            Self::fmt_pressure(f, &self.0.pressure)?;
+        }
+        if !self.0.cgroups.is_empty() {
+            Self::fmt_cgroups(f, &self.0.cgroups)?;
+        }
+        if !self.0.custom.is_empty() {
+            Self::fmt_custom(f, &self.0.custom)?;
+        }
+        if !self.0.collectors.is_empty() {
+            Self::fmt_collectors(f, &self.0.collectors)?;
+        }
 
         Ok( () )
     }
@@ -149,20 +725,163 @@ impl<'a> CollectedMetricsFormatter<'a> {
 
         Ok( () )
     }
+    fn fmt_disk_io(f: &mut std::fmt::Formatter<'_>, disks: &[DiskIoMetric]) -> std::fmt::Result {
+        writeln!(f, "Disk IO:\n")?;
+        writeln!(f, "   DEVICE   |  READ/S  | WRITE/S  |  READ RATE  |  WRITE RATE  | R. AWAIT | W. AWAIT |  UTIL.  |")?;
+        writeln!(f, "------------|----------|----------|-------------|--------------|----------|----------|---------|")?;
+        for disk in disks {
+            writeln!(
+                f,
+                " {:^10} | {:>8.1} | {:>8.1} | {:^11} | {:^12} | {:>6.2}ms | {:>6.2}ms | {:>6.1}% |",
+                &disk.device,
+                disk.read_iops,
+                disk.write_iops,
+                disk.read_rate,
+                disk.write_rate,
+                disk.read_await,
+                disk.write_await,
+                disk.utilization
+            )?;
+        }
+
+        Ok( () )
+    }
     fn fmt_cpu(f: &mut std::fmt::Formatter<'_>, cpu: &CpuMetric) -> std::fmt::Result {
-        writeln!(f, "CPU:")?;
-        writeln!(f, "{TAB1} User: {}", cpu.user)?;
-        writeln!(f, "{TAB1} System: {}", cpu.system)?;
-        writeln!(f, "{TAB1} Nice: {}", cpu.nice)?;
-        writeln!(f, "{TAB1} Idle: {}", cpu.idle)?;
-        writeln!(f, "{TAB1} IO Waiting: {}", cpu.waiting)?;
-        writeln!(f, "{TAB1} Stolen Time: {}", cpu.steal)?;
+        writeln!(f, "CPU:\n")?;
+        if let Some(load) = cpu.load.as_ref() {
+            writeln!(f, "{TAB1} Load Average: {:.2}, {:.2}, {:.2}\n", load.one, load.five, load.fifteen)?;
+        }
+
+        writeln!(f, "   CORE   |  USER  | SYSTEM |  NICE  |  IDLE  | IOWAIT |  IRQ   |SOFTIRQ | STEAL  |")?;
+        writeln!(f, "----------|--------|--------|--------|--------|--------|--------|--------|--------|")?;
+        Self::fmt_cpu_usage(f, "all", &cpu.total)?;
+        for core in &cpu.cores {
+            Self::fmt_cpu_usage(f, &core.id.to_string(), &core.usage)?;
+        }
 
         Ok( () )
     }
+    fn fmt_cpu_usage(f: &mut std::fmt::Formatter<'_>, name: &str, usage: &CpuUsage) -> std::fmt::Result {
+        write!(f, " {name:^8} |")?;
+        for value in [usage.user, usage.system, usage.nice, usage.idle, usage.iowait, usage.irq, usage.softirq, usage.steal] {
+            write!(f, " {:>5.1}% |", value)?;
+        }
+
+        writeln!(f)
+    }
     fn fmt_network(f: &mut std::fmt::Formatter<'_>, network: &[NetworkMetric]) -> std::fmt::Result {
         writeln!(f, "Network:\n")?;
-        writeln!(f, "Todo tee hee, print {} elements", network.len())?;
+        writeln!(f, "  INTERFACE  |  MTU  |  RX TOTAL  |    RX/S    | RX PKT/S | RX ERR/DROP |  TX TOTAL  |    TX/S    | TX PKT/S | TX ERR/DROP |")?;
+        writeln!(f, "-------------|-------|------------|------------|----------|-------------|------------|------------|----------|-------------|")?;
+        for link in network {
+            write!(f, " {:^11} | {:^5} |", &link.name, &link.mtu)?;
+            Self::fmt_network_section(f, &link.rx, link.rx_rate.as_ref())?;
+            Self::fmt_network_section(f, &link.tx, link.tx_rate.as_ref())?;
+            writeln!(f)?;
+        }
+
+        Ok( () )
+    }
+    fn fmt_network_section(f: &mut std::fmt::Formatter<'_>, section: &NetworkMetricSection, rate: Option<&NetworkRate>) -> std::fmt::Result {
+        write!(f, " {:^10} |", BinaryNumber::parse(section.bytes))?;
+        match rate {
+            Some(rate) => write!(f, " {:^10} | {:>8.1} |", rate.bytes, rate.packets)?,
+            None => write!(f, " {:^10} | {:>8} |", "-", "-")?
+        }
+        write!(f, " {:^11} |", format!("{}/{}", section.err, section.drop))
+    }
+
+    fn fmt_sensors(f: &mut std::fmt::Formatter<'_>, sensors: &[SensorMetric]) -> std::fmt::Result {
+        writeln!(f, "Sensors:\n")?;
+        writeln!(f, "     CHIP     |    DEVICE    |       SENSOR       |    VALUE    |  CRITICAL   |")?;
+        writeln!(f, "--------------|--------------|--------------------|-------------|-------------|")?;
+        for sensor in sensors {
+            let critical = match sensor.critical {
+                Some(v) => format!("{v:.1} {}", sensor.kind),
+                None => "-".to_string()
+            };
+
+            writeln!(f, " {:^12} | {:^12} | {:^18} | {:^11} | {:^11} |", &sensor.chip, &sensor.device, &sensor.label, format!("{:.1} {}", sensor.value, sensor.kind), critical)?;
+        }
+
+        Ok( () )
+    }
+
+    fn fmt_pressure(f: &mut std::fmt::Formatter<'_>, pressure: &[PressureMetric]) -> std::fmt::Result {
+        writeln!(f, "Pressure:\n")?;
+        writeln!(f, "  RESOURCE  | KIND |  AVG10  |  AVG60  | AVG300  |   TOTAL   |")?;
+        writeln!(f, "------------|------|---------|---------|---------|-----------|")?;
+        for metric in pressure {
+            let resource = metric.resource.to_string();
+            for (kind, values) in [("some", Some(&metric.some)), ("full", metric.full.as_ref())] {
+                if let Some(values) = values {
+                    writeln!(
+                        f,
+                        " {:^10} | {:^4} | {:>6.2}% | {:>6.2}% | {:>6.2}% | {:>8.1}s |",
+                        resource,
+                        kind,
+                        values.avg10,
+                        values.avg60,
+                        values.avg300,
+                        values.total as f64 / 1_000_000.0
+                    )?;
+                }
+            }
+        }
+
+        Ok( () )
+    }
+    fn fmt_cgroups(f: &mut std::fmt::Formatter<'_>, cgroups: &[CgroupMetric]) -> std::fmt::Result {
+        writeln!(f, "Control Groups:\n")?;
+        writeln!(f, "               CGROUP               |   CPU   |   MEMORY   |   LIMIT    |  READ RATE  |  WRITE RATE  |  PIDS  |")?;
+        writeln!(f, "------------------------------------|---------|------------|------------|-------------|--------------|--------|")?;
+        for group in cgroups {
+            let limit = match group.memory_max {
+                Some(v) => v.to_string(),
+                None => "-".to_string()
+            };
+
+            writeln!(
+                f,
+                " {:<34} | {:>6.1}% | {:^10} | {:^10} | {:^11} | {:^12} | {:>6} |",
+                &group.path,
+                group.cpu_usage,
+                group.memory,
+                limit,
+                group.read_rate,
+                group.write_rate,
+                group.pids
+            )?;
+        }
+
+        Ok( () )
+    }
+
+    fn fmt_custom(f: &mut std::fmt::Formatter<'_>, custom: &[CustomMetric]) -> std::fmt::Result {
+        writeln!(f, "Custom:\n")?;
+        writeln!(f, "      PLUGIN      |           SERIES           |     VALUE     |")?;
+        writeln!(f, "------------------|----------------------------|---------------|")?;
+        for metric in custom {
+            writeln!(f, " {:^16} | {:^26} | {:>13} |", &metric.plugin, &metric.name, metric.value)?;
+        }
+
+        Ok( () )
+    }
+
+    fn fmt_collectors(f: &mut std::fmt::Formatter<'_>, collectors: &[CollectorStatus]) -> std::fmt::Result {
+        writeln!(f, "Collectors:\n")?;
+        writeln!(f, "   FAMILY   |      STATUS      |  DURATION  |          SAMPLED          |")?;
+        writeln!(f, "------------|------------------|------------|---------------------------|")?;
+        for status in collectors {
+            writeln!(
+                f,
+                " {:^10} | {:^16} | {:>8.1}ms | {:^25} |",
+                status.family.to_string(),
+                status.state.to_string(),
+                status.duration,
+                status.time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+            )?;
+        }
 
         Ok( () )
     }
@@ -171,3 +890,31 @@ impl<'a> CollectedMetricsFormatter<'a> {
         Self(data)
     }
 }
+
+pub struct ProcessMetricsFormatter<'a>(&'a [ProcessMetric]);
+impl Display for ProcessMetricsFormatter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "   PID   |    USER    | S | THREADS |   CPU%   |    RSS     | COMMAND")?;
+        writeln!(f, "---------|------------|---|---------|----------|------------|---------")?;
+        for process in self.0 {
+            writeln!(
+                f,
+                " {:>7} | {:<10} | {} | {:>7} | {:>7.1}% | {:^10} | {}",
+                process.pid,
+                process.user,
+                process.state,
+                process.threads,
+                process.cpu,
+                process.rss,
+                process.command
+            )?;
+        }
+
+        Ok( () )
+    }
+}
+impl<'a> ProcessMetricsFormatter<'a> {
+    pub fn new(data: &'a [ProcessMetric]) -> Self {
+        Self(data)
+    }
+}
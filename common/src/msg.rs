@@ -1,7 +1,7 @@
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
-use crate::{config::DaemonConfig, metric::{CollectedMetrics, CollectedMetricsFormatter}, usr::UserHistoryElement};
+use crate::{alert::{Alert, AlertState, Anomaly}, config::DaemonConfig, metric::{ByteCount, CollectedMetrics, CollectedMetricsFormatter, Forecast, MetricFamily, ProcessMetric, ProcessMetricsFormatter, Resolution, RollupPoint}, usr::UserHistoryElement};
 
 use std::{fmt::{Debug, Display}, net::IpAddr, ops::Deref};
 
@@ -16,42 +16,382 @@ impl Display for ServerStatusResponse {
     }
 }
 
+/// Determines how far apart the points of a metrics query are.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum QueryDensity {
+    /// At most this many points, as finely spaced as the stored metrics allow.
+    MaxPoints(usize),
+    /// One point per this many seconds.
+    Step(u64)
+}
+
+/// Requests the metrics recorded over a specific time range.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct MetricsQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub density: QueryDensity,
+    /// The families to include. When empty, every family is included.
+    pub families: Vec<MetricFamily>,
+    /// The series to include, named as `measurement.field` (such as `cpu.user`) or just `measurement`. When empty, every series of the included families is sent.
+    pub fields: Vec<String>
+}
+impl MetricsQuery {
+    /// Requests every family over the last `span` seconds, in at most `max_points` points.
+    pub fn recent(span: u64, max_points: usize) -> Self {
+        let to = Utc::now();
+        Self {
+            from: to - chrono::Duration::seconds(span as i64),
+            to,
+            density: QueryDensity::MaxPoints(max_points),
+            families: vec![],
+            fields: vec![]
+        }
+    }
+
+    /// The length of the range, in seconds.
+    pub fn span(&self) -> u64 {
+        (self.to - self.from).num_seconds().max(0) as u64
+    }
+
+    pub fn includes_family(&self, family: MetricFamily) -> bool {
+        self.families.is_empty() || self.families.contains(&family)
+    }
+}
+
+/// The metrics of a query, either as whole snapshots or as summaries of each series.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetricsData {
+    /// Every recorded snapshot in the range, holding only the requested families.
+    Snapshots(Vec<CollectedMetrics>),
+    /// The min, max, average & last value of the requested series over each step.
+    Points(Vec<RollupPoint>)
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct MetricsResponse {
-    pub info: Vec<CollectedMetrics>
+    /// The stored metrics that the points were built from.
+    pub resolution: Resolution,
+    /// In seconds, how far apart the points are.
+    pub step: u64,
+    pub info: MetricsData
+}
+impl MetricsResponse {
+    pub fn empty() -> Self {
+        Self {
+            resolution: Resolution::Raw,
+            step: 0,
+            info: MetricsData::Snapshots(vec![])
+        }
+    }
 }
 impl Display for MetricsResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for metric in &self.info {
-            let fmt = CollectedMetricsFormatter::new(metric);
+        match &self.info {
+            MetricsData::Snapshots(snapshots) => {
+                for metric in snapshots {
+                    let fmt = CollectedMetricsFormatter::new(metric);
 
-            fmt.fmt(f)?;
-            writeln!(f)?;
+                    fmt.fmt(f)?;
+                    writeln!(f)?;
+                }
+            }
+            MetricsData::Points(points) => {
+                writeln!(f, "{} points every {}s (from {} metrics)", points.len(), self.step, self.resolution)?;
+                for point in points {
+                    writeln!(f, "Metrics from {} ({}s):", point.time, point.period)?;
+                    for (key, value) in &point.series {
+                        writeln!(f, "\t {key}: avg {:.2}, min {:.2}, max {:.2}, last {:.2} ({} values)", value.avg, value.min, value.max, value.last, value.count)?;
+                    }
+                }
+            }
         }
 
         Ok( () )
     }
 }
 
+/// A snapshot pushed to a subscribed client, as soon as it is recorded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotResponse {
+    pub info: CollectedMetrics
+}
+impl Display for SnapshotResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        CollectedMetricsFormatter::new(&self.info).fmt(f)
+    }
+}
+
+/// Acknowledges a subscribe or unsubscribe request. No snapshots are pushed after an inactive subscription is acknowledged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SubscriptionResponse {
+    pub active: bool
+}
+
+/// The alerts raised by the server's alert rules, most severe first. Resolved alerts are kept for a while after resolving.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlertsResponse {
+    pub info: Vec<Alert>
+}
+impl Display for AlertsResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let active = self.info.iter().filter(|x| x.state != AlertState::Resolved).count();
+        writeln!(f, "{active} active alert(s), {} resolved", self.info.len() - active)?;
+        for alert in &self.info {
+            writeln!(f, "\t {alert}")?;
+        }
+
+        Ok( () )
+    }
+}
+
+/// The anomalies detected by the server that were ongoing at some point since the requested time, newest first.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AnomaliesResponse {
+    pub info: Vec<Anomaly>
+}
+impl Display for AnomaliesResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ongoing = self.info.iter().filter(|x| x.end.is_none()).count();
+        writeln!(f, "{} anomalies, {ongoing} ongoing", self.info.len())?;
+        for anomaly in &self.info {
+            writeln!(f, "\t {anomaly}")?;
+        }
+
+        Ok( () )
+    }
+}
+
+/// The capacity forecasts of the server's filesystems & memory, soonest to be full first.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ForecastResponse {
+    pub info: Vec<Forecast>
+}
+impl Display for ForecastResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let filling = self.info.iter().filter(|x| x.full_at.is_some()).count();
+        writeln!(f, "{} forecast(s), {filling} filling", self.info.len())?;
+        for forecast in &self.info {
+            writeln!(f, "\t {forecast}")?;
+        }
+
+        Ok( () )
+    }
+}
+
+/// The running processes of the server, sorted and limited as requested.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProcessesResponse {
+    pub info: Vec<ProcessMetric>
+}
+impl Display for ProcessesResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        ProcessMetricsFormatter::new(&self.info).fmt(f)
+    }
+}
+
+/// Static information about the server, which does not change while regisd is running.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct HostInfo {
+    pub hostname: String,
+    /// The kernel release
+    pub kernel: String,
+    /// The name of the operating system, from os-release
+    pub os_name: String,
+    /// The version of the operating system, from os-release
+    pub os_version: String,
+    pub cpu_model: String,
+    pub cpu_cores: u32,
+    pub total_memory: ByteCount,
+    /// When the server booted, if it could be determined
+    pub boot_time: Option<DateTime<Utc>>,
+    /// In seconds, how long the server has been running as of the response, if it could be determined
+    pub uptime: Option<u64>,
+    /// The version of regisd running on the server
+    pub daemon_version: String
+}
+
+/// The host information of the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HostInfoResponse {
+    pub info: HostInfo
+}
+impl Display for HostInfoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let info = &self.info;
+
+        writeln!(f, "Host '{}':", &info.hostname)?;
+        writeln!(f, "\t OS: {} {}", &info.os_name, &info.os_version)?;
+        writeln!(f, "\t Kernel: {}", &info.kernel)?;
+        writeln!(f, "\t CPU: {} ({} cores)", &info.cpu_model, info.cpu_cores)?;
+        writeln!(f, "\t Memory: {}", info.total_memory)?;
+        match info.boot_time {
+            Some(v) => write!(f, "\t Booted: {v}")?,
+            None => write!(f, "\t Booted: unknown")?
+        }
+        match info.uptime {
+            Some(v) => writeln!(f, " (up {}d {}h {}m)", v / 86400, (v % 86400) / 3600, (v % 3600) / 60)?,
+            None => writeln!(f)?
+        }
+        writeln!(f, "\t regisd: {}", &info.daemon_version)
+    }
+}
+
+/// Determines how the process table is ordered, highest first.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ProcessSort {
+    #[default]
+    Cpu,
+    Memory
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum RequestMessages {
     Status,
-    Metrics(usize)
+    Metrics(MetricsQuery),
+    Processes { sort: ProcessSort, limit: usize },
+    HostInfo,
+    /// Pushes every new snapshot, holding only `families` (or every family, when empty), at most once per `every` seconds. This replaces any previous subscription.
+    Subscribe { families: Vec<MetricFamily>, every: u64 },
+    Unsubscribe,
+    Alerts,
+    /// Requests the anomalies that were ongoing at any point since `since`.
+    Anomalies { since: DateTime<Utc> },
+    Forecast
+}
+impl RequestMessages {
+    /// The capability that must be negotiated before this request is sent, if it is optional.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            Self::Status | Self::Metrics(_) | Self::Processes { .. } | Self::HostInfo => None,
+            Self::Subscribe { .. } | Self::Unsubscribe => Some(CAPABILITY_SUBSCRIBE),
+            Self::Alerts => Some(CAPABILITY_ALERTS),
+            Self::Anomalies { .. } => Some(CAPABILITY_ANOMALIES),
+            Self::Forecast => Some(CAPABILITY_FORECAST)
+        }
+    }
 }
-impl From<usize> for RequestMessages {
-    fn from(value: usize) -> Self {
+impl From<MetricsQuery> for RequestMessages {
+    fn from(value: MetricsQuery) -> Self {
         Self::Metrics(value)
     }
 }
 
+/// Describes why a request failed, so that tools can react to it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ErrorCode {
+    /// The sender is not signed in, or is no longer allowed to make requests (such as a user revoked while connected).
+    Unauthorized,
+    /// The request could not be decoded, or its contents are invalid. Sending it again will fail the same way.
+    BadRequest,
+    /// The request is valid, but is not served by this daemon (such as a capability that was not negotiated).
+    Unsupported,
+    /// The request refers to something that does not exist, such as an unknown user.
+    NotFound,
+    /// The daemon is serving too much at once. The request can be sent again later.
+    Busy,
+    /// The daemon failed while serving the request.
+    Internal
+}
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Unauthorized => "unauthorized",
+                Self::BadRequest => "bad request",
+                Self::Unsupported => "unsupported",
+                Self::NotFound => "not found",
+                Self::Busy => "busy",
+                Self::Internal => "internal error"
+            }
+        )
+    }
+}
+
+/// Describes a failed request. Clients receive it as [`ResponseMessages::Error`], and consoles as the error of a [`ConsoleResponse`].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// A description of the failure, meant for people
+    pub message: String
+}
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        Self {
+            code,
+            message
+        }
+    }
+}
+impl Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", &self.message, self.code)
+    }
+}
+
+/// What regisd sends back for each console request: the response, or the reason it failed.
+pub type ConsoleResponse<T> = Result<T, ErrorResponse>;
+
+/// Identifies a request on a connection, so that its response can be matched to it. Ids are picked by the client, and should not be reused while a request is in flight.
+pub type RequestId = u64;
+
+/// A request, as sent by a client. Several requests can be in flight at once, and their responses may arrive in any order.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct RequestEnvelope {
+    pub id: RequestId,
+    pub request: RequestMessages
+}
+impl RequestEnvelope {
+    pub fn new(id: RequestId, request: RequestMessages) -> Self {
+        Self {
+            id,
+            request
+        }
+    }
+}
+
+/// A message sent by the server, either in response to a request or pushed on its own (such as the snapshots of a subscription).
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ResponseEnvelope {
+    /// The request that this responds to, or `None` if it was pushed (or is an error about a request whose id could not be read).
+    pub id: Option<RequestId>,
+    pub response: ResponseMessages
+}
+impl ResponseEnvelope {
+    pub fn reply(id: RequestId, response: ResponseMessages) -> Self {
+        Self {
+            id: Some(id),
+            response
+        }
+    }
+    pub fn push(response: ResponseMessages) -> Self {
+        Self {
+            id: None,
+            response
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum ResponseMessages {
-    Status(ServerStatusResponse),
-    Metrics(MetricsResponse)
+    Status(Box<ServerStatusResponse>),
+    Metrics(MetricsResponse),
+    Processes(ProcessesResponse),
+    HostInfo(HostInfoResponse),
+    Subscription(SubscriptionResponse),
+    /// Sent without a request, while subscribed.
+    Snapshot(Box<SnapshotResponse>),
+    Alerts(AlertsResponse),
+    Anomalies(AnomaliesResponse),
+    Forecast(ForecastResponse),
+    /// The request failed. The connection stays open, so other requests can still be sent.
+    Error(ErrorResponse)
 }
 impl From<ServerStatusResponse> for ResponseMessages {
     fn from(value: ServerStatusResponse) -> Self {
-        Self::Status(value)
+        Self::Status(Box::new(value))
     }
 }
 impl From<MetricsResponse> for ResponseMessages {
@@ -59,6 +399,46 @@ impl From<MetricsResponse> for ResponseMessages {
         Self::Metrics(value)
     }
 }
+impl From<ProcessesResponse> for ResponseMessages {
+    fn from(value: ProcessesResponse) -> Self {
+        Self::Processes(value)
+    }
+}
+impl From<HostInfoResponse> for ResponseMessages {
+    fn from(value: HostInfoResponse) -> Self {
+        Self::HostInfo(value)
+    }
+}
+impl From<SubscriptionResponse> for ResponseMessages {
+    fn from(value: SubscriptionResponse) -> Self {
+        Self::Subscription(value)
+    }
+}
+impl From<SnapshotResponse> for ResponseMessages {
+    fn from(value: SnapshotResponse) -> Self {
+        Self::Snapshot(Box::new(value))
+    }
+}
+impl From<AlertsResponse> for ResponseMessages {
+    fn from(value: AlertsResponse) -> Self {
+        Self::Alerts(value)
+    }
+}
+impl From<AnomaliesResponse> for ResponseMessages {
+    fn from(value: AnomaliesResponse) -> Self {
+        Self::Anomalies(value)
+    }
+}
+impl From<ForecastResponse> for ResponseMessages {
+    fn from(value: ForecastResponse) -> Self {
+        Self::Forecast(value)
+    }
+}
+impl From<ErrorResponse> for ResponseMessages {
+    fn from(value: ErrorResponse) -> Self {
+        Self::Error(value)
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct PendingUser {
@@ -131,6 +511,87 @@ impl UserDetails {
     }
 }
 
+/// The newest version of the protocol between clients & regisd. This is raised whenever a released message changes in a way that older versions cannot read.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest version of the protocol that is still spoken.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The optional requests. Each is only sent once both sides have negotiated it.
+pub const CAPABILITY_SUBSCRIBE: &str = "subscribe";
+pub const CAPABILITY_ALERTS: &str = "alerts";
+pub const CAPABILITY_ANOMALIES: &str = "anomalies";
+pub const CAPABILITY_FORECAST: &str = "forecast";
+
+/// Introduces each side when a client connects, before any keys are exchanged. The server sends its hello first, and the client replies with its own.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// The name & version of the software, such as `regisd 0.2.0`
+    pub software: String,
+    /// The oldest protocol version spoken
+    pub min_protocol: u32,
+    /// The newest protocol version spoken
+    pub protocol: u32,
+    /// The optional requests that are understood (and, for the server, enabled)
+    #[serde(default)]
+    pub capabilities: Vec<String>
+}
+impl Hello {
+    /// A hello for the current protocol version.
+    pub fn new(software: String, capabilities: Vec<String>) -> Self {
+        Self {
+            software,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            protocol: PROTOCOL_VERSION,
+            capabilities
+        }
+    }
+
+    /// Determines the newest protocol version & the capabilities that both sides have. The error describes why the versions are incompatible.
+    pub fn negotiate(&self, other: &Self) -> Result<Negotiated, String> {
+        let protocol = self.protocol.min(other.protocol);
+        if protocol < self.min_protocol.max(other.min_protocol) {
+            return Err(
+                format!(
+                    "{} speaks protocol versions {} to {}, but {} speaks {} to {}",
+                    &self.software, self.min_protocol, self.protocol,
+                    &other.software, other.min_protocol, other.protocol
+                )
+            );
+        }
+
+        Ok(
+            Negotiated {
+                protocol,
+                capabilities: self.capabilities.iter()
+                    .filter(|x| other.capabilities.contains(x))
+                    .cloned()
+                    .collect()
+            }
+        )
+    }
+}
+
+/// The protocol version & capabilities agreed on by a client & the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol: u32,
+    pub capabilities: Vec<String>
+}
+impl Negotiated {
+    /// Determines if a request may be sent.
+    pub fn supports(&self, request: &RequestMessages) -> bool {
+        request.capability().is_none_or(|x| self.capabilities.iter().any(|y| y == x))
+    }
+}
+
+/// The server's reply to the client's hello.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum HelloResponse {
+    Accepted(Negotiated),
+    /// The versions are incompatible, and the connection will be closed.
+    Rejected { reason: String }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum SignInMessage {
     Returning(String),
@@ -155,9 +616,9 @@ pub enum ConsoleAuthRequests {
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ConsoleConfigRequests {
-    Reload,               // Response -> ()
-    Get,                  // Response -> DaemonConfig
-    Set(DaemonConfig)     // Response -> bool
+    Reload,                 // Response -> ()
+    Get,                    // Response -> DaemonConfig
+    Set(Box<DaemonConfig>)  // Response -> bool
 }
 impl ConsoleConfigRequests {
     pub fn flatten(&self) -> ConsoleConfigFlatRequests {
@@ -21,7 +21,25 @@ pub struct DaemonConfigUpdate {
     pub hosts_port: Option<u16>,
     /// In seconds, how frequently the system records metrics.
     #[arg(long = "freq")]
-    pub metric_freq: Option<u64>
+    pub metric_freq: Option<u64>,
+    /// The cgroups to collect resource usage for, such as `system.slice`. Replaces the current list.
+    #[arg(long = "cgroup")]
+    pub cgroups: Option<Vec<String>>,
+    /// Enables or disables the Prometheus metrics endpoint.
+    #[arg(long = "prometheus")]
+    pub prometheus_enabled: Option<bool>,
+    /// The address the Prometheus metrics endpoint listens on.
+    #[arg(long = "prometheus-bind")]
+    pub prometheus_bind: Option<std::net::IpAddr>,
+    /// The port used by the Prometheus metrics endpoint.
+    #[arg(long = "prometheus-port")]
+    pub prometheus_port: Option<u16>,
+    /// The addresses allowed to scrape the Prometheus metrics endpoint. Replaces the current list.
+    #[arg(long = "prometheus-allow")]
+    pub prometheus_allowlist: Option<Vec<std::net::IpAddr>>,
+    /// The bearer token that Prometheus scrapers must send. An empty token removes it.
+    #[arg(long = "prometheus-token")]
+    pub prometheus_token: Option<String>
 }
 
 #[derive(Clone, Debug)]
@@ -99,9 +117,27 @@ where L: Logger + 'static {
             if let Some(metric_freq) = config_diff.metric_freq {
                 config.metric_freq = metric_freq;
             }
+            if let Some(cgroups) = config_diff.cgroups {
+                config.cgroups = cgroups;
+            }
+            if let Some(enabled) = config_diff.prometheus_enabled {
+                config.prometheus.enabled = enabled;
+            }
+            if let Some(bind) = config_diff.prometheus_bind {
+                config.prometheus.bind = bind;
+            }
+            if let Some(port) = config_diff.prometheus_port {
+                config.prometheus.port = port;
+            }
+            if let Some(allowlist) = config_diff.prometheus_allowlist {
+                config.prometheus.allowlist = allowlist;
+            }
+            if let Some(token) = config_diff.prometheus_token {
+                config.prometheus.token = if token.is_empty() { None } else { Some(token) };
+            }
 
             // Now send back the previous config.
-            ConsoleRequests::Config(ConsoleConfigRequests::Set(config))
+            ConsoleRequests::Config(ConsoleConfigRequests::Set(Box::new(config)))
         }
     };
     log_debug!(logger, "Sending request {:?} to regisd", &request);
@@ -132,6 +168,15 @@ where L: Logger + 'static {
                                 break;
                             }
                         },
+                        // The connection is still usable, so the error is passed along.
+                        Err(ConnectionError::Daemon(e)) => {
+                            log_warning!(&logger, "regisd could not complete the request: {e}");
+                            if !comm.force_send(Err(ConnectionError::Daemon(e)).into()).await {
+                                log_error!(&logger, "Unable to send response back to the backend controller. Backend exiting.");
+                                result = BackendOutput::CommFailure;
+                                break;
+                            }
+                        },
                         Err(e) => {
                             log_error!(&logger, "Unable to process request with error: '{e:?}'. Backend exiting.");
 
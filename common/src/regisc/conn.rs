@@ -3,7 +3,7 @@ use std::io::Error as IOError;
 use serde::de::DeserializeOwned;
 use tokio::net::UnixStream;
 
-use crate::{loc::COMM_PATH, msg::ConsoleRequests};
+use crate::{loc::COMM_PATH, msg::{ConsoleRequests, ConsoleResponse, ErrorResponse}};
 use exdisj::io::{msg::{decode_message_async, send_message_async, DecodeError, SendError}, net::receive_buffer_async};
 
 #[derive(Debug)]
@@ -11,7 +11,9 @@ pub enum ConnectionError {
     IO(IOError),
     Serde(serde_json::Error),
     UTF(std::string::FromUtf8Error),
-    Inappropriate
+    Inappropriate,
+    /// regisd could not complete the request.
+    Daemon(ErrorResponse)
 }
 impl From<IOError> for ConnectionError {
     fn from(value: IOError) -> Self {
@@ -72,16 +74,19 @@ impl Connection {
     pub async fn recv<T>(&mut self) -> Result<T, DecodeError> where T: DeserializeOwned {
         decode_message_async(&mut self.stream).await
     }
+    /// Sends a request, and receives the response. This fails with [`ConnectionError::Daemon`] if regisd sent an error in place of the response.
     pub async fn send_with_response<T, R>(&mut self, message: T) -> Result<R, ConnectionError>
         where T: Into<ConsoleRequests>,
         R: DeserializeOwned {
         self.send(message).await.map_err(ConnectionError::from)?;
-        self.recv().await.map_err(ConnectionError::from)
+        let response: ConsoleResponse<R> = self.recv().await.map_err(ConnectionError::from)?;
+        response.map_err(ConnectionError::Daemon)
     }
+    /// Like [`Connection::send_with_response`], but leaves the response serialized.
     pub async fn send_with_response_bytes<T>(&mut self, message: T) -> Result<Vec<u8>, ConnectionError>
         where T: Into<ConsoleRequests> {
-        self.send(message).await.map_err(ConnectionError::from)?;
-        self.recv_bytes().await.map_err(ConnectionError::from)
+        let response: serde_json::Value = self.send_with_response(message).await?;
+        serde_json::to_vec(&response).map_err(ConnectionError::from)
     }
 
     pub async fn poll(&mut self) -> Result<(), SendError> {
@@ -4,6 +4,8 @@ use std::process::ExitCode;
 use exdisj::auth::{AesRecvError, AesSendError, RsaRecvError};
 use rand::{CryptoRng, RngCore};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::io::{stdin, stdout, AsyncWriteExt, AsyncBufReadExt, BufReader, Lines, Stdin, Stdout, AsyncRead, AsyncWrite};
 
 use std::str::FromStr;
@@ -19,7 +21,9 @@ use exdisj::{
     },
     auth::{RsaHandler, RsaStream, AesStream, AesHandler}
 };
-use common::msg::{RequestMessages, ResponseMessages};
+use common::metric::MetricFamily;
+use common::msg::{ErrorResponse, Hello, HelloResponse, MetricsQuery, Negotiated, ProcessSort, QueryDensity, RequestEnvelope, RequestId, RequestMessages, ResponseEnvelope, ResponseMessages};
+use common::msg::{CAPABILITY_ALERTS, CAPABILITY_ANOMALIES, CAPABILITY_FORECAST, CAPABILITY_SUBSCRIBE};
 use rsa_ext::RsaPublicKey;
 
 use common::config::{KnownHost, REGIS_CONFIG};
@@ -39,10 +43,40 @@ pub async fn prompt_message(msg: &[u8], out: &mut Stdout, lines: &mut Lines<BufR
     Ok( raw_command )
 }
 
+/// The number of processes shown by `top` when no amount is given.
+pub const DEFAULT_TOP_AMOUNT: usize = 15;
+/// The most points requested by `metrics` when no amount or step is given.
+pub const DEFAULT_METRIC_POINTS: usize = 60;
+/// In seconds, how far back `anomalies` looks when no length of time is given.
+pub const DEFAULT_ANOMALY_SPAN: u64 = 24 * 60 * 60;
+
+/// Parses a length of time, such as `90`, `30s`, `15m`, `2h` or `7d`, into seconds.
+pub fn parse_span(contents: &str) -> Option<u64> {
+    let (number, unit) = match contents.find(|x: char| !x.is_ascii_digit()) {
+        Some(i) => contents.split_at(i),
+        None => (contents, "s")
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
 pub enum Commands {
     Quit,
     Status,
-    Metrics { amount: usize },
+    Metrics { query: MetricsQuery },
+    Top { sort: ProcessSort, amount: usize },
+    Host,
+    Watch { families: Vec<MetricFamily>, every: u64 },
+    Alerts,
+    Anomalies { span: u64 },
+    Forecast,
     Help
 }
 impl FromStr for Commands {
@@ -50,6 +84,11 @@ impl FromStr for Commands {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let lower = s.trim().to_lowercase();
+        // Commands with arguments are matched on their first word, so that `topology` is not taken as `top`.
+        let (command, args) = match lower.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (lower.as_str(), "")
+        };
 
         if lower == "quit" || lower == "exit" || lower == "close" {
             Ok(Self::Quit)
@@ -57,14 +96,95 @@ impl FromStr for Commands {
         else if lower == "status" {
             Ok(Self::Status)
         }
-        else if let Some(n) = lower.strip_prefix("metrics") {
-            let trimmed = n.trim();
-            let number: usize = match trimmed.parse() {
-                Ok(v) => v,
-                Err(_) => return Err(FormattingError::new(&trimmed, "could not be parsed as a number"))
+        else if lower == "host" {
+            Ok(Self::Host)
+        }
+        else if lower == "alerts" {
+            Ok(Self::Alerts)
+        }
+        else if lower == "forecast" {
+            Ok(Self::Forecast)
+        }
+        else if command == "anomalies" {
+            let span = match args {
+                "" => DEFAULT_ANOMALY_SPAN,
+                arg => match parse_span(arg) {
+                    Some(v) => v,
+                    None => return Err(FormattingError::new(&arg, "could not be parsed as a length of time"))
+                }
             };
 
-            Ok(Self::Metrics { amount: number })
+            Ok(Self::Anomalies { span })
+        }
+        else if command == "metrics" {
+            let mut args = args.split_whitespace();
+            let span = match args.next() {
+                Some(v) => v,
+                None => return Err(FormattingError::new(&lower, "requires a length of time"))
+            };
+            let span = match parse_span(span) {
+                Some(v) => v,
+                None => return Err(FormattingError::new(&span, "could not be parsed as a length of time"))
+            };
+
+            let mut query = MetricsQuery::recent(span, DEFAULT_METRIC_POINTS);
+            for arg in args {
+                if let Some(step) = arg.strip_prefix("step=") {
+                    query.density = match parse_span(step) {
+                        Some(v) => QueryDensity::Step(v),
+                        None => return Err(FormattingError::new(&step, "could not be parsed as a length of time"))
+                    };
+                }
+                else if let Ok(points) = arg.parse() {
+                    query.density = QueryDensity::MaxPoints(points);
+                }
+                else if let Some(family) = MetricFamily::from_name(arg) {
+                    query.families.push(family);
+                }
+                else if arg.chars().all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '.') {
+                    query.fields.push(arg.to_string());
+                }
+                else {
+                    return Err(FormattingError::new(&arg, "could not be parsed as a point count, step, family or field"));
+                }
+            }
+
+            Ok(Self::Metrics { query })
+        }
+        else if command == "top" {
+            let mut sort = ProcessSort::Cpu;
+            let mut amount = DEFAULT_TOP_AMOUNT;
+            for arg in args.split_whitespace() {
+                match arg {
+                    "cpu" => sort = ProcessSort::Cpu,
+                    "mem" | "memory" => sort = ProcessSort::Memory,
+                    _ => {
+                        amount = match arg.parse() {
+                            Ok(v) => v,
+                            Err(_) => return Err(FormattingError::new(&arg, "could not be parsed as a sort order or a number"))
+                        };
+                    }
+                }
+            }
+
+            Ok(Self::Top { sort, amount })
+        }
+        else if command == "watch" {
+            let mut families = vec![];
+            let mut every = 0;
+            for arg in args.split_whitespace() {
+                if let Some(family) = MetricFamily::from_name(arg) {
+                    families.push(family);
+                }
+                else {
+                    every = match parse_span(arg) {
+                        Some(v) => v,
+                        None => return Err(FormattingError::new(&arg, "could not be parsed as a family or a length of time"))
+                    };
+                }
+            }
+
+            Ok(Self::Watch { families, every })
         }
         else if lower == "h" || lower == "help" {
             Ok(Self::Help)
@@ -214,12 +334,66 @@ pub enum ConnectionFailure {
     Config,
     Quit,
     InvalidKey,
-    RsaRecv(RsaRecvError)
+    RsaRecv(RsaRecvError),
+    /// The server does not speak a compatible protocol version, for the reason given.
+    Incompatible(String),
+    /// The server turned the connection away (such as when it is serving too many clients).
+    Refused(ErrorResponse)
 }
 
-pub async fn perform_handshake<R, S>(logger: &Logger, rng: &mut R, mut stream: S) -> Result<AesStream<S>, ConnectionFailure> 
+/// Exchanges hellos with the server, which introduces itself first. This determines the protocol version & the requests that may be sent.
+async fn exchange_hello<S>(logger: &Logger, stream: &mut S) -> Result<Negotiated, ConnectionFailure>
+    where S: AsyncRead + AsyncWrite + Unpin {
+        let mut server_bytes: Vec<u8> = vec![];
+        if let Err(e) = receive_buffer_async(&mut server_bytes, stream).await {
+            log_error!(logger, "Unable to receive the server's hello, error '{:?}'.", &e);
+            return Err( ConnectionFailure::IO(e) )
+        }
+        let server_hello: Hello = match serde_json::from_slice(&server_bytes) {
+            Ok(v) => v,
+            Err(_) => {
+                if let Ok(e) = serde_json::from_slice::<ErrorResponse>(&server_bytes) {
+                    log_error!(logger, "The server refused the connection: {e}");
+                    return Err( ConnectionFailure::Refused(e) )
+                }
+
+                log_error!(logger, "The server did not send a hello, so it is likely running a version of regisd that predates protocol negotiation.");
+                return Err( ConnectionFailure::Incompatible("the server predates protocol negotiation".to_string()) )
+            }
+        };
+        log_debug!(logger, "Got hello from '{}'.", &server_hello.software);
+
+        let capabilities = [CAPABILITY_SUBSCRIBE, CAPABILITY_ALERTS, CAPABILITY_ANOMALIES, CAPABILITY_FORECAST]
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect();
+        let hello = Hello::new(format!("regis-cli {}", env!("CARGO_PKG_VERSION")), capabilities);
+        let hello_bytes = serde_json::to_vec(&hello).map_err(ConnectionFailure::Serde)?;
+        if let Err(e) = send_buffer_async(&hello_bytes, stream).await {
+            log_error!(logger, "Unable to send the hello '{:?}'", &e);
+            return Err( ConnectionFailure::IO(e) );
+        }
+
+        let mut response_bytes: Vec<u8> = vec![];
+        if let Err(e) = receive_buffer_async(&mut response_bytes, stream).await {
+            log_error!(logger, "Unable to receive the server's reply to the hello, error '{:?}'.", &e);
+            return Err( ConnectionFailure::IO(e) )
+        }
+        match serde_json::from_slice(&response_bytes).map_err(ConnectionFailure::Serde)? {
+            HelloResponse::Accepted(v) => Ok(v),
+            HelloResponse::Rejected { reason } => {
+                log_error!(logger, "The server rejected the connection, since {reason}.");
+                Err( ConnectionFailure::Incompatible(reason) )
+            }
+        }
+}
+
+/// Exchanges hellos and keys with the server, giving back the stream with the AES key to use on it.
+pub async fn perform_handshake<R, S>(logger: &Logger, rng: &mut R, mut stream: S) -> Result<(S, AesHandler, Negotiated), ConnectionFailure> 
     where S: AsyncRead + AsyncWrite + Unpin,
     R: RngCore + CryptoRng {
+        let negotiated = exchange_hello(logger, &mut stream).await?;
+
         let rsa_pub_priv = RsaHandler::new(rng).map_err(|x| {
             log_error!(logger, "Unable to create a RSA key, error '{x:?}'");
             return ConnectionFailure::InvalidKey
@@ -273,12 +447,41 @@ pub async fn perform_handshake<R, S>(logger: &Logger, rng: &mut R, mut stream: S
             }
         };
 
-        // Now we can use AES encryption streams
-        log_debug!(logger, "Switching to AES encrypted stream");
-        Ok( AesStream::new(rsa_stream.take().0, aes_key) )
+        Ok( (rsa_stream.take().0, aes_key, negotiated) )
+}
+
+/// A connection to the server. Messages are received on their own task, so that waiting for one can be given up (such as when a line is entered) without losing part of it.
+pub struct Connection {
+    stream: AesStream<TcpStream>,
+    responses: mpsc::Receiver<Result<ResponseEnvelope, AesRecvError>>,
+    reader: JoinHandle<()>
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Receives messages from the server, and forwards them to the connection. Receiving cannot be interrupted partway, so it is kept out of any `select!`.
+async fn receive_responses(mut stream: AesStream<TcpStream>, sender: mpsc::Sender<Result<ResponseEnvelope, AesRecvError>>) {
+    loop {
+        let msg = stream.receive_deserialize_async().await;
+        let failed = msg.is_err();
+        if sender.send(msg).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Opens a second handle to the same connection, so that it can be read and written separately.
+fn duplicate_stream(stream: TcpStream) -> std::io::Result<(TcpStream, TcpStream)> {
+    let stream = stream.into_std()?;
+    let other = stream.try_clone()?;
+
+    Ok( (TcpStream::from_std(stream)?, TcpStream::from_std(other)?) )
 }
 
-pub async fn connect<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout, logger: &Logger, rng: &mut R) -> Result<AesStream<TcpStream>, ConnectionFailure> 
+pub async fn connect<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout, logger: &Logger, rng: &mut R) -> Result<(Connection, Negotiated), ConnectionFailure> 
     where R: RngCore + CryptoRng {
     let host = match determine_dest_ip(lines, out, logger).await {
         Ok(v) => v,
@@ -301,20 +504,121 @@ pub async fn connect<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout, l
     };
 
     //Now the handshake
-    perform_handshake(logger, rng, stream).await
+    let (stream, aes_key, negotiated) = perform_handshake(logger, rng, stream).await?;
+
+    // Now we can use AES encryption streams. Messages are received separately from sending, so that pushed snapshots can be read while waiting for input.
+    log_debug!(logger, "Switching to AES encrypted streams");
+    let (recv_stream, send_stream) = match duplicate_stream(stream) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(logger, "Unable to duplicate the server stream, error '{e}'");
+            return Err( ConnectionFailure::IO(e) );
+        }
+    };
+    let recv_key = match AesHandler::from_bytes(aes_key.as_bytes()) {
+        Some(v) => v,
+        None => {
+            log_error!(logger, "Unable to copy the AES key.");
+            return Err( ConnectionFailure::InvalidKey );
+        }
+    };
+
+    let (sender, responses) = mpsc::channel(1);
+    let reader = tokio::spawn(receive_responses(AesStream::new(recv_stream, recv_key), sender));
+    Ok( (Connection { stream: AesStream::new(send_stream, aes_key), responses, reader }, negotiated) )
 }
 
 #[derive(Debug)]
 pub enum MainLoopFailure {
     IO(IOError),
     Send(AesSendError),
-    Recv(AesRecvError)
+    Recv(AesRecvError),
+    /// The messages from the server stopped without an error.
+    Closed
+}
+
+/// Sends requests to the server, numbering each so that its response can be told apart from others.
+#[derive(Default)]
+pub struct Requester {
+    next_id: RequestId
+}
+impl Requester {
+    /// Sends a request, giving back its id.
+    pub async fn send<R>(&mut self, logger: &Logger, rng: &mut R, stream: &mut AesStream<TcpStream>, request: RequestMessages) -> Result<RequestId, MainLoopFailure>
+        where R: RngCore + CryptoRng {
+            let id = self.next_id;
+            self.next_id += 1;
+
+            if let Err(e) = stream.send_serialize_async(&RequestEnvelope::new(id, request), rng).await {
+                log_error!(logger, "Unable to send request to server '{:?}'", &e);
+                return Err( MainLoopFailure::Send(e) );
+            }
+
+            Ok(id)
+    }
+}
+
+/// Receives the next message from the server.
+async fn receive(logger: &Logger, responses: &mut mpsc::Receiver<Result<ResponseEnvelope, AesRecvError>>) -> Result<ResponseEnvelope, MainLoopFailure> {
+    match responses.recv().await {
+        Some(Ok(v)) => Ok(v),
+        Some(Err(e)) => {
+            log_error!(logger, "Unable to decode message from server '{:?}'", &e);
+            Err( MainLoopFailure::Recv(e) )
+        }
+        None => {
+            log_error!(logger, "The messages from the server stopped.");
+            Err( MainLoopFailure::Closed )
+        }
+    }
+}
+
+/// Waits for the response to a specific request. Other messages that arrive first (such as pushed snapshots) are skipped.
+async fn receive_response(logger: &Logger, responses: &mut mpsc::Receiver<Result<ResponseEnvelope, AesRecvError>>, id: RequestId) -> Result<ResponseMessages, MainLoopFailure> {
+    loop {
+        let envelope = receive(logger, responses).await?;
+        if envelope.id == Some(id) {
+            return Ok(envelope.response);
+        }
+
+        log_debug!(logger, "Skipping message for request {:?} while waiting for #{id}", envelope.id);
+    }
 }
 
-pub async fn main_loop<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout, logger: &Logger, rng: &mut R, mut stream: AesStream<TcpStream>) -> Result<(), MainLoopFailure> 
+/// Prints the snapshots pushed by the server until a line is entered, and then unsubscribes.
+async fn watch<R>(lines: &mut Lines<BufReader<Stdin>>, logger: &Logger, rng: &mut R, connection: &mut Connection, requester: &mut Requester) -> Result<(), MainLoopFailure>
+    where R: RngCore + CryptoRng {
+        println!("Watching metrics, press enter to stop.\n");
+
+        let mut stopping: Option<RequestId> = None;
+        loop {
+            tokio::select! {
+                envelope = receive(logger, &mut connection.responses) => {
+                    let envelope = envelope?;
+                    match envelope.response {
+                        ResponseMessages::Snapshot(s) => println!("{s}"),
+                        ResponseMessages::Subscription(s) if !s.active && stopping.is_some() && envelope.id == stopping => return Ok(()),
+                        ResponseMessages::Error(e) if stopping.is_some() && envelope.id == stopping => {
+                            println!("Unable to stop watching: {e}");
+                            return Ok(());
+                        },
+                        _ => ()
+                    }
+                },
+                line = lines.next_line(), if stopping.is_none() => {
+                    line.map_err(MainLoopFailure::IO)?;
+
+                    stopping = Some( requester.send(logger, rng, &mut connection.stream, RequestMessages::Unsubscribe).await? );
+                }
+            }
+        }
+}
+
+pub async fn main_loop<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout, logger: &Logger, rng: &mut R, mut connection: Connection, negotiated: &Negotiated) -> Result<(), MainLoopFailure> 
     where R: RngCore + CryptoRng {
         println!("\n Type h or help for help, otherwise type commands.\n");
 
+        let mut requester = Requester::default();
         loop {
             let raw_message = prompt(out, lines).await
                 .map_err(MainLoopFailure::IO)?;
@@ -334,39 +638,85 @@ pub async fn main_loop<R>(lines: &mut Lines<BufReader<Stdin>>, out: &mut Stdout,
                 }
                 Commands::Help => {
                     println!("quit|exit|close -> Quits the program");
-                    println!("metrics AMOUNT -> Requests a specific number of collected metrics from the server.");
+                    println!("metrics SPAN [POINTS|step=STEP] [FAMILY|FIELD...] -> Requests the metrics over the last SPAN (such as 30m, 2h or 7d), in at most POINTS points ({DEFAULT_METRIC_POINTS} by default) or one per STEP.");
+                    println!("\t Families: cpu, memory, storage, diskio, network, sensors, cgroups, pressure, plugins. Fields are named like cpu.user or load.");
                     println!("status -> Requests the current status from the server.");
+                    println!("host -> Requests information about the server (OS, CPU, memory, uptime).");
+                    println!("top [cpu|mem] [AMOUNT] -> Requests the processes using the most CPU (default) or memory.");
+                    println!("alerts -> Requests the alerts raised by the server's alert rules.");
+                    println!("forecast -> Requests when the server's filesystems & memory are projected to be full.");
+                    println!("anomalies [SPAN] -> Requests the series that behaved unlike themselves over the last SPAN (1d by default).");
+                    println!("watch [EVERY] [FAMILY...] -> Shows each new snapshot as it is recorded (at most one per EVERY, such as 30s), until enter is pressed.");
                     continue;
                 }
-                Commands::Metrics { amount } => {
-                    RequestMessages::Metrics(amount)
+                Commands::Metrics { query } => {
+                    RequestMessages::Metrics(query)
                 }
                 Commands::Status => {
                     RequestMessages::Status
                 }
+                Commands::Top { sort, amount } => {
+                    RequestMessages::Processes { sort, limit: amount }
+                }
+                Commands::Host => {
+                    RequestMessages::HostInfo
+                }
+                Commands::Watch { families, every } => {
+                    RequestMessages::Subscribe { families, every }
+                }
+                Commands::Alerts => {
+                    RequestMessages::Alerts
+                }
+                Commands::Forecast => {
+                    RequestMessages::Forecast
+                }
+                Commands::Anomalies { span } => {
+                    RequestMessages::Anomalies { since: chrono::Utc::now() - chrono::Duration::seconds(span as i64) }
+                }
             };
 
-            if let Err(e) = stream.send_serialize_async(&message, rng).await {
-                log_error!(logger, "Unable to send request to server '{:?}'", &e);
-                return Err( MainLoopFailure::Send(e) );
+            if !negotiated.supports(&message) {
+                println!("The server does not support this request (it is older than this client, or the feature is disabled).");
+                continue;
             }
 
-            let response: ResponseMessages = match stream.receive_deserialize_async().await {
-                Ok(v) => v,
-                Err(e) => {
-                    log_error!(logger, "Unable to decode message from server '{:?}'", &e);
-                    return Err( MainLoopFailure::Recv(e));
-                }
-            };
+            let id = requester.send(logger, rng, &mut connection.stream, message).await?;
+            let response = receive_response(logger, &mut connection.responses, id).await?;
 
             match response {
                 //ResponseMessages::Ack(_) => (),
                 ResponseMessages::Metrics(m) => {
-                    println!("Current metrics:\n{m:#?}");
+                    println!("Metrics:\n{m}");
                 }
                 ResponseMessages::Status(s) => {
                     println!("Current status: {s:#?}");
                 }
+                ResponseMessages::Processes(p) => {
+                    println!("Processes:\n{p}");
+                }
+                ResponseMessages::HostInfo(h) => {
+                    println!("{h}");
+                }
+                ResponseMessages::Subscription(s) => {
+                    if s.active {
+                        watch(lines, logger, rng, &mut connection, &mut requester).await?;
+                    }
+                }
+                ResponseMessages::Snapshot(s) => {
+                    println!("{s}");
+                }
+                ResponseMessages::Alerts(a) => {
+                    println!("{a}");
+                }
+                ResponseMessages::Anomalies(a) => {
+                    println!("{a}");
+                }
+                ResponseMessages::Forecast(f) => {
+                    println!("{f}");
+                }
+                ResponseMessages::Error(e) => {
+                    println!("The server could not complete the request: {e}");
+                }
             }
         }
 }
@@ -383,13 +733,18 @@ pub async fn cli_entry(logger: &Logger) -> Result<(), ExitCode> {
     println!("Please connect to a host.");
 
     let mut rng = rand::thread_rng();
-    let connection = connect(&mut lines, &mut stdout, logger, &mut rng).await.map_err(|e| {
+    let (connection, negotiated) = connect(&mut lines, &mut stdout, logger, &mut rng).await.map_err(|e| {
+            match &e {
+                ConnectionFailure::Incompatible(reason) => println!("Unable to connect, since {reason}. Update regis-cli or the server's regisd so that their versions are compatible."),
+                ConnectionFailure::Refused(error) => println!("The server refused the connection: {error}"),
+                _ => ()
+            }
             log_error!(logger, "Unable to connect to a host '{:?}'", &e);
             return ExitCode::FAILURE;
         }
     )?;
 
-    main_loop(&mut lines, &mut stdout, logger, &mut rng, connection).await.map_err(|x| {
+    main_loop(&mut lines, &mut stdout, logger, &mut rng, connection, &negotiated).await.map_err(|x| {
         log_error!(logger, "Main loop exited with error '{x:?}'");
         return ExitCode::FAILURE
     })
@@ -310,6 +310,10 @@ L2: 'static + ConstructableLogger  {
             Some(m) => {
                 let message = match m {
                     Ok(v) => v,
+                    Err(ConnectionError::Daemon(e)) => {
+                        println!("regisd could not complete the request: {e}");
+                        continue;
+                    },
                     Err(e) => {
                         log_error!(&logger, "Unable to get the response due to error '{e:?}'");
                         continue;
@@ -322,7 +326,7 @@ L2: 'static + ConstructableLogger  {
                     CliCommands::Config(inner) => {
                         match inner {
                             ConfigCommands::Get => {
-                                let config_values: Option<DaemonConfig> = match serde_json::from_slice(&message) {
+                                let config: DaemonConfig = match serde_json::from_slice(&message) {
                                     Ok(v) => v,
                                     Err(e) => {
                                         log_error!(&logger, "Unable to decode the server's configurations. (error '{e:?}'");
@@ -330,12 +334,7 @@ L2: 'static + ConstructableLogger  {
                                     }
                                 };
 
-                                if let Some(config) = config_values {
-                                    println!("Console configuration:\n{config:#?}");
-                                }
-                                else {
-                                    println!("The daemon's configuration is not loaded properly.");
-                                }
+                                println!("Console configuration:\n{config:#?}");
                             },
                             ConfigCommands::Reload => println!("The daemon has been notified of the changed configuration."),
                             ConfigCommands::Update(_) => println!("The configuration has been updated.")
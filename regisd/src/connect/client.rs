@@ -1,22 +1,44 @@
+use std::collections::HashMap;
+use std::future::pending;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
-use common::msg::{MetricsResponse, RequestMessages, ResponseMessages, ServerStatusResponse, SignInMessage, SignInResponse};
+use common::msg::{AlertsResponse, AnomaliesResponse, ErrorCode, ErrorResponse, ForecastResponse, Hello, HelloResponse, HostInfoResponse, Negotiated, ProcessesResponse, QueryDensity, RequestEnvelope, RequestId, RequestMessages, ResponseEnvelope, ResponseMessages, ServerStatusResponse, SignInMessage, SignInResponse, SnapshotResponse, SubscriptionResponse};
+use common::msg::{CAPABILITY_ALERTS, CAPABILITY_ANOMALIES, CAPABILITY_FORECAST, CAPABILITY_SUBSCRIBE};
 use exdisj::{
-    auth::{AesHandler, AesStream, RsaHandler, RsaStream}, io::{
+    auth::{AesHandler, AesRecvError, AesStream, RsaHandler, RsaStream}, io::{
         lock::OptionRwProvider, log::{ConstructableLogger, Logger}, net::{receive_buffer_async, send_buffer_async}
     }, log_debug, log_error, log_info, log_warning, task::{ChildComm, TaskMessage, TaskOnce}
 };
-use rand::{CryptoRng, RngCore};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 use rsa_ext::RsaPublicKey;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::{Id, JoinSet};
+use tokio::time::{timeout, Duration};
 
 use common::usr::ClientUserInformation;
 use crate::auth::{app::ApprovalStatus, man::{AUTH, AuthManager}};
 use crate::config::CONFIG;
-use crate::metric::collect::collect_all_snapshots;
+use crate::metric::collect::{collect_all_snapshots, collect_processes, host_info, CollectedMetrics, MetricFamily};
+use crate::metric::alert::current_alerts;
+use crate::metric::anomaly::recent_anomalies;
+use crate::metric::forecast::current_forecasts;
+use crate::metric::apply_rates;
 use crate::metric::io::METRICS;
+use crate::metric::query::query_metrics;
+use crate::metric::subscribe::Subscription;
 use crate::msg::{SimpleComm, WorkerTaskResult};
+use crate::REGISD_VERSION;
+
+/// The most requests of one client that are served at once. Further requests are refused as busy until one completes.
+const MAX_IN_FLIGHT: usize = 16;
+/// The most processes sent in a single response, whatever the client asks for.
+const MAX_PROCESSES: usize = 200;
+/// The most points (or snapshots) a metrics query may ask for. Larger queries are refused, rather than read into memory.
+const MAX_QUERY_POINTS: u64 = 10_000;
+/// How long sending a single message may take. A client that stops reading is disconnected once this passes.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
 
 async fn setup_listener(addr: Ipv4Addr, logger: &impl Logger, port: &mut u16, max_clients: &mut usize, old_listener: Option<&mut TcpListener>) -> Result<Option<TcpListener>, WorkerTaskResult> {
     let old_port = *port;
@@ -84,8 +106,13 @@ pub async fn client_entry<L: ConstructableLogger + 'static>(logger: L, mut recv:
                 };
 
                 if active.len() >= max_clients {
-                    //Send message stating that the network is busy.
+                    // The client reads this in place of the hello.
                     log_info!(&logger, "Closing connection to '{}' because the max hosts has been reached.", &conn.1);
+                    let busy = ErrorResponse::new(ErrorCode::Busy, "The server is serving the most clients it allows.".to_string());
+                    let (mut stream, _) = conn;
+                    if let Ok(v) = serde_json::to_vec(&busy) {
+                        let _ = send_buffer_async(&v, &mut stream).await;
+                    }
 
                     continue;
                 }
@@ -171,9 +198,92 @@ pub async fn client_entry<L: ConstructableLogger + 'static>(logger: L, mut recv:
     result_status
 }
 
-async fn setup_handshake<R, L>(logger: &impl Logger, mut stream: TcpStream, auth: &AuthManager<L>, rng: &mut R) -> Option<AesStream<TcpStream>>
+/// Opens a second handle to the same connection, so that it can be read and written separately.
+fn duplicate_stream(stream: TcpStream) -> std::io::Result<(TcpStream, TcpStream)> {
+    let stream = stream.into_std()?;
+    let other = stream.try_clone()?;
+
+    Ok( (TcpStream::from_std(stream)?, TcpStream::from_std(other)?) )
+}
+
+/// The optional requests that this daemon serves, given its configuration.
+fn capabilities() -> Vec<String> {
+    let mut result = vec![CAPABILITY_SUBSCRIBE.to_string(), CAPABILITY_ALERTS.to_string()];
+    if let Some(config) = CONFIG.access().access() {
+        if config.anomalies.enabled {
+            result.push(CAPABILITY_ANOMALIES.to_string());
+        }
+        if config.forecast.enabled {
+            result.push(CAPABILITY_FORECAST.to_string());
+        }
+    }
+
+    result
+}
+
+/// Exchanges hellos with the client, before any keys are exchanged. This is `None` if the client is incompatible, or did not send a hello.
+async fn exchange_hello(logger: &impl Logger, stream: &mut TcpStream) -> Option<Negotiated> {
+    let hello = Hello::new(format!("regisd {REGISD_VERSION}"), capabilities());
+    let hello_bytes = match serde_json::to_vec(&hello) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(logger, "Unable to serialize the hello, error '{e:?}'");
+            return None;
+        }
+    };
+
+    log_debug!(logger, "Sending the hello to the client.");
+    if let Err(e) = send_buffer_async(&hello_bytes, stream).await {
+        log_error!(logger, "Unable to send the hello '{e:?}'");
+        return None;
+    }
+
+    let mut client_bytes: Vec<u8> = vec![];
+    if let Err(e) = receive_buffer_async(&mut client_bytes, stream).await {
+        log_error!(logger, "Unable to receive the client's hello, error '{e:?}'.");
+        return None;
+    }
+    let client_hello: Hello = match serde_json::from_slice(&client_bytes) {
+        Ok(v) => v,
+        Err(e) => {
+            log_warning!(logger, "The client did not send a valid hello ('{e}'), so it likely predates protocol negotiation. Closing the connection.");
+            return None;
+        }
+    };
+
+    let (response, result) = match hello.negotiate(&client_hello) {
+        Ok(v) => {
+            log_info!(logger, "Negotiated protocol version {} with '{}' (capabilities: {}).", v.protocol, &client_hello.software, v.capabilities.join(", "));
+            (HelloResponse::Accepted(v.clone()), Some(v))
+        },
+        Err(reason) => {
+            log_warning!(logger, "Rejecting the client, since {reason}.");
+            (HelloResponse::Rejected { reason }, None)
+        }
+    };
+
+    let response_bytes = match serde_json::to_vec(&response) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(logger, "Unable to serialize the hello response, error '{e:?}'");
+            return None;
+        }
+    };
+    if let Err(e) = send_buffer_async(&response_bytes, stream).await {
+        log_error!(logger, "Unable to send the hello response '{e:?}'");
+        return None;
+    }
+
+    result
+}
+
+/// Performs the key exchange, giving back the encrypted streams for receiving & sending (in that order), along with what was negotiated.
+async fn setup_handshake<R, L>(logger: &impl Logger, mut stream: TcpStream, auth: &AuthManager<L>, rng: &mut R) -> Option<(AesStream<TcpStream>, AesStream<TcpStream>, Negotiated)>
 where R: CryptoRng + RngCore,
 L: Logger + ?Sized {
+    // Versions are checked before anything else, so that an incompatible client is told why.
+    let negotiated = exchange_hello(logger, &mut stream).await?;
+
     // Send the RSA public key.
     let (pub_key, priv_key) = auth.get_rsa().clone().split();
     log_debug!(logger, "Serializing the RSA public key for the client");
@@ -218,16 +328,29 @@ L: Logger + ?Sized {
         return None;
     }
 
-    // Now we can use AES encryption streams
-    log_debug!(logger, "Switching to AES encrypted stream");
-    let aes_stream = AesStream::new(rsa_stream.take().0, aes_key);
+    // Now we can use AES encryption streams. Requests are received separately from sending, so that snapshots can be pushed while waiting for a request.
+    log_debug!(logger, "Switching to AES encrypted streams");
+    let (recv_stream, send_stream) = match duplicate_stream(rsa_stream.take().0) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(logger, "Unable to duplicate the client stream, error '{e}'");
+            return None;
+        }
+    };
+    let recv_key = match AesHandler::from_bytes(aes_key.as_bytes()) {
+        Some(v) => v,
+        None => {
+            log_error!(logger, "Unable to copy the AES key.");
+            return None;
+        }
+    };
 
-    Some( aes_stream )
+    Some( (AesStream::new(recv_stream, recv_key), AesStream::new(send_stream, aes_key), negotiated) )
 }
-async fn determine_user_sign_in<R, L>(logger: &impl Logger, aes_stream: &mut AesStream<TcpStream>, auth: &AuthManager<L>, rng: &mut R, ip: IpAddr) -> Option<ClientUserInformation>
+async fn determine_user_sign_in<R, L>(logger: &impl Logger, recv_stream: &mut AesStream<TcpStream>, send_stream: &mut AesStream<TcpStream>, auth: &AuthManager<L>, rng: &mut R, ip: IpAddr) -> Option<ClientUserInformation>
 where R: CryptoRng + RngCore,
 L: Logger + ?Sized {
-    let sign_in = match aes_stream.receive_deserialize_async().await {
+    let sign_in = match recv_stream.receive_deserialize_async().await {
         Ok(v) => v,
         Err(e) => {
             log_error!(logger, "Unable to decode handshake message from client '{e}'. Exiting");
@@ -243,7 +366,7 @@ L: Logger + ?Sized {
             match manager.sign_user_in(jwt, ip) {
                 Ok(Some(c)) => {
                     log_info!(logger, "User #{} signed in.", c.id());
-                    if let Err(e) = aes_stream.send_serialize_async(&SignInResponse::Approved, rng).await {
+                    if let Err(e) = send_stream.send_serialize_async(&SignInResponse::Approved, rng).await {
                         log_error!(logger, "Unable to send message: '{e}'");
                         return None;
                     }
@@ -252,12 +375,12 @@ L: Logger + ?Sized {
                 },
                 Ok(None) => {
                     log_error!(logger, "User could not be found.");
-                    let _ = aes_stream.send_serialize_async(&SignInResponse::UserNotFound, rng).await;
+                    let _ = send_stream.send_serialize_async(&SignInResponse::UserNotFound, rng).await;
                     return None;
                 },
                 Err(e) => {
                     log_error!(logger, "Unable to decode information: '{e}'.");
-                    let _ = aes_stream.send_serialize_async(&SignInResponse::ServerError, rng).await;
+                    let _ = send_stream.send_serialize_async(&SignInResponse::ServerError, rng).await;
                     return None;
                 }
             }
@@ -268,7 +391,7 @@ L: Logger + ?Sized {
             match status {
                 ApprovalStatus::Approved(v) => {
                     log_info!(logger, "User was approved.");
-                    if let Err(e) = aes_stream.send_serialize_async(&SignInResponse::Approved, rng).await {
+                    if let Err(e) = send_stream.send_serialize_async(&SignInResponse::Approved, rng).await {
                         log_error!(logger, "Unable to send message: '{e}'");
                         return None;
                     }
@@ -276,7 +399,7 @@ L: Logger + ?Sized {
                     return Some(v)
                 }
                 ApprovalStatus::Denied => {
-                    let _ = aes_stream.send_serialize_async(&SignInResponse::Denied, rng).await;
+                    let _ = send_stream.send_serialize_async(&SignInResponse::Denied, rng).await;
                     log_info!(logger, "User was denied entry. Exiting.");
                     return None;
                 }
@@ -285,70 +408,233 @@ L: Logger + ?Sized {
     }
 }
 
+/// Receives requests from the client, and forwards them to its worker. Receiving cannot be interrupted partway, so it is kept out of the worker's `select!`.
+/// Requests are forwarded undecoded, so that a request which cannot be understood is told apart from a broken connection.
+async fn receive_requests(mut stream: AesStream<TcpStream>, sender: mpsc::Sender<Result<serde_json::Value, AesRecvError>>) {
+    loop {
+        let msg = stream.receive_deserialize_async().await;
+        let failed = msg.is_err();
+        if sender.send(msg).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Waits for the next snapshot to push, if the client is subscribed.
+async fn next_update(subscription: &mut Option<Subscription>) -> Option<CollectedMetrics> {
+    match subscription {
+        Some(v) => v.next().await,
+        None => pending().await
+    }
+}
+
+/// Decodes a request, and determines if it can be served. The error holds the id of the request, if it could be read.
+fn accept_request(value: serde_json::Value, negotiated: &Negotiated, in_flight: usize, revoked: bool) -> Result<RequestEnvelope, (Option<RequestId>, ErrorResponse)> {
+    let id = value.get("id").and_then(|x| x.as_u64());
+    if revoked {
+        return Err( (id, ErrorResponse::new(ErrorCode::Unauthorized, "The user has been revoked.".to_string())) );
+    }
+    let msg: RequestEnvelope = match serde_json::from_value(value) {
+        Ok(v) => v,
+        Err(e) => return Err( (id, ErrorResponse::new(ErrorCode::BadRequest, format!("The request could not be decoded ({e})."))) )
+    };
+
+    if !negotiated.supports(&msg.request) {
+        return Err( (id, ErrorResponse::new(ErrorCode::Unsupported, "The request needs a capability that was not negotiated.".to_string())) );
+    }
+    if in_flight >= MAX_IN_FLIGHT {
+        return Err( (id, ErrorResponse::new(ErrorCode::Busy, format!("{MAX_IN_FLIGHT} requests are already being served."))) );
+    }
+
+    Ok(msg)
+}
+
+/// Serves a request of a signed in client.
+async fn serve_request(request: RequestMessages) -> Result<ResponseMessages, ErrorResponse> {
+    let response = match request {
+        RequestMessages::Metrics(query) => {
+            if query.from > query.to {
+                return Err( ErrorResponse::new(ErrorCode::BadRequest, "The start of the range is after its end.".to_string()) );
+            }
+            let points = match query.density {
+                QueryDensity::MaxPoints(n) => n as u64,
+                QueryDensity::Step(s) => query.span().div_ceil(s.max(1))
+            };
+            if points > MAX_QUERY_POINTS {
+                return Err( ErrorResponse::new(ErrorCode::BadRequest, format!("The query asks for {points} points, but at most {MAX_QUERY_POINTS} are allowed.")) );
+            }
+
+            match query_metrics(&query).await {
+                Some(v) => v.into(),
+                None => return Err( ErrorResponse::new(ErrorCode::Internal, "Unable to retrieve the metrics.".to_string()) )
+            }
+        },
+        RequestMessages::Status => {
+            let mut metrics = collect_all_snapshots().await;
+            if let Some(prev) = METRICS.latest() {
+                apply_rates(&mut metrics, &prev);
+                // Plugins & the sampled families are only collected on the metric interval, so their most recent values are used.
+                let reused: Vec<MetricFamily> = MetricFamily::ALL.into_iter()
+                    .filter(|x| *x == MetricFamily::Plugins || x.is_sampled())
+                    .collect();
+                metrics.copy_families(&reused, &prev);
+            }
+
+            ServerStatusResponse { info: metrics }.into()
+        },
+        RequestMessages::Processes { sort, limit } => {
+            let processes = collect_processes(sort, limit.min(MAX_PROCESSES)).await;
+            ProcessesResponse { info: processes }.into()
+        },
+        RequestMessages::HostInfo => {
+            HostInfoResponse { info: host_info().await }.into()
+        },
+        // The subscription itself is changed by the client's worker.
+        RequestMessages::Subscribe { .. } => {
+            SubscriptionResponse { active: true }.into()
+        },
+        RequestMessages::Unsubscribe => {
+            SubscriptionResponse { active: false }.into()
+        },
+        RequestMessages::Alerts => {
+            AlertsResponse { info: current_alerts() }.into()
+        },
+        RequestMessages::Anomalies { since } => {
+            AnomaliesResponse { info: recent_anomalies(since) }.into()
+        },
+        RequestMessages::Forecast => {
+            ForecastResponse { info: current_forecasts() }.into()
+        }
+    };
+
+    Ok(response)
+}
+
 async fn client_worker(logger: impl Logger, mut comm: ChildComm<()>, stream: TcpStream, ip: IpAddr) {
     let auth = AUTH.get().unwrap();
-    let mut aes_stream;
-    {
-        let mut rng_guard = auth.get_rng().await;
-        aes_stream = match setup_handshake(&logger, stream, auth, &mut *rng_guard).await {
-            Some(v) => v,
-            None => return
-        };
+    // Each connection has its own generator, so that a client which stops reading cannot hold up the others.
+    let mut rng = match StdRng::from_rng(&mut *auth.get_rng().await) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(&logger, "Unable to seed the connection's random generator '{e}'.");
+            return;
+        }
+    };
 
-        let status: ClientUserInformation = match determine_user_sign_in(&logger, &mut aes_stream, auth, &mut *rng_guard, ip).await {
-            Some(v) => v,
-            None => return
-        };
-        log_info!(&logger, "Signed In as user ID {}", status.id());
-    }
+    let (mut recv_stream, mut send_stream, negotiated) = match setup_handshake(&logger, stream, auth, &mut rng).await {
+        Some(v) => v,
+        None => return
+    };
+
+    let status: ClientUserInformation = match determine_user_sign_in(&logger, &mut recv_stream, &mut send_stream, auth, &mut rng, ip).await {
+        Some(v) => v,
+        None => return
+    };
+    log_info!(&logger, "Signed In as user ID {}", status.id());
+
+    let (sender, mut requests) = mpsc::channel(1);
+    let reader = tokio::spawn(receive_requests(recv_stream, sender));
+    let mut subscription: Option<Subscription> = None;
+    // Each request is served in its own task, so that slow requests (such as long metric queries) do not hold up quick ones.
+    let mut in_flight: JoinSet<Result<ResponseMessages, ErrorResponse>> = JoinSet::new();
+    // The request served by each task.
+    let mut in_flight_ids: HashMap<Id, RequestId> = HashMap::new();
 
     loop {
-        select! {
+        let response: ResponseEnvelope = select! {
             v = comm.recv() => {
                 match v {
-                    TaskMessage::Kill => return,
+                    TaskMessage::Kill => break,
                     TaskMessage::Poll | TaskMessage::Inner(_) => continue,
                 }
             },
-            raw_msg = aes_stream.receive_deserialize_async() => {
-                let msg: RequestMessages = match raw_msg {
+            update = next_update(&mut subscription) => {
+                match update {
+                    Some(v) => ResponseEnvelope::push(SnapshotResponse { info: v }.into()),
+                    None => {
+                        log_warning!(&logger, "Snapshots are no longer being sent, ending the subscription.");
+                        subscription = None;
+                        continue;
+                    }
+                }
+            },
+            Some(joined) = in_flight.join_next_with_id(), if !in_flight.is_empty() => {
+                let (task, served) = match joined {
                     Ok(v) => v,
                     Err(e) => {
-                        log_error!(&logger, "Unable to decode message from bound client '{e}'. Exiting.");
-                        return;
+                        log_error!(&logger, "A request task failed '{e}'.");
+                        (e.id(), Err( ErrorResponse::new(ErrorCode::Internal, "The request failed unexpectedly.".to_string()) ))
+                    }
+                };
+                let id = match in_flight_ids.remove(&task) {
+                    Some(v) => v,
+                    None => continue
+                };
+
+                match served {
+                    Ok(v) => ResponseEnvelope::reply(id, v),
+                    Err(e) => {
+                        log_warning!(&logger, "Request #{id} failed: {e}");
+                        ResponseEnvelope::reply(id, e.into())
+                    }
+                }
+            },
+            raw_msg = requests.recv() => {
+                let value = match raw_msg {
+                    Some(Ok(v)) => v,
+                    Some(Err(e)) => {
+                        log_error!(&logger, "Unable to receive message from bound client '{e}'. Exiting.");
+                        break;
                     }
+                    None => break
                 };
-    
-                log_info!(&logger, "Serving request '{:?}'", &msg);
-    
-                let response: ResponseMessages = match msg {
-                    RequestMessages::Metrics(amount) => {
-                        let collected = METRICS.view(amount);
-    
-                        let to_send = if let Some(c) = collected {
-                            c
+                // The user may have been revoked since signing in, in which case nothing more is served.
+                let revoked = auth.get_provision().await.as_ref().is_user_revoked(status.id());
+                if revoked {
+                    subscription = None;
+                }
+
+                match accept_request(value, &negotiated, in_flight.len(), revoked) {
+                    Ok(msg) => {
+                        log_info!(&logger, "Serving request #{} '{:?}'", msg.id, &msg.request);
+
+                        // The subscription belongs to the connection, so it is changed as soon as the request is read.
+                        match &msg.request {
+                            RequestMessages::Subscribe { families, every } => {
+                                subscription = Some( Subscription::new(METRICS.subscribe(), families.clone(), *every) );
+                            },
+                            RequestMessages::Unsubscribe => subscription = None,
+                            _ => ()
                         }
-                        else {
-                            log_warning!(&logger, "Unable to retrieve metrics. Resetting metrics.");
-                            METRICS.reset();
-                            vec![]
-                        };
-    
-                        MetricsResponse { info: to_send }.into()
+
+                        let RequestEnvelope { id, request } = msg;
+                        let task = in_flight.spawn(serve_request(request));
+                        in_flight_ids.insert(task.id(), id);
+                        continue;
                     },
-                    RequestMessages::Status => {
-                        let metrics = collect_all_snapshots().await;
-                        ServerStatusResponse { info: metrics }.into()
+                    Err((id, e)) => {
+                        log_warning!(&logger, "Refusing request {id:?}: {e}");
+                        ResponseEnvelope { id, response: e.into() }
                     }
-                };
-    
-                log_debug!(&logger, "Sending response message...");
-                let mut rng_guard = auth.get_rng().await;
-                if let Err(e) = aes_stream.send_serialize_async(&response, &mut *rng_guard).await {
-                    log_error!(&logger, "Unable to send message to client '{e}'.");
-                    return;
                 }
             }
+        };
+
+        log_debug!(&logger, "Sending response message...");
+        match timeout(SEND_TIMEOUT, send_stream.send_serialize_async(&response, &mut rng)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                log_error!(&logger, "Unable to send message to client '{e}'.");
+                break;
+            },
+            Err(_) => {
+                // Part of the message may have been written, so the connection cannot be used again.
+                log_warning!(&logger, "The client has not read its messages in {}s, closing the connection.", SEND_TIMEOUT.as_secs());
+                break;
+            }
         }
     }
+
+    // The connection stays open until the receiving stream is dropped as well.
+    reader.abort();
 }
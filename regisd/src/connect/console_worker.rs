@@ -6,15 +6,26 @@ use tokio::{
 
 use exdisj::{
     io::{
-        lock::OptionRwProvider, log::Logger, msg::{decode_message_async, send_message_async}, net::send_buffer_async
-    }, log_debug, log_error, task::{ChildComm, TaskMessage}
+        lock::OptionRwProvider, log::Logger, msg::{decode_message_async, send_message_async, DecodeError}, net::send_buffer_async
+    }, log_debug, log_error, log_warning, task::{ChildComm, TaskMessage}
 };
 use common::{
-    config::ClientConfig, msg::{ConsoleAuthRequests, ConsoleConfigRequests, ConsoleFlatRequests, ConsoleRequests, UserDetails, UserSummary}
+    msg::{ConsoleAuthRequests, ConsoleConfigRequests, ConsoleFlatRequests, ConsoleRequests, ConsoleResponse, ErrorCode, ErrorResponse, UserDetails, UserSummary}
 };
 
 use crate::{auth::man::{AUTH, AuthManager}, config::CONFIG, msg::ConsoleComm};
 
+/// Sends an error in place of the response. This is `false` if it could not be sent.
+async fn send_error(logger: &impl Logger, source: &mut UnixStream, error: ErrorResponse) -> bool {
+    log_warning!(logger, "Console request failed: {error}");
+    if let Err(e) = send_message_async(ConsoleResponse::<()>::Err(error), source).await {
+        log_error!(logger, "Unable to send error message back to console connection: '{e}'.");
+        return false;
+    }
+
+    true
+}
+
 async fn decode_auth_request<L>(v: ConsoleAuthRequests, logger: &impl Logger, source: &mut UnixStream, auth: &AuthManager<L>) -> bool 
 where L: Logger + ?Sized{
      match v {
@@ -28,7 +39,7 @@ where L: Logger + ?Sized{
                     .collect()
             };
 
-            if let Err(e) = send_message_async(result,  source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result),  source).await {
                 log_error!(logger, "Unable to send ok message back to console connection: '{e}'.");
                 return false;
             };
@@ -43,10 +54,14 @@ where L: Logger + ?Sized{
                         user.nickname().to_string(),
                         user.history().to_vec() 
                     )
-                );
+                )
+            };
+            let result = match result {
+                Some(v) => v,
+                None => return send_error(logger, source, ErrorResponse::new(ErrorCode::NotFound, format!("There is no user with id {id}."))).await
             };
 
-            if let Err(e) = send_message_async(result, source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result), source).await {
                 log_error!(logger, "Unable to send ok message back to console connection: '{e}'.");
                 return false;
             }
@@ -61,7 +76,7 @@ where L: Logger + ?Sized{
                     .collect()
             };
 
-            if let Err(e) = send_message_async(result, source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result), source).await {
                 log_error!(logger, "Unable to send message back to console connection: '{e}'.");
                 return false;
             }
@@ -73,7 +88,7 @@ where L: Logger + ?Sized{
                 provision.as_mut().revoke_user(id)
             };
 
-            if let Err(e) = send_message_async(result, source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result), source).await {
                 log_error!(logger, "Unable to send message back to console connection: '{e}'.");
                 return false;
             }
@@ -86,7 +101,7 @@ where L: Logger + ?Sized{
                 provision.as_mut().approvals().approve_user(id, name, &mut *rng)
             };
 
-            if let Err(e) = send_message_async(result, source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result), source).await {
                 log_error!(logger, "Unable to send message back to console connection: '{e}'.");
                 return false;
             }
@@ -98,7 +113,7 @@ where L: Logger + ?Sized{
                 provision.as_mut().approvals().deny(id)
             };
 
-            if let Err(e) = send_message_async(result, source).await {
+            if let Err(e) = send_message_async(ConsoleResponse::Ok(result), source).await {
                 log_error!(logger, "Unable to send message back to console connection: '{e}'.");
                 return false;
             }
@@ -123,6 +138,19 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
             raw_msg = decode_message_async(&mut source) => {
                 let msg: ConsoleRequests = match raw_msg {
                     Ok(v) => v,
+                    // The message was received whole, so the connection can still be used.
+                    Err(DecodeError::Serde(e)) => {
+                        if !send_error(&logger, &mut source, ErrorResponse::new(ErrorCode::BadRequest, format!("The request could not be decoded ({e})."))).await {
+                            return;
+                        }
+                        continue;
+                    },
+                    Err(DecodeError::UTF(e)) => {
+                        if !send_error(&logger, &mut source, ErrorResponse::new(ErrorCode::BadRequest, format!("The request is not valid UTF-8 ({e})."))).await {
+                            return;
+                        }
+                        continue;
+                    },
                     Err(e) => {
                         log_error!(&logger, "Unable to decode message from bound client '{e}'");
                         return;
@@ -134,7 +162,7 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
                 let flat = msg.flatten();
                 match msg {
                     ConsoleRequests::Poll => {
-                        if let Err(e) = send_message_async((), &mut source).await {
+                        if let Err(e) = send_message_async(ConsoleResponse::Ok(()), &mut source).await {
                             log_error!(&logger, "Unable to send ok message back to console connection: '{e}'.");
                             return;
                         }
@@ -148,10 +176,11 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
 
                         if let Err(e) = sender.send(top_request).await {
                             log_error!(&logger, "Unable to send message to console manager: '{e}'.");
+                            send_error(&logger, &mut source, ErrorResponse::new(ErrorCode::Internal, "The daemon could not be notified of the request.".to_string())).await;
                             return;
                         }
 
-                        if let Err(e) = send_message_async((), &mut source).await {
+                        if let Err(e) = send_message_async(ConsoleResponse::Ok(()), &mut source).await {
                             log_error!(&logger, "Unable to send ok message back to console connection: '{e}'.");
                             return;
                         }
@@ -159,15 +188,19 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
                     ConsoleRequests::Config(ConsoleConfigRequests::Get) => {
                         let result = {
                             let config = CONFIG.access();
-                            match serde_json::to_vec(&config.access()) {
-                                Ok(v) => {
-                                    log_debug!(&logger, "Got configuration value. Is some? {}", config.access().is_some());
-                                    v
-                                }
-                                Err(e) => {
-                                    log_error!(&logger, "Unable to serialize the value (error: '{e:?}', returning None.");
-                                    serde_json::to_vec::<Option<&ClientConfig>>(&None).expect("unable to serialize none???")
+                            match config.access().map(|x| serde_json::to_vec(&ConsoleResponse::Ok(x))) {
+                                Some(Ok(v)) => Ok(v),
+                                Some(Err(e)) => Err( ErrorResponse::new(ErrorCode::Internal, format!("The configuration could not be serialized ({e}).")) ),
+                                None => Err( ErrorResponse::new(ErrorCode::Internal, "The configuration is not loaded.".to_string()) )
+                            }
+                        };
+                        let result = match result {
+                            Ok(v) => v,
+                            Err(e) => {
+                                if !send_error(&logger, &mut source, e).await {
+                                    return;
                                 }
+                                continue;
                             }
                         };
 
@@ -177,13 +210,14 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
                         }
                     },
                     ConsoleRequests::Config(ConsoleConfigRequests::Set(new_config)) => {
-                        CONFIG.direct_set(new_config);
+                        CONFIG.direct_set(*new_config);
                         if let Err(e) = sender.send(ConsoleComm::ConfigReload(false)).await {
                             log_error!(&logger, "Unable to send message to console manager: '{e}'.");
+                            send_error(&logger, &mut source, ErrorResponse::new(ErrorCode::Internal, "The daemon could not be notified of the new configuration.".to_string())).await;
                             return;
                         }
 
-                        if let Err(e) = send_message_async(true, &mut source).await {
+                        if let Err(e) = send_message_async(ConsoleResponse::Ok(true), &mut source).await {
                             log_error!(&logger, "Unable to send back result of configuration set to console connection '{e:?}'.");
                             return;
                         }
@@ -202,6 +236,8 @@ pub(crate) async fn console_worker(logger: impl Logger, mut comm: ChildComm<()>,
 
 #[test]
 fn config_get_test() {
+    use common::config::ClientConfig;
+
     CONFIG.open(common::loc::DAEMON_CONFIG_PATH).expect("Unable to open config.");
     let logger = exdisj::io::log::NullLogger;
 
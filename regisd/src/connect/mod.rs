@@ -1,3 +1,4 @@
 pub mod client;
 pub mod console;
-pub mod console_worker;
\ No newline at end of file
+pub mod console_worker;
+pub mod prometheus;
\ No newline at end of file
@@ -0,0 +1,316 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::config::PrometheusConfig;
+use common::metric::CollectedMetrics;
+use exdisj::{
+    io::{lock::OptionRwProvider, log::Logger}, log_debug, log_error, log_info, log_warning, task::{ChildComm, TaskMessage}
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::config::CONFIG;
+use crate::metric::io::METRICS;
+use crate::metric::prometheus::{render, CONTENT_TYPE};
+use crate::msg::{SimpleComm, WorkerTaskResult};
+
+/// The largest request head that is read from a scraper.
+const MAX_HEAD: usize = 8 * 1024;
+/// How long a scraper has to send its request & receive the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most scrapes that are served at once. Further connections are closed until one finishes.
+const MAX_SCRAPES: usize = 8;
+
+/// A response to a scrape request.
+#[derive(Debug, PartialEq)]
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+    body: String
+}
+impl HttpResponse {
+    fn text(status: u16, reason: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            headers: vec![],
+            body: format!("{body}\n")
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut result = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        );
+        for (key, value) in self.headers {
+            result.push_str(&format!("{key}: {value}\r\n"));
+        }
+        result.push_str("\r\n");
+        result.push_str(&self.body);
+
+        result.into_bytes()
+    }
+}
+
+/// Compares two secrets without stopping at the first difference, so that the time taken does not reveal how much of a guess was right.
+fn secrets_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Determines the response to a request head, from a scraper at `peer`.
+fn respond(config: &PrometheusConfig, peer: &IpAddr, head: &str, latest: impl FnOnce() -> Option<CollectedMetrics>) -> HttpResponse {
+    if !config.allows(peer) {
+        return HttpResponse::text(403, "Forbidden", "forbidden");
+    }
+
+    let mut lines = head.lines();
+    let mut request = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request.next(), request.next()) {
+        (Some(m), Some(t)) => (m, t),
+        _ => return HttpResponse::text(400, "Bad Request", "bad request")
+    };
+
+    if let Some(token) = config.token.as_deref() {
+        let authorized = lines
+            .filter_map(|x| x.split_once(':'))
+            .filter(|(key, _)| key.trim().eq_ignore_ascii_case("authorization"))
+            .any(|(_, value)| value.trim().strip_prefix("Bearer ").is_some_and(|x| secrets_match(x, token)));
+
+        if !authorized {
+            let mut response = HttpResponse::text(401, "Unauthorized", "unauthorized");
+            response.headers.push(("WWW-Authenticate", "Bearer"));
+            return response;
+        }
+    }
+
+    let path = target.split('?').next().unwrap_or_default();
+    if path != "/metrics" {
+        return HttpResponse::text(404, "Not Found", "not found");
+    }
+    if method != "GET" {
+        let mut response = HttpResponse::text(405, "Method Not Allowed", "method not allowed");
+        response.headers.push(("Allow", "GET"));
+        return response;
+    }
+
+    match latest() {
+        Some(metrics) => HttpResponse {
+            status: 200,
+            reason: "OK",
+            content_type: CONTENT_TYPE,
+            headers: vec![],
+            body: render(&metrics)
+        },
+        None => HttpResponse::text(503, "Service Unavailable", "no metrics have been collected yet")
+    }
+}
+#[test]
+fn test_prometheus_responses() {
+    use std::net::Ipv4Addr;
+
+    let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let config = PrometheusConfig {
+        allowlist: vec![local],
+        token: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let some = || Some(CollectedMetrics::default());
+
+    assert_eq!(respond(&config, &other, "GET /metrics HTTP/1.1\r\n", some).status, 403);
+    assert_eq!(respond(&config, &local, "GET /metrics HTTP/1.1\r\nHost: x\r\n", some).status, 401);
+    assert_eq!(respond(&config, &local, "GET /metrics HTTP/1.1\r\nAuthorization: Bearer wrong\r\n", some).status, 401);
+
+    let authorized = |head: &str| format!("{head}\r\nauthorization: Bearer secret\r\n");
+    assert_eq!(respond(&config, &local, &authorized("GET /other HTTP/1.1"), some).status, 404);
+    assert_eq!(respond(&config, &local, &authorized("POST /metrics HTTP/1.1"), some).status, 405);
+    assert_eq!(respond(&config, &local, &authorized("GET /metrics HTTP/1.1"), || None).status, 503);
+
+    let ok = respond(&config, &local, &authorized("GET /metrics?x=1 HTTP/1.1"), some);
+    assert_eq!(ok.status, 200);
+    assert_eq!(ok.content_type, CONTENT_TYPE);
+    assert!(ok.body.contains("regis_snapshot_timestamp_seconds"));
+
+    assert!(secrets_match("secret", "secret"));
+    assert!(!secrets_match("secreT", "secret") && !secrets_match("secret2", "secret") && !secrets_match("", "secret"));
+
+    // Without an allowlist or token, anyone may scrape.
+    assert_eq!(respond(&PrometheusConfig::default(), &other, "GET /metrics HTTP/1.1\r\n", some).status, 200);
+
+    // Such an endpoint is only started on a loopback address.
+    let open = PrometheusConfig { bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED), ..Default::default() };
+    assert!(!PrometheusConfig::default().is_unrestricted() && !config.is_unrestricted());
+    assert!(open.is_unrestricted());
+    assert!(!PrometheusConfig { token: Some("secret".to_string()), ..open }.is_unrestricted());
+}
+
+/// Reads the request head, stopping at the blank line or the size limit.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while buffer.len() < MAX_HEAD && !buffer.windows(4).any(|x| x == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok( String::from_utf8_lossy(&buffer).into_owned() )
+}
+
+/// Answers a single scrape, giving back the status of the response.
+async fn serve(config: PrometheusConfig, mut stream: TcpStream, peer: SocketAddr) -> std::io::Result<u16> {
+    let result = timeout(REQUEST_TIMEOUT, async {
+        let head = read_head(&mut stream).await?;
+        let response = respond(&config, &peer.ip(), &head, || METRICS.latest());
+        let status = response.status;
+
+        stream.write_all(&response.into_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(status)
+    }).await;
+
+    match result {
+        Ok(v) => v,
+        Err(_) => Err( std::io::Error::new(ErrorKind::TimedOut, "the scrape timed out") )
+    }
+}
+
+/// Opens the listener described by the configuration, if the endpoint is enabled.
+async fn setup_listener(logger: &impl Logger, config: &PrometheusConfig) -> Result<Option<TcpListener>, WorkerTaskResult> {
+    if !config.enabled {
+        log_info!(logger, "The Prometheus endpoint is disabled.");
+        return Ok(None);
+    }
+    if config.is_unrestricted() {
+        log_error!(logger, "The Prometheus endpoint would be open to anyone on '{}', so it is not started. Set an allowlist or token, or bind it to a loopback address.", config.bind);
+        return Ok(None);
+    }
+
+    let addr = SocketAddr::new(config.bind, config.port);
+    match TcpListener::bind(addr).await {
+        Ok(v) => {
+            log_info!(logger, "Serving Prometheus metrics on '{addr}'.");
+            Ok(Some(v))
+        },
+        Err(e) => {
+            log_error!(logger, "Unable to open the Prometheus listener on '{addr}' '{e}', exiting task.");
+            Err(WorkerTaskResult::Sockets)
+        }
+    }
+}
+
+fn get_config() -> Option<PrometheusConfig> {
+    CONFIG.access()
+        .access()
+        .map(|x| x.prometheus.clone())
+}
+
+pub async fn prometheus_entry(logger: impl Logger, mut recv: ChildComm<SimpleComm>) -> WorkerTaskResult {
+    let mut config = match get_config() {
+        Some(v) => v,
+        None => return WorkerTaskResult::Configuration
+    };
+    let mut listener = match setup_listener(&logger, &config).await {
+        Ok(v) => v,
+        Err(e) => return e
+    };
+
+    // Each scrape is served on its own task, so that a slow or idle peer does not hold up the others.
+    let limit = Arc::new(Semaphore::new(MAX_SCRAPES));
+    let mut scrapes: JoinSet<(SocketAddr, std::io::Result<u16>)> = JoinSet::new();
+
+    loop {
+        select! {
+            Some(joined) = scrapes.join_next(), if !scrapes.is_empty() => {
+                match joined {
+                    Ok((peer, Ok(status))) => log_debug!(&logger, "Scrape from '{peer}' answered with {status}."),
+                    Ok((peer, Err(e))) => log_warning!(&logger, "Unable to serve scrape from '{peer}': '{e}'"),
+                    Err(e) => log_warning!(&logger, "A scrape task failed '{e}'.")
+                }
+            },
+            conn = async {
+                match listener.as_ref() {
+                    Some(l) => l.accept().await,
+                    None => std::future::pending().await
+                }
+            } => {
+                match conn {
+                    Ok((stream, peer)) => {
+                        // Peers that may not scrape are closed without anything being read from them.
+                        if !config.allows(&peer.ip()) {
+                            log_debug!(&logger, "Closing connection from '{peer}', which is not in the allowlist.");
+                            continue;
+                        }
+                        let permit = match limit.clone().try_acquire_owned() {
+                            Ok(v) => v,
+                            Err(_) => {
+                                log_warning!(&logger, "Closing connection from '{peer}', since {MAX_SCRAPES} scrapes are already being served.");
+                                continue;
+                            }
+                        };
+
+                        let config = config.clone();
+                        scrapes.spawn(async move {
+                            let result = serve(config, stream, peer).await;
+                            drop(permit);
+                            (peer, result)
+                        });
+                    },
+                    Err(e) => {
+                        log_error!(&logger, "Unable to accept from listener '{e}', exiting task.");
+                        return WorkerTaskResult::Sockets;
+                    }
+                }
+            },
+            m = recv.recv() => {
+                match m {
+                    TaskMessage::Poll => continue,
+                    TaskMessage::Kill => {
+                        log_info!(&logger, "Got shutdown message from Orch.");
+                        break;
+                    }
+                    TaskMessage::Inner(SimpleComm::ReloadConfiguration) => {
+                        let new_config = match get_config() {
+                            Some(v) => v,
+                            None => {
+                                log_error!(&logger, "Unable to retrive configuration. Exiting task.");
+                                return WorkerTaskResult::Configuration;
+                            }
+                        };
+
+                        let rebind = new_config.enabled != config.enabled || new_config.bind != config.bind || new_config.port != config.port
+                            || new_config.is_unrestricted() != config.is_unrestricted();
+                        config = new_config;
+                        if rebind {
+                            // The old listener must be closed first, since the new one may use the same address.
+                            drop(listener.take());
+                            listener = match setup_listener(&logger, &config).await {
+                                Ok(v) => v,
+                                Err(e) => return e
+                            };
+                        }
+
+                        log_info!(&logger, "Configuration reloaded.");
+                    }
+                }
+            }
+        }
+    }
+
+    WorkerTaskResult::Ok
+}
@@ -7,6 +7,9 @@ pub mod failure;
 pub mod setup;
 pub mod auth;
 
+/// The version of regisd, reported to clients in the host information.
+pub const REGISD_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 use exdisj::{log_critical, log_info, log_warning};
 use exdisj::io::lock::OptionRwProvider;
 use common::loc::DAEMON_CONFIG_PATH;
@@ -0,0 +1,334 @@
+/*
+    Alerting
+
+    Every snapshot is checked against the alert rules in the configuration. Each series matched by a rule is tracked
+    separately: once a level is exceeded the alert is pending, and it fires after the level has been exceeded for the
+    rule's duration. A firing alert is resolved once the value returns past its level by the rule's hysteresis, or
+    once its series is no longer reported. If a family was not collected in full, the values it did report are still
+    checked, but the alerts of its missing series are left as they are.
+    Resolved alerts are kept for a while so that clients can see them. The alerts are saved whenever one changes
+    state, so that they are kept across restarts.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::fs::{read_to_string, write};
+
+use common::alert::{Alert, AlertSeverity, AlertState};
+use common::config::AlertRule;
+
+use super::collect::CollectedMetrics;
+use super::series::{incomplete_families, series, series_family};
+
+/// In seconds, how long resolved alerts are kept.
+const RESOLVED_RETENTION: i64 = 60 * 60;
+
+lazy_static::lazy_static! {
+    /// The alerts as of the most recent snapshot, so that they can be sent to clients.
+    static ref CURRENT_ALERTS: RwLock<Vec<Alert>> = RwLock::new(vec![]);
+}
+
+/// The alerts as of the most recent snapshot, most severe first.
+pub fn current_alerts() -> Vec<Alert> {
+    match CURRENT_ALERTS.read() {
+        Ok(v) => v.clone(),
+        Err(e) => e.into_inner().clone()
+    }
+}
+
+/// Splits a series key or selector, such as `cpu.user{core=all}`, into its name & tags.
+fn split_series(key: &str) -> (&str, Vec<&str>) {
+    match key.split_once('{') {
+        Some((name, tags)) => (name, tags.trim_end_matches('}').split(',').filter(|x| !x.is_empty()).collect()),
+        None => (key, vec![])
+    }
+}
+
+/// Determines if a series is selected by a rule. The names must match, and the series must have every tag in the selector.
+pub fn matches_selector(selector: &str, key: &str) -> bool {
+    let (name, tags) = split_series(selector);
+    let (key_name, key_tags) = split_series(key);
+
+    name == key_name && tags.iter().all(|x| key_tags.contains(x))
+}
+
+/// The severity that `value` is at for a rule, given the severity it was at before (if any).
+fn severity_of(rule: &AlertRule, value: f64, previous: Option<AlertSeverity>) -> Option<AlertSeverity> {
+    let levels = [(AlertSeverity::Critical, rule.critical), (AlertSeverity::Warning, rule.warn)];
+    for (severity, level) in levels {
+        let level = match level {
+            Some(v) => v,
+            None => continue
+        };
+
+        // Once a level is reached, it is kept until the value returns past the hysteresis.
+        let held = previous.is_some_and(|x| x >= severity) && rule.comparison.holds(value, level, rule.hysteresis);
+        if rule.comparison.exceeds(value, level) || held {
+            return Some(severity);
+        }
+    }
+
+    None
+}
+
+/// Evaluates the alert rules against every snapshot.
+pub struct AlertEngine {
+    path: PathBuf,
+    rules: Vec<AlertRule>,
+    /// Keyed by the rule name & series.
+    alerts: BTreeMap<(String, String), Alert>
+}
+impl AlertEngine {
+    /// Opens the engine, picking up the alerts saved at `path` whose rules still exist.
+    pub async fn open(path: PathBuf, rules: Vec<AlertRule>) -> std::io::Result<Self> {
+        let saved: Vec<Alert> = match read_to_string(&path).await {
+            Ok(v) => serde_json::from_str(&v).unwrap_or_default(),
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e)
+        };
+
+        let mut result = Self {
+            path,
+            rules: vec![],
+            alerts: saved.into_iter()
+                .map(|x| ((x.rule.clone(), x.series.clone()), x))
+                .collect()
+        };
+        result.set_rules(rules);
+
+        Ok(result)
+    }
+
+    /// Replaces the rules, removing the alerts of any rule that no longer exists.
+    pub fn set_rules(&mut self, rules: Vec<AlertRule>) {
+        self.alerts.retain(|(rule, _), _| rules.iter().any(|x| &x.name == rule));
+        self.rules = rules;
+        self.publish();
+    }
+
+    /// Makes the alerts available to clients.
+    fn publish(&self) {
+        let mut alerts: Vec<Alert> = self.alerts.values().cloned().collect();
+        alerts.sort_by(|a, b| (a.state == AlertState::Resolved, b.severity).cmp(&(b.state == AlertState::Resolved, a.severity)));
+
+        match CURRENT_ALERTS.write() {
+            Ok(mut v) => *v = alerts,
+            Err(e) => *e.into_inner() = alerts
+        }
+    }
+
+    /// Checks a snapshot, along with the series derived from the history (such as forecasts), against the rules. This returns the alerts that changed state (or severity, while firing).
+    pub fn evaluate(&mut self, metrics: &CollectedMetrics, derived: &[(String, f64)]) -> Vec<Alert> {
+        let time = metrics.time;
+        // The series missing from a family that was not collected in full are unknown, rather than gone.
+        let incomplete = incomplete_families(metrics);
+        let is_held = |key: &str| series_family(key).is_some_and(|x| incomplete.contains(&x));
+
+        let mut values = series(metrics);
+        values.extend_from_slice(derived);
+
+        let mut changed = vec![];
+        let mut seen = BTreeSet::new();
+        for rule in &self.rules {
+            for (key, value) in values.iter().filter(|x| matches_selector(&rule.selector, &x.0)) {
+                let id = (rule.name.clone(), key.clone());
+                let previous = self.alerts.get(&id).filter(|x| x.state != AlertState::Resolved);
+
+                let severity = severity_of(rule, *value, previous.map(|x| x.severity));
+                if let Some(alert) = Self::step(rule, previous, key, *value, severity, time) {
+                    let notify = match previous {
+                        Some(v) => v.state != alert.state || (alert.state == AlertState::Firing && v.severity != alert.severity),
+                        None => true
+                    };
+                    if notify {
+                        changed.push(alert.clone());
+                    }
+                    self.alerts.insert(id.clone(), alert);
+                }
+                else if previous.is_some() {
+                    // The level was cleared before the alert fired.
+                    self.alerts.remove(&id);
+                }
+                seen.insert(id);
+            }
+        }
+
+        // A series that is no longer reported (such as an unmounted filesystem) cannot exceed its levels, so its firing alert is resolved, and its pending alert is dropped.
+        self.alerts.retain(|id, alert| {
+            if seen.contains(id) || alert.state == AlertState::Resolved || is_held(&id.1) {
+                return true;
+            }
+
+            if alert.state == AlertState::Firing {
+                alert.state = AlertState::Resolved;
+                alert.resolved = Some(time);
+                changed.push(alert.clone());
+                return true;
+            }
+
+            false
+        });
+
+        let cutoff = time - Duration::seconds(RESOLVED_RETENTION);
+        self.alerts.retain(|_, x| x.resolved.is_none_or(|x| x > cutoff));
+
+        self.publish();
+        changed
+    }
+
+    /// The next state of an alert, if it should be kept.
+    fn step(rule: &AlertRule, previous: Option<&Alert>, key: &str, value: f64, severity: Option<AlertSeverity>, time: DateTime<Utc>) -> Option<Alert> {
+        let mut alert = match (previous, severity) {
+            (Some(v), _) => v.clone(),
+            (None, Some(severity)) => Alert {
+                rule: rule.name.clone(),
+                series: key.to_string(),
+                severity,
+                state: AlertState::Pending,
+                value,
+                since: time,
+                fired: None,
+                resolved: None
+            },
+            (None, None) => return None
+        };
+        alert.value = value;
+
+        match severity {
+            Some(severity) => {
+                alert.severity = severity;
+                if alert.state == AlertState::Pending && (time - alert.since).num_seconds() >= rule.for_secs as i64 {
+                    alert.state = AlertState::Firing;
+                    alert.fired = Some(time);
+                }
+            },
+            None if alert.state == AlertState::Firing => {
+                alert.state = AlertState::Resolved;
+                alert.resolved = Some(time);
+            },
+            None => return None
+        }
+
+        Some(alert)
+    }
+
+    /// Writes the alerts to disk.
+    pub async fn save(&self) -> std::io::Result<()> {
+        let alerts: Vec<&Alert> = self.alerts.values().collect();
+        write(&self.path, serde_json::to_string(&alerts)?).await
+    }
+}
+
+#[tokio::test]
+async fn test_alert_transitions() {
+    use chrono::TimeZone;
+    use common::metric::{CollectorState, CollectorStatus, CustomMetric, MetricFamily};
+
+    let dir = super::fixture::TempDir::new("alerts");
+    let path = dir.join("alerts.json");
+
+    let at = |secs: i64, value: f64| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap(),
+        custom: vec![
+            CustomMetric { plugin: "p".to_string(), name: "v".to_string(), value },
+            CustomMetric { plugin: "p".to_string(), name: "other".to_string(), value: 0.0 }
+        ],
+        ..Default::default()
+    };
+    let rule = AlertRule {
+        for_secs: 10,
+        hysteresis: 5.0,
+        ..AlertRule::new("high".to_string(), "custom.value{series=v}".to_string(), Some(70.0), Some(90.0))
+    };
+    let states = |x: Vec<Alert>| x.into_iter().map(|x| (x.state, x.severity)).collect::<Vec<_>>();
+
+    let mut engine = AlertEngine::open(path.clone(), vec![rule.clone()]).await.unwrap();
+    assert!(engine.evaluate(&at(0, 50.0), &[]).is_empty());
+    assert_eq!(states(engine.evaluate(&at(5, 75.0), &[])), vec![(AlertState::Pending, AlertSeverity::Warning)]);
+    // Escalating while pending does not notify, and the alert fires once the duration has passed.
+    assert!(engine.evaluate(&at(10, 95.0), &[]).is_empty());
+    assert_eq!(states(engine.evaluate(&at(15, 95.0), &[])), vec![(AlertState::Firing, AlertSeverity::Critical)]);
+
+    // Within the hysteresis of the critical level, so nothing changes.
+    assert!(engine.evaluate(&at(20, 86.0), &[]).is_empty());
+    assert_eq!(states(engine.evaluate(&at(25, 80.0), &[])), vec![(AlertState::Firing, AlertSeverity::Warning)]);
+    engine.save().await.unwrap();
+
+    // The firing alert is picked up again after a restart.
+    let mut engine = AlertEngine::open(path.clone(), vec![rule.clone()]).await.unwrap();
+    assert!(engine.evaluate(&at(30, 66.0), &[]).is_empty());
+    let resolved = engine.evaluate(&at(35, 64.0), &[]);
+    assert_eq!(states(resolved.clone()), vec![(AlertState::Resolved, AlertSeverity::Warning)]);
+    assert_eq!(resolved[0].fired, Some(at(15, 0.0).time));
+    assert_eq!(current_alerts().len(), 1);
+
+    // Resolved alerts are eventually removed, and a new alert can be raised for the same series.
+    assert!(engine.evaluate(&at(35 + RESOLVED_RETENTION, 0.0), &[]).is_empty());
+    assert!(engine.alerts.is_empty());
+    assert_eq!(states(engine.evaluate(&at(40 + RESOLVED_RETENTION, 75.0), &[])), vec![(AlertState::Pending, AlertSeverity::Warning)]);
+
+    // A pending alert that clears is dropped without notifying.
+    assert!(engine.evaluate(&at(45 + RESOLVED_RETENTION, 10.0), &[]).is_empty());
+    assert!(engine.alerts.is_empty());
+
+    // While the family cannot be collected, its alerts are left as they are.
+    engine.evaluate(&at(50 + RESOLVED_RETENTION, 95.0), &[]);
+    assert_eq!(states(engine.evaluate(&at(60 + RESOLVED_RETENTION, 95.0), &[])), vec![(AlertState::Firing, AlertSeverity::Critical)]);
+    let mut failed = at(70 + RESOLVED_RETENTION, 0.0);
+    failed.custom.clear();
+    failed.set_status(CollectorStatus { family: MetricFamily::Plugins, state: CollectorState::TimedOut, time: failed.time, duration: 0.0 });
+    assert!(engine.evaluate(&failed, &[]).is_empty());
+
+    // Once the series is no longer reported, the alert is resolved.
+    let mut gone = at(80 + RESOLVED_RETENTION, 0.0);
+    gone.custom.clear();
+    assert_eq!(states(engine.evaluate(&gone, &[])), vec![(AlertState::Resolved, AlertSeverity::Critical)]);
+}
+
+#[tokio::test]
+async fn test_stuck_mount_alerts() {
+    use chrono::TimeZone;
+    use common::metric::{ByteCount, CollectorState, CollectorStatus, MetricFamily, StorageMetric, Utilization};
+
+    let dir = super::fixture::TempDir::new("alerts-mounts");
+    let mount = |device: &str, mount: &str, used: u64| StorageMetric {
+        system: device.to_string(),
+        mount: mount.to_string(),
+        size: ByteCount(100),
+        used: ByteCount(used),
+        availiable: ByteCount(100 - used),
+        capacity: Utilization::new_unwrap(0)
+    };
+    let at = |secs: i64, storage: Vec<StorageMetric>| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap(),
+        storage,
+        ..Default::default()
+    };
+    let firing = |engine: &AlertEngine| engine.alerts.values()
+        .filter(|x| x.state == AlertState::Firing)
+        .map(|x| x.series.clone())
+        .collect::<Vec<_>>();
+    let root = "storage.used_percent{device=/dev/sda1,mount=/}".to_string();
+    let nfs = "storage.used_percent{device=nas:/share,mount=/mnt/nfs}".to_string();
+
+    let rule = AlertRule::new("full".to_string(), "storage.used_percent".to_string(), Some(80.0), Some(90.0));
+    let mut engine = AlertEngine::open(dir.join("alerts.json"), vec![rule]).await.unwrap();
+    engine.evaluate(&at(0, vec![mount("/dev/sda1", "/", 50), mount("nas:/share", "/mnt/nfs", 95)]), &[]);
+    assert_eq!(firing(&engine), vec![nfs.clone()]);
+
+    // The share is stuck, but the root filesystem was collected, so it is still checked. The alert of the share is left as it is.
+    let mut stuck = at(10, vec![mount("/dev/sda1", "/", 95)]);
+    stuck.set_status(CollectorStatus { family: MetricFamily::Storage, state: CollectorState::Error("timed out mounts: /mnt/nfs".to_string()), time: stuck.time, duration: 0.0 });
+    let changed = engine.evaluate(&stuck, &[]);
+    assert_eq!(changed.iter().map(|x| (x.series.clone(), x.state)).collect::<Vec<_>>(), vec![(root.clone(), AlertState::Firing)]);
+    assert_eq!(firing(&engine), vec![root.clone(), nfs.clone()]);
+
+    // Once the share is collected without it, it is gone.
+    let changed = engine.evaluate(&at(20, vec![mount("/dev/sda1", "/", 95)]), &[]);
+    assert_eq!(changed.iter().map(|x| (x.series.clone(), x.state)).collect::<Vec<_>>(), vec![(nfs, AlertState::Resolved)]);
+    assert_eq!(firing(&engine), vec![root]);
+}
@@ -0,0 +1,329 @@
+/*
+    Anomaly detection
+
+    Every series selected by the configuration has a baseline: an exponentially weighted mean & variance of its own
+    values, optionally kept per hour of the day. Once a baseline has learned from enough snapshots, a value further
+    than the configured number of standard deviations from its mean is anomalous. The consecutive anomalous values of
+    a series form one event, which ends once the series returns to its baseline. Every value updates its baseline, so
+    that a lasting change eventually becomes the new normal. The baselines & recent events are saved periodically and
+    when the daemon stops, since baselines take a long time to learn.
+*/
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Duration, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{read_to_string, write};
+
+use common::alert::Anomaly;
+use common::config::AnomalyConfig;
+
+use super::alert::matches_selector;
+use super::collect::CollectedMetrics;
+use super::series::{incomplete_families, series, series_family};
+
+/// In seconds, how long events are kept after they end.
+const EVENT_RETENTION: i64 = 24 * 60 * 60;
+/// The most ended events that are kept.
+const MAX_EVENTS: usize = 1000;
+/// How frequently the baselines are saved while running.
+const SAVE_INTERVAL: StdDuration = StdDuration::from_secs(10 * 60);
+/// The least standard deviation of a baseline, relative to its mean. This keeps a nearly constant series from being anomalous on every small change.
+const MIN_RELATIVE_DEVIATION: f64 = 0.01;
+const MIN_DEVIATION: f64 = 1e-6;
+
+lazy_static::lazy_static! {
+    /// The recent events as of the most recent snapshot, so that they can be sent to clients.
+    static ref RECENT_ANOMALIES: RwLock<Vec<Anomaly>> = RwLock::new(vec![]);
+}
+
+/// The events that were ongoing at some point since `since`, newest first.
+pub fn recent_anomalies(since: DateTime<Utc>) -> Vec<Anomaly> {
+    let events = match RECENT_ANOMALIES.read() {
+        Ok(v) => v,
+        Err(e) => e.into_inner()
+    };
+
+    events.iter()
+        .filter(|x| x.end.is_none_or(|x| x >= since))
+        .cloned()
+        .collect()
+}
+
+/// The exponentially weighted mean & variance of a series.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    /// How many values the baseline has learned from.
+    count: u64
+}
+impl Baseline {
+    fn deviation(&self) -> f64 {
+        self.variance.sqrt()
+            .max(self.mean.abs() * MIN_RELATIVE_DEVIATION)
+            .max(MIN_DEVIATION)
+    }
+
+    /// How many standard deviations `value` is from the mean.
+    fn score(&self, value: f64) -> f64 {
+        (value - self.mean) / self.deviation()
+    }
+
+    fn update(&mut self, value: f64, alpha: f64) {
+        // Until enough values were seen, this is a plain average, so that the first value does not dominate.
+        let alpha = alpha.max(1.0 / (self.count + 1) as f64);
+        let diff = value - self.mean;
+        let increment = alpha * diff;
+
+        self.mean += increment;
+        self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        self.count += 1;
+    }
+}
+
+/// The baselines & events, as saved to disk.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedAnomalies {
+    /// Each baseline, along with its series & hour of the day.
+    baselines: Vec<(String, Option<u32>, Baseline)>,
+    events: Vec<Anomaly>
+}
+
+/// Compares every snapshot to the baselines of its series.
+pub struct AnomalyDetector {
+    path: PathBuf,
+    config: AnomalyConfig,
+    /// Keyed by the series & hour of the day (if baselines are kept per hour).
+    baselines: BTreeMap<(String, Option<u32>), Baseline>,
+    /// The events that have not ended, keyed by series.
+    ongoing: BTreeMap<String, Anomaly>,
+    /// The events that have ended, oldest first.
+    ended: VecDeque<Anomaly>,
+    last_save: Instant
+}
+impl AnomalyDetector {
+    /// Opens the detector, picking up the baselines & events saved at `path`.
+    pub async fn open(path: PathBuf, config: AnomalyConfig) -> std::io::Result<Self> {
+        let saved: SavedAnomalies = match read_to_string(&path).await {
+            Ok(v) => serde_json::from_str(&v).unwrap_or_default(),
+            Err(e) if e.kind() == ErrorKind::NotFound => SavedAnomalies::default(),
+            Err(e) => return Err(e)
+        };
+
+        let (ongoing, ended): (Vec<Anomaly>, Vec<Anomaly>) = saved.events.into_iter().partition(|x| x.end.is_none());
+        let mut result = Self {
+            path,
+            config: AnomalyConfig::default(),
+            baselines: saved.baselines.into_iter()
+                .map(|(series, hour, baseline)| ((series, hour), baseline))
+                .collect(),
+            ongoing: ongoing.into_iter()
+                .map(|x| (x.series.clone(), x))
+                .collect(),
+            ended: ended.into(),
+            last_save: Instant::now()
+        };
+        result.set_config(config);
+
+        Ok(result)
+    }
+
+    fn is_selected(config: &AnomalyConfig, key: &str) -> bool {
+        config.selectors.is_empty() || config.selectors.iter().any(|x| matches_selector(x, key))
+    }
+
+    /// Replaces the settings, removing the baselines that no longer apply.
+    pub fn set_config(&mut self, config: AnomalyConfig) {
+        self.baselines.retain(|(key, hour), _| hour.is_some() == config.hourly && Self::is_selected(&config, key));
+        self.ongoing.retain(|key, _| Self::is_selected(&config, key));
+        self.config = config;
+        self.publish();
+    }
+
+    /// Makes the events available to clients.
+    fn publish(&self) {
+        let mut events: Vec<Anomaly> = self.ongoing.values()
+            .chain(self.ended.iter())
+            .cloned()
+            .collect();
+        events.sort_by_key(|x| std::cmp::Reverse(x.start));
+
+        match RECENT_ANOMALIES.write() {
+            Ok(mut v) => *v = events,
+            Err(e) => *e.into_inner() = events
+        }
+    }
+
+    /// Compares a snapshot to the baselines, and then updates them. This returns the events started by the snapshot.
+    pub fn evaluate(&mut self, metrics: &CollectedMetrics) -> Vec<Anomaly> {
+        let time = metrics.time;
+        let hour = self.config.hourly.then(|| time.with_timezone(&Local).hour());
+        let alpha = 1.0 - 0.5f64.powf(1.0 / self.config.half_life.max(1) as f64);
+
+        let mut started = vec![];
+        let mut seen = BTreeSet::new();
+        for (key, value) in series(metrics) {
+            if !value.is_finite() || !Self::is_selected(&self.config, &key) {
+                continue;
+            }
+
+            let baseline = self.baselines.entry((key.clone(), hour)).or_default();
+            let previous = *baseline;
+            baseline.update(value, alpha);
+
+            let score = previous.score(value);
+            if previous.count < self.config.warmup || score.abs() <= self.config.sigma {
+                if let Some(mut event) = self.ongoing.remove(&key) {
+                    event.end = Some(time);
+                    self.ended.push_back(event);
+                }
+                seen.insert(key);
+                continue;
+            }
+
+            match self.ongoing.get_mut(&key) {
+                Some(event) => {
+                    event.samples += 1;
+                    if score.abs() > event.score.abs() {
+                        event.value = value;
+                        event.expected = previous.mean;
+                        event.deviation = previous.deviation();
+                        event.score = score;
+                    }
+                },
+                None => {
+                    let event = Anomaly {
+                        series: key.clone(),
+                        start: time,
+                        end: None,
+                        value,
+                        expected: previous.mean,
+                        deviation: previous.deviation(),
+                        score,
+                        samples: 1
+                    };
+                    started.push(event.clone());
+                    self.ongoing.insert(key.clone(), event);
+                }
+            }
+            seen.insert(key);
+        }
+
+        // A series that is no longer reported (such as a removed interface) cannot be anomalous. If its family was not collected in full, it may still exist, so its event is kept.
+        let incomplete = incomplete_families(metrics);
+        let gone: Vec<String> = self.ongoing.keys()
+            .filter(|x| !seen.contains(*x) && !series_family(x).is_some_and(|x| incomplete.contains(&x)))
+            .cloned()
+            .collect();
+        for key in gone {
+            if let Some(mut event) = self.ongoing.remove(&key) {
+                event.end = Some(time);
+                self.ended.push_back(event);
+            }
+        }
+
+        let cutoff = time - Duration::seconds(EVENT_RETENTION);
+        while self.ended.len() > MAX_EVENTS || self.ended.front().is_some_and(|x| x.end.is_some_and(|x| x < cutoff)) {
+            self.ended.pop_front();
+        }
+
+        self.publish();
+        started
+    }
+
+    /// Determines if the baselines have not been saved for a while.
+    pub fn is_save_due(&self) -> bool {
+        self.last_save.elapsed() >= SAVE_INTERVAL
+    }
+
+    /// Writes the baselines & events to disk.
+    pub async fn save(&mut self) -> std::io::Result<()> {
+        let saved = SavedAnomalies {
+            baselines: self.baselines.iter()
+                .map(|((series, hour), baseline)| (series.clone(), *hour, *baseline))
+                .collect(),
+            events: self.ongoing.values()
+                .chain(self.ended.iter())
+                .cloned()
+                .collect()
+        };
+
+        self.last_save = Instant::now();
+        write(&self.path, serde_json::to_string(&saved)?).await
+    }
+}
+
+#[tokio::test]
+async fn test_anomaly_detection() {
+    use chrono::TimeZone;
+    use common::metric::{CollectorState, CollectorStatus, CustomMetric, MetricFamily};
+
+    let dir = super::fixture::TempDir::new("anomalies");
+    let path = dir.join("anomalies.json");
+
+    let at = |secs: i64, values: &[(&str, f64)]| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap(),
+        custom: values.iter()
+            .map(|(name, value)| CustomMetric { plugin: "p".to_string(), name: name.to_string(), value: *value })
+            .collect(),
+        ..Default::default()
+    };
+    let config = AnomalyConfig {
+        selectors: vec!["custom.value{series=v}".to_string()],
+        half_life: 20,
+        sigma: 3.0,
+        warmup: 10,
+        ..Default::default()
+    };
+
+    let mut baseline = Baseline::default();
+    for value in [10.0, 12.0, 8.0, 10.0] {
+        baseline.update(value, 0.0);
+    }
+    assert_eq!((baseline.mean, baseline.count), (10.0, 4));
+
+    // A series alternating around 50 is learned, and large deviations are only flagged after the warmup.
+    let mut detector = AnomalyDetector::open(path.clone(), config.clone()).await.unwrap();
+    assert!(detector.evaluate(&at(0, &[("v", 50.0), ("other", 1.0)])).is_empty());
+    assert!(detector.evaluate(&at(1, &[("v", 60.0)])).is_empty());
+    for i in 2..40 {
+        let value = if i % 2 == 0 { 48.0 } else { 52.0 };
+        assert!(detector.evaluate(&at(i, &[("v", value)])).is_empty());
+    }
+    assert_eq!(detector.baselines.len(), 1);
+
+    let started = detector.evaluate(&at(40, &[("v", 90.0)]));
+    assert_eq!(started.len(), 1);
+    assert!(started[0].score > 3.0 && started[0].expected > 48.0 && started[0].expected < 52.0);
+    // Later deviations extend the same event, which keeps the value furthest from the baseline (relative to its deviation at the time).
+    assert!(detector.evaluate(&at(41, &[("v", 120.0)])).is_empty());
+    assert!(detector.evaluate(&at(42, &[("v", 0.0)])).is_empty());
+    assert_eq!(detector.ongoing["custom.value{plugin=p,series=v}"].samples, 3);
+    assert_eq!(detector.ongoing["custom.value{plugin=p,series=v}"].value, 90.0);
+
+    // While the family times out, the event is kept rather than ended, so that the incident stays one event.
+    let mut failed = at(43, &[]);
+    failed.set_status(CollectorStatus { family: MetricFamily::Plugins, state: CollectorState::TimedOut, time: failed.time, duration: 0.0 });
+    assert!(detector.evaluate(&failed).is_empty());
+    assert!(detector.evaluate(&at(44, &[("v", 200.0)])).is_empty());
+    assert_eq!(detector.ongoing["custom.value{plugin=p,series=v}"].samples, 4);
+    detector.save().await.unwrap();
+
+    // The ongoing event & the baseline are picked up again after a restart, and the event ends once the value is normal.
+    let mut detector = AnomalyDetector::open(path.clone(), config.clone()).await.unwrap();
+    assert!(detector.evaluate(&at(45, &[("v", 50.0)])).is_empty());
+    assert!(detector.ongoing.is_empty());
+    let events = recent_anomalies(at(45, &[]).time);
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].start, events[0].end), (at(40, &[]).time, Some(at(45, &[]).time)));
+    assert!(recent_anomalies(at(46, &[]).time).is_empty());
+
+    // Baselines kept per hour start over, since they are learned separately.
+    detector.set_config(AnomalyConfig { hourly: true, ..config });
+    assert!(detector.baselines.is_empty());
+}
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use tokio::fs::{read_dir, read_to_string, try_exists};
+
+use common::metric::{ByteCount, CgroupMetric};
+
+use super::LinuxCollector;
+
+/// The cumulative counters of a specific cgroup, used to determine its rates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(super) struct CgroupCounters {
+    usage_usec: u64,
+    read_bytes: u64,
+    write_bytes: u64
+}
+
+/// The counters for every tracked cgroup, and when they were read.
+#[derive(Clone, Debug)]
+pub(super) struct CgroupSample {
+    time: Instant,
+    groups: HashMap<String, CgroupCounters>
+}
+
+/// Finds the value of `usage_usec` in a `cpu.stat` file.
+fn parse_cpu_stat(contents: &str) -> Option<u64> {
+    contents.lines()
+        .find_map(|x| x.strip_prefix("usage_usec "))
+        .and_then(|x| x.trim().parse().ok())
+}
+
+/// Sums the `rbytes` and `wbytes` of every device in an `io.stat` file.
+fn parse_io_stat(contents: &str) -> (u64, u64) {
+    /*
+        Format:
+        [major]:[minor] rbytes=[n] wbytes=[n] rios=[n] wios=[n] dbytes=[n] dios=[n]
+     */
+    let mut read = 0;
+    let mut write = 0;
+    for (key, value) in contents.split_whitespace().filter_map(|x| x.split_once('=')) {
+        let value: u64 = value.parse().unwrap_or(0);
+        match key {
+            "rbytes" => read += value,
+            "wbytes" => write += value,
+            _ => continue
+        }
+    }
+
+    (read, write)
+}
+
+/// Parses `memory.max`, which is `max` when the cgroup has no limit.
+fn parse_memory_max(contents: &str) -> Option<ByteCount> {
+    contents.trim().parse().ok().map(ByteCount)
+}
+#[test]
+fn test_cgroup_parsing() {
+    let cpu = "usage_usec 250000\nuser_usec 200000\nsystem_usec 50000\nnr_periods 0\nnr_throttled 0\nthrottled_usec 0\n";
+    assert_eq!(parse_cpu_stat(cpu), Some(250000));
+
+    let io = "8:0 rbytes=1024 wbytes=2048 rios=1 wios=2 dbytes=0 dios=0\n259:0 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+    assert_eq!(parse_io_stat(io), (2048, 2048));
+    assert_eq!(parse_io_stat(""), (0, 0));
+
+    assert_eq!(parse_memory_max("max\n"), None);
+    assert_eq!(parse_memory_max("1048576\n"), Some(ByteCount(1048576)));
+}
+
+async fn read_u64(path: &Path) -> Option<u64> {
+    read_to_string(path).await.ok()?.trim().parse().ok()
+}
+
+/// Resolves the tracked paths (relative to the cgroup root) into the cgroups to report. Each tracked cgroup is reported, along with each of its direct children.
+async fn resolve_tracked(root: &Path, tracked: &[String]) -> Vec<(String, PathBuf)> {
+    let mut result = vec![];
+    for path in tracked {
+        let name = path.trim_matches('/');
+        let dir = root.join(name);
+        if !try_exists(dir.join("cgroup.controllers")).await.unwrap_or(false) {
+            continue;
+        }
+
+        let mut children = vec![];
+        if let Ok(mut entries) = read_dir(&dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if entry.file_type().await.map(|x| x.is_dir()).unwrap_or(false) {
+                    let child = entry.file_name().to_string_lossy().to_string();
+                    let child_name = if name.is_empty() { child } else { format!("{name}/{child}") };
+                    children.push( (child_name, entry.path()) );
+                }
+            }
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        result.push( (format!("/{name}"), dir) );
+        result.extend(children.into_iter().map(|(x, path)| (format!("/{x}"), path)));
+    }
+
+    // A cgroup may be both tracked & a child of another tracked cgroup, so it is only kept the first time.
+    let mut seen = HashSet::new();
+    result.retain(|x| seen.insert(x.0.clone()));
+    result
+}
+
+impl LinuxCollector {
+    /// Determines the root of the cgroup v2 hierarchy. Hybrid systems mount it under `unified`.
+    async fn cgroup_root(&self) -> Option<PathBuf> {
+        let root = self.sys_root.join("fs/cgroup");
+        for candidate in [root.clone(), root.join("unified")] {
+            if try_exists(candidate.join("cgroup.controllers")).await.unwrap_or(false) {
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+
+    /// Reads the resource usage of the tracked cgroups. CPU & IO rates are determined since the last time this was called, so cgroups that were not present in the previous call report no rates.
+    pub(super) async fn sample_cgroups(&self, tracked: &[String]) -> Vec<CgroupMetric> {
+        let root = match self.cgroup_root().await {
+            Some(v) => v,
+            None => return vec![]
+        };
+
+        let mut result = vec![];
+        let mut groups = HashMap::new();
+        for (name, dir) in resolve_tracked(&root, tracked).await {
+            let usage_usec = read_to_string(dir.join("cpu.stat")).await
+                .ok()
+                .and_then(|x| parse_cpu_stat(&x))
+                .unwrap_or(0);
+            let (read_bytes, write_bytes) = read_to_string(dir.join("io.stat")).await
+                .map(|x| parse_io_stat(&x))
+                .unwrap_or_default();
+
+            groups.insert(name.clone(), CgroupCounters { usage_usec, read_bytes, write_bytes });
+            result.push(
+                CgroupMetric {
+                    path: name,
+                    cpu_usage: 0.0,
+                    memory: ByteCount(read_u64(&dir.join("memory.current")).await.unwrap_or(0)),
+                    memory_max: read_to_string(dir.join("memory.max")).await
+                        .ok()
+                        .and_then(|x| parse_memory_max(&x)),
+                    read_rate: ByteCount(0),
+                    write_rate: ByteCount(0),
+                    pids: read_u64(&dir.join("pids.current")).await.unwrap_or(0)
+                }
+            );
+        }
+
+        let current = CgroupSample {
+            time: Instant::now(),
+            groups
+        };
+        let prev = {
+            let mut guard = match self.last_cgroups.lock() {
+                Ok(g) => g,
+                Err(e) => e.into_inner()
+            };
+
+            guard.replace(current.clone())
+        };
+        let prev = match prev {
+            Some(v) => v,
+            None => return result
+        };
+
+        let secs = current.time.duration_since(prev.time).as_secs_f64();
+        if secs <= 0.0 {
+            return result;
+        }
+        for metric in &mut result {
+            let (now, old) = match (current.groups.get(&metric.path), prev.groups.get(&metric.path)) {
+                (Some(n), Some(o)) => (n, o),
+                _ => continue
+            };
+
+            metric.cpu_usage = now.usage_usec.saturating_sub(old.usage_usec) as f64 / (secs * 10_000.0);
+            metric.read_rate = ByteCount((now.read_bytes.saturating_sub(old.read_bytes) as f64 / secs) as u64);
+            metric.write_rate = ByteCount((now.write_bytes.saturating_sub(old.write_bytes) as f64 / secs) as u64);
+        }
+
+        result
+    }
+}
+
+#[tokio::test]
+async fn test_cgroup_tree() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("cgroup");
+    let cgroup_root = root.join("fs/cgroup");
+    let slice = cgroup_root.join("system.slice");
+    let service = slice.join("sshd.service");
+    create_dir_all(&service).unwrap();
+
+    write(cgroup_root.join("cgroup.controllers"), "cpu io memory pids\n").unwrap();
+    write(slice.join("cgroup.controllers"), "cpu io memory pids\n").unwrap();
+    write(slice.join("memory.current"), "4096\n").unwrap();
+    write(slice.join("memory.max"), "max\n").unwrap();
+    write(slice.join("pids.current"), "12\n").unwrap();
+    write(service.join("memory.current"), "1024\n").unwrap();
+    write(service.join("memory.max"), "2048\n").unwrap();
+    write(service.join("pids.current"), "2\n").unwrap();
+    write(service.join("cpu.stat"), "usage_usec 1000\n").unwrap();
+
+    let collector = LinuxCollector::new(root.join("proc"), root.to_path_buf());
+    let groups = collector.sample_cgroups(&["system.slice".to_string(), "missing.slice".to_string()]).await;
+
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].path, "/system.slice");
+    assert_eq!(groups[0].memory, ByteCount(4096));
+    assert_eq!(groups[0].memory_max, None);
+    assert_eq!(groups[0].pids, 12);
+    assert_eq!(groups[1].path, "/system.slice/sshd.service");
+    assert_eq!(groups[1].memory_max, Some(ByteCount(2048)));
+    assert_eq!(groups[1].cpu_usage, 0.0);
+
+    write(service.join("cgroup.controllers"), "cpu io memory pids\n").unwrap();
+    let tracked = resolve_tracked(&cgroup_root, &["system.slice/sshd.service".to_string(), "system.slice".to_string()]).await;
+    let names: Vec<&str> = tracked.iter().map(|x| x.0.as_str()).collect();
+    assert_eq!(names, ["/system.slice/sshd.service", "/system.slice"]);
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::fs::try_exists;
+
+use common::metric::{ByteCount, DiskIoMetric};
+
+use super::LinuxCollector;
+
+/// The kernel always reports sectors in `/proc/diskstats` as 512 bytes, regardless of the device.
+const SECTOR_SIZE: u64 = 512;
+
+/// The raw counters for a single device in `/proc/diskstats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(super) struct DiskCounters {
+    reads: u64,
+    read_sectors: u64,
+    read_ms: u64,
+    writes: u64,
+    write_sectors: u64,
+    write_ms: u64,
+    io_ms: u64
+}
+impl DiskCounters {
+    fn since(&self, prev: &Self) -> Self {
+        Self {
+            reads: self.reads.saturating_sub(prev.reads),
+            read_sectors: self.read_sectors.saturating_sub(prev.read_sectors),
+            read_ms: self.read_ms.saturating_sub(prev.read_ms),
+            writes: self.writes.saturating_sub(prev.writes),
+            write_sectors: self.write_sectors.saturating_sub(prev.write_sectors),
+            write_ms: self.write_ms.saturating_sub(prev.write_ms),
+            io_ms: self.io_ms.saturating_sub(prev.io_ms)
+        }
+    }
+
+    /// Converts a set of (delta) counters, accumulated over `elapsed`, into rates.
+    fn as_metric(&self, device: String, elapsed: Duration) -> Option<DiskIoMetric> {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+
+        let average = |ms: u64, count: u64| if count == 0 { 0.0 } else { ms as f64 / count as f64 };
+        Some(
+            DiskIoMetric {
+                device,
+                read_iops: self.reads as f64 / secs,
+                write_iops: self.writes as f64 / secs,
+                read_rate: ByteCount(((self.read_sectors * SECTOR_SIZE) as f64 / secs) as u64),
+                write_rate: ByteCount(((self.write_sectors * SECTOR_SIZE) as f64 / secs) as u64),
+                read_await: average(self.read_ms, self.reads),
+                write_await: average(self.write_ms, self.writes),
+                utilization: (self.io_ms as f64 / (secs * 10.0)).min(100.0)
+            }
+        )
+    }
+}
+
+/// The counters for every device, and when they were read.
+#[derive(Clone, Debug)]
+pub(super) struct DiskSample {
+    time: Instant,
+    disks: HashMap<String, DiskCounters>
+}
+
+/// Parses `/proc/diskstats` into the counters for each device.
+fn parse_diskstats(contents: &str) -> HashMap<String, DiskCounters> {
+    let mut result = HashMap::new();
+    for line in contents.lines() {
+        /*
+            Format:
+            [major] [minor] [name] [reads] [reads merged] [sectors read] [ms reading] [writes] [writes merged] [sectors written] [ms writing] [in progress] [ms doing io] [weighted ms] ...
+         */
+        let splits: Vec<&str> = line.split_whitespace().collect();
+        if splits.len() < 14 {
+            continue;
+        }
+
+        let values: Vec<u64> = match splits[3..14].iter().map(|x| x.parse::<u64>()).collect() {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        result.insert(
+            splits[2].to_string(),
+            DiskCounters {
+                reads: values[0],
+                read_sectors: values[2],
+                read_ms: values[3],
+                writes: values[4],
+                write_sectors: values[6],
+                write_ms: values[7],
+                io_ms: values[9]
+            }
+        );
+    }
+
+    result
+}
+#[test]
+fn test_diskstats_rates() {
+    let first = parse_diskstats("   8       0 sda 100 0 800 50 200 0 1600 100 0 500 600 0 0 0 0\n   8       1 sda1 1 0 8 1 1 0 8 1 0 1 2 0 0 0 0\n");
+    let second = parse_diskstats("   8       0 sda 200 0 1600 150 400 0 4800 500 0 1500 1600 0 0 0 0\n");
+
+    assert_eq!(first.len(), 2);
+    let metric = second["sda"].since(&first["sda"])
+        .as_metric("sda".to_string(), Duration::from_secs(2))
+        .unwrap();
+
+    assert_eq!(metric.read_iops, 50.0);
+    assert_eq!(metric.write_iops, 100.0);
+    assert_eq!(metric.read_rate, ByteCount(800 * SECTOR_SIZE / 2));
+    assert_eq!(metric.write_rate, ByteCount(3200 * SECTOR_SIZE / 2));
+    assert_eq!(metric.read_await, 1.0);
+    assert_eq!(metric.write_await, 2.0);
+    assert_eq!(metric.utilization, 50.0);
+}
+
+impl LinuxCollector {
+    /// Determines the IO rates of each whole block device since the last time this was called. The first call reports the rates since boot.
+    pub(super) async fn sample_disk_io(&self) -> Vec<DiskIoMetric> {
+        let contents = match self.read_proc("diskstats").await {
+            Some(v) => v,
+            None => return vec![]
+        };
+
+        let mut disks = HashMap::new();
+        for (name, counters) in parse_diskstats(&contents) {
+            // Partitions are not listed in /sys/block, and loop & ram devices are rarely of interest.
+            if name.starts_with("loop") || name.starts_with("ram") {
+                continue;
+            }
+            if !try_exists(self.sys_root.join("block").join(&name)).await.unwrap_or(false) {
+                continue;
+            }
+
+            disks.insert(name, counters);
+        }
+
+        let current = DiskSample {
+            time: Instant::now(),
+            disks
+        };
+        let prev = {
+            let mut guard = match self.last_disks.lock() {
+                Ok(g) => g,
+                Err(e) => e.into_inner()
+            };
+
+            guard.replace(current.clone())
+        };
+
+        let (prev_disks, elapsed) = match prev {
+            Some(prev) => (prev.disks, current.time.duration_since(prev.time)),
+            None => (HashMap::new(), self.uptime().await.unwrap_or_default())
+        };
+
+        let mut result: Vec<DiskIoMetric> = current.disks.into_iter()
+            .filter_map(|(name, counters)| {
+                let prev = prev_disks.get(&name).copied().unwrap_or_default();
+                counters.since(&prev).as_metric(name, elapsed)
+            })
+            .collect();
+
+        result.sort_by(|a, b| a.device.cmp(&b.device));
+        result
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use tokio::fs::read_to_string;
+
+use common::metric::ByteCount;
+use common::msg::HostInfo;
+
+use super::{parse_meminfo, LinuxCollector};
+
+const OS_RELEASE_PATHS: &[&str] = &["etc/os-release", "usr/lib/os-release"];
+
+/// Parses os-release into the name and version of the operating system. Values may be quoted.
+fn parse_os_release(contents: &str) -> (String, String) {
+    let mut name = String::new();
+    let mut version = String::new();
+    for line in contents.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(v) => v,
+            None => continue
+        };
+        let value = value.trim().trim_matches(|x| x == '"' || x == '\'').to_string();
+
+        match key.trim() {
+            "NAME" => name = value,
+            "VERSION" => version = value,
+            "VERSION_ID" if version.is_empty() => version = value,
+            _ => continue
+        }
+    }
+
+    (name, version)
+}
+
+/// Parses `/proc/cpuinfo` into the CPU model, and the number of logical cores.
+fn parse_cpuinfo(contents: &str) -> (String, u32) {
+    let mut model: Option<String> = None;
+    let mut cores = 0u32;
+    for line in contents.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue
+        };
+
+        match key.trim() {
+            "processor" => cores += 1,
+            // x86 reports "model name", while most ARM kernels only report "Hardware" or "Model".
+            "model name" | "Hardware" | "Model" if model.is_none() => model = Some(value.trim().to_string()),
+            _ => continue
+        }
+    }
+
+    (model.unwrap_or_else(|| "unknown".to_string()), cores)
+}
+#[test]
+fn test_host_parsing() {
+    let os_release = "NAME=\"Ubuntu\"\nVERSION_ID=\"24.04\"\nVERSION=\"24.04.1 LTS (Noble Numbat)\"\nID=ubuntu\n";
+    assert_eq!(parse_os_release(os_release), ("Ubuntu".to_string(), "24.04.1 LTS (Noble Numbat)".to_string()));
+    assert_eq!(parse_os_release("NAME=Arch Linux\nVERSION_ID=rolling\n"), ("Arch Linux".to_string(), "rolling".to_string()));
+
+    let cpuinfo = "processor\t: 0\nmodel name\t: AMD Ryzen 7 5800X\n\nprocessor\t: 1\nmodel name\t: AMD Ryzen 7 5800X\n";
+    assert_eq!(parse_cpuinfo(cpuinfo), ("AMD Ryzen 7 5800X".to_string(), 2));
+}
+
+/// Finds the boot time (in seconds since the epoch) from the `btime` line of `/proc/stat`.
+fn parse_boot_time(contents: &str) -> Option<DateTime<Utc>> {
+    let line = contents.lines().find(|x| x.starts_with("btime "))?;
+    let secs: i64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    DateTime::from_timestamp(secs, 0)
+}
+
+impl LinuxCollector {
+    /// Gathers the static information about this host. The uptime is determined as of this call.
+    pub async fn host_info(&self, daemon_version: &str) -> HostInfo {
+        let mut os = (String::new(), String::new());
+        for path in OS_RELEASE_PATHS {
+            if let Ok(contents) = read_to_string(self.system_path(path)).await {
+                os = parse_os_release(&contents);
+                break;
+            }
+        }
+
+        let (cpu_model, cpu_cores) = parse_cpuinfo(&self.read_proc("cpuinfo").await.unwrap_or_default());
+        let total_memory = parse_meminfo(&self.read_proc("meminfo").await.unwrap_or_default())
+            .first()
+            .map(|x| x.total)
+            .unwrap_or(ByteCount(0));
+        let boot_time = self.read_proc("stat").await
+            .as_deref()
+            .and_then(parse_boot_time);
+
+        HostInfo {
+            hostname: self.read_proc("sys/kernel/hostname").await.unwrap_or_default().trim().to_string(),
+            kernel: self.read_proc("sys/kernel/osrelease").await.unwrap_or_default().trim().to_string(),
+            os_name: os.0,
+            os_version: os.1,
+            cpu_model,
+            cpu_cores,
+            total_memory,
+            boot_time,
+            uptime: self.uptime().await.map(|x| x.as_secs()),
+            daemon_version: daemon_version.to_string()
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_host_tree() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("host");
+    create_dir_all(root.join("proc")).unwrap();
+    create_dir_all(root.join("usr/lib")).unwrap();
+    write(root.join("usr/lib/os-release"), "NAME=\"Fallback\"\nVERSION_ID=1\n").unwrap();
+    let collector = LinuxCollector::new(root.join("proc"), root.join("sys"));
+
+    // Without `/proc/stat` & `/proc/uptime`, the boot time & uptime are unknown, rather than the epoch.
+    let info = collector.host_info("test").await;
+    assert_eq!((info.boot_time, info.uptime), (None, None));
+    assert_eq!((info.os_name.as_str(), info.os_version.as_str()), ("Fallback", "1"));
+
+    // The os-release in `/etc` takes precedence.
+    create_dir_all(root.join("etc")).unwrap();
+    write(root.join("etc/os-release"), "NAME=\"Debian GNU/Linux\"\nVERSION=\"12 (bookworm)\"\n").unwrap();
+    let info = collector.host_info("test").await;
+    assert_eq!((info.os_name.as_str(), info.os_version.as_str()), ("Debian GNU/Linux", "12 (bookworm)"));
+}
@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use tokio::fs::{read_dir, read_link, read_to_string};
+
+use common::metric::{SensorKind, SensorMetric};
+
+use super::LinuxCollector;
+
+/// Determines the kind of a sensor, and the divisor used to convert the raw value, from its prefix in sysfs.
+fn sensor_kind(prefix: &str) -> Option<(SensorKind, f64)> {
+    match prefix {
+        "temp" => Some( (SensorKind::Temperature, 1000.0) ), // Millidegrees
+        "fan" => Some( (SensorKind::Fan, 1.0) ),
+        "in" => Some( (SensorKind::Voltage, 1000.0) ), // Millivolts
+        _ => None
+    }
+}
+
+/// Splits a file name such as `temp1_input` into its prefix (`temp`) and sensor name (`temp1`), only if it is an input file.
+fn parse_input_name(file_name: &str) -> Option<(&str, &str)> {
+    let sensor = file_name.strip_suffix("_input")?;
+    let prefix = sensor.trim_end_matches(|x: char| x.is_ascii_digit());
+    if prefix.len() == sensor.len() {
+        return None;
+    }
+
+    Some( (prefix, sensor) )
+}
+#[test]
+fn test_input_names() {
+    assert_eq!(parse_input_name("temp1_input"), Some(("temp", "temp1")));
+    assert_eq!(parse_input_name("in10_input"), Some(("in", "in10")));
+    assert_eq!(parse_input_name("temp1_label"), None);
+    assert_eq!(parse_input_name("name"), None);
+}
+
+async fn read_value(path: &Path) -> Option<f64> {
+    read_to_string(path).await.ok()?.trim().parse().ok()
+}
+
+/// Reads every sensor of a specific hwmon chip directory.
+async fn read_chip(dir: &Path) -> Vec<SensorMetric> {
+    let mut result = vec![];
+    let chip = match read_to_string(dir.join("name")).await {
+        Ok(v) => v.trim().to_string(),
+        Err(_) => return result
+    };
+    // The hwmon numbering can change between boots, so the device is preferred.
+    let device = read_link(dir.join("device")).await
+        .ok()
+        .as_deref()
+        .and_then(Path::file_name)
+        .or_else(|| dir.file_name())
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut entries = match read_dir(dir).await {
+        Ok(v) => v,
+        Err(_) => return result
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name();
+        let (prefix, sensor) = match file_name.to_str().and_then(parse_input_name) {
+            Some(v) => v,
+            None => continue
+        };
+        let (kind, divisor) = match sensor_kind(prefix) {
+            Some(v) => v,
+            None => continue
+        };
+
+        let value = match read_value(&entry.path()).await {
+            Some(v) => v / divisor,
+            None => continue
+        };
+        let label = match read_to_string(dir.join(format!("{sensor}_label"))).await {
+            Ok(v) => v.trim().to_string(),
+            Err(_) => sensor.to_string()
+        };
+        let critical = read_value(&dir.join(format!("{sensor}_crit"))).await
+            .map(|x| x / divisor);
+
+        result.push(
+            SensorMetric {
+                chip: chip.clone(),
+                device: device.clone(),
+                label,
+                kind,
+                value,
+                critical
+            }
+        );
+    }
+
+    result
+}
+
+impl LinuxCollector {
+    /// Reads the temperature, fan, and voltage sensors of every chip in `/sys/class/hwmon`.
+    pub(super) async fn read_hwmon(&self) -> Vec<SensorMetric> {
+        let mut result = vec![];
+        let mut entries = match read_dir(self.sys_root.join("class/hwmon")).await {
+            Ok(v) => v,
+            Err(_) => return result
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            result.extend(read_chip(&entry.path()).await);
+        }
+
+        result.sort_by(|a, b| a.chip.cmp(&b.chip).then(a.device.cmp(&b.device)).then(a.label.cmp(&b.label)));
+        result
+    }
+}
+
+#[tokio::test]
+async fn test_hwmon_tree() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("hwmon");
+    let chip = root.join("class/hwmon/hwmon0");
+    create_dir_all(&chip).unwrap();
+
+    write(chip.join("name"), "coretemp\n").unwrap();
+    write(chip.join("temp1_input"), "45000\n").unwrap();
+    write(chip.join("temp1_label"), "Package id 0\n").unwrap();
+    write(chip.join("temp1_crit"), "100000\n").unwrap();
+    write(chip.join("fan1_input"), "1200\n").unwrap();
+    write(chip.join("in0_input"), "1250\n").unwrap();
+
+    // A second chip with the same name, told apart by its device.
+    let other = root.join("class/hwmon/hwmon1");
+    let device = root.join("devices/platform/coretemp.1");
+    create_dir_all(&other).unwrap();
+    create_dir_all(&device).unwrap();
+    std::os::unix::fs::symlink(&device, other.join("device")).unwrap();
+    write(other.join("name"), "coretemp\n").unwrap();
+    write(other.join("temp1_input"), "50000\n").unwrap();
+
+    let collector = LinuxCollector::new(root.join("proc"), root.to_path_buf());
+    let sensors = collector.read_hwmon().await;
+
+    assert_eq!(sensors.len(), 4);
+    assert_eq!(sensors[0], SensorMetric { chip: "coretemp".to_string(), device: "coretemp.1".to_string(), label: "temp1".to_string(), kind: SensorKind::Temperature, value: 50.0, critical: None });
+    assert_eq!(sensors[1], SensorMetric { chip: "coretemp".to_string(), device: "hwmon0".to_string(), label: "Package id 0".to_string(), kind: SensorKind::Temperature, value: 45.0, critical: Some(100.0) });
+    assert_eq!(sensors[2], SensorMetric { chip: "coretemp".to_string(), device: "hwmon0".to_string(), label: "fan1".to_string(), kind: SensorKind::Fan, value: 1200.0, critical: None });
+    assert_eq!(sensors[3], SensorMetric { chip: "coretemp".to_string(), device: "hwmon0".to_string(), label: "in0".to_string(), kind: SensorKind::Voltage, value: 1.25, critical: None });
+}
@@ -0,0 +1,638 @@
+use tokio::fs::read_to_string;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{timeout_at, Instant};
+
+use common::metric::{ByteCount, CpuCoreMetric, CpuUsage, LoadAverage, NetworkMetricSection, Utilization};
+
+use super::prelude::*;
+use std::{collections::{BTreeMap, HashMap, HashSet}, ffi::CString, path::PathBuf, sync::{Arc, Mutex, OnceLock}, time::Duration};
+
+use cgroup::CgroupSample;
+use disk::DiskSample;
+
+pub mod cgroup;
+pub mod disk;
+pub mod host;
+pub mod hwmon;
+pub mod pressure;
+pub mod process;
+
+pub const DEFAULT_PROC_ROOT: &str = "/proc";
+pub const DEFAULT_SYS_ROOT: &str = "/sys";
+
+const MEMORY_TARGETS: &[&str] = &["MemTotal", "MemAvailable", "MemFree","Buffers","Cached"];
+const SWAP_TARGETS: &[&str] = &["SwapTotal", "SwapFree", "SwapCached"];
+static MEMORY_TARGETS_SET: OnceLock<Arc<HashSet<&'static str>>> = OnceLock::new();
+static SWAP_TARGETS_SET: OnceLock<Arc<HashSet<&'static str>>> = OnceLock::new();
+
+fn load_targets(targets: &[&'static str]) -> Arc<HashSet<&'static str>> {
+    let mut result: HashSet<&'static str> = HashSet::new();
+    for target in targets {
+       result.insert(target);
+    }
+
+    Arc::new(result)
+}
+fn load_memory_targets() -> Arc<HashSet<&'static str>> {
+    load_targets(MEMORY_TARGETS)
+}
+fn load_swap_targets() -> Arc<HashSet<&'static str>> {
+    load_targets(SWAP_TARGETS)
+}
+
+struct Targets {
+    mem: Arc<HashSet<&'static str>>,
+    swap: Arc<HashSet<&'static str>>
+}
+
+fn get_targets() -> Targets {
+    let mem = Arc::clone(MEMORY_TARGETS_SET.get_or_init(load_memory_targets));
+    let swap = Arc::clone(SWAP_TARGETS_SET.get_or_init(load_swap_targets));
+
+    Targets {
+        mem,
+        swap
+    }
+}
+
+/// Determines the percentage of `part` in `total`, without rounding.
+fn fractional_percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    }
+    else {
+        (part as f64 / total as f64) * 100.0
+    }
+}
+/// Converts a part of a whole into a utilization, rounding to the nearest percent.
+fn to_utilization(part: u64, total: u64) -> Utilization {
+    Utilization::new_unwrap(fractional_percent(part, total).round().min(100.0) as u8)
+}
+
+/// The raw counters (in jiffies) from a `cpu` line of `/proc/stat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+struct CpuTimes {
+    user: u64,
+    nice: u64,
+    system: u64,
+    idle: u64,
+    iowait: u64,
+    irq: u64,
+    softirq: u64,
+    steal: u64
+}
+impl CpuTimes {
+    /// Parses the values of a `cpu` line from `/proc/stat`. Older kernels do not report all fields, so any missing trailing fields are zero.
+    fn parse<'a>(splits: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let values: Vec<u64> = splits
+            .map(|x| x.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .ok()?;
+        if values.len() < 4 {
+            return None;
+        }
+
+        let field = |i: usize| values.get(i).copied().unwrap_or(0);
+        Some(
+            Self {
+                user: field(0),
+                nice: field(1),
+                system: field(2),
+                idle: field(3),
+                iowait: field(4),
+                irq: field(5),
+                softirq: field(6),
+                steal: field(7)
+            }
+        )
+    }
+
+    fn total(&self) -> u64 {
+        self.user + self.nice + self.system + self.idle + self.iowait + self.irq + self.softirq + self.steal
+    }
+
+    /// Determines the counters accumulated between `prev` and `self`.
+    fn since(&self, prev: &Self) -> Self {
+        Self {
+            user: self.user.saturating_sub(prev.user),
+            nice: self.nice.saturating_sub(prev.nice),
+            system: self.system.saturating_sub(prev.system),
+            idle: self.idle.saturating_sub(prev.idle),
+            iowait: self.iowait.saturating_sub(prev.iowait),
+            irq: self.irq.saturating_sub(prev.irq),
+            softirq: self.softirq.saturating_sub(prev.softirq),
+            steal: self.steal.saturating_sub(prev.steal)
+        }
+    }
+
+    /// Converts a set of (delta) counters into percentages. If no time has elapsed, this returns `None`.
+    fn as_usage(&self) -> Option<CpuUsage> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        Some(
+            CpuUsage {
+                user: fractional_percent(self.user, total),
+                system: fractional_percent(self.system, total),
+                nice: fractional_percent(self.nice, total),
+                idle: fractional_percent(self.idle, total),
+                iowait: fractional_percent(self.iowait, total),
+                irq: fractional_percent(self.irq, total),
+                softirq: fractional_percent(self.softirq, total),
+                steal: fractional_percent(self.steal, total)
+            }
+        )
+    }
+}
+
+/// The counters for the aggregate `cpu` line, and each `cpuN` line of `/proc/stat`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+struct CpuSample {
+    total: CpuTimes,
+    cores: BTreeMap<u32, CpuTimes>
+}
+impl CpuSample {
+    /// Determines the utilization between `prev` and `self`. Cores that are not in `prev` (just brought online) are measured since boot.
+    fn since(&self, prev: &Self) -> Option<(CpuUsage, Vec<CpuCoreMetric>)> {
+        let total = self.total.since(&prev.total).as_usage()?;
+        let cores = self.cores.iter()
+            .filter_map(|(id, times)| {
+                let prev = prev.cores.get(id).copied().unwrap_or_default();
+
+                Some(
+                    CpuCoreMetric {
+                        id: *id,
+                        usage: times.since(&prev).as_usage()?
+                    }
+                )
+            })
+            .collect();
+
+        Some( (total, cores) )
+    }
+}
+
+fn parse_stat(contents: &str) -> Option<CpuSample> {
+    let mut total: Option<CpuTimes> = None;
+    let mut cores = BTreeMap::new();
+    for line in contents.lines() {
+        let mut splits = line.split_whitespace();
+        let label = match splits.next() {
+            Some(v) => v,
+            None => continue
+        };
+
+        if label == "cpu" {
+            total = CpuTimes::parse(splits);
+        }
+        else if let Some(id) = label.strip_prefix("cpu").and_then(|x| x.parse::<u32>().ok()) 
+            && let Some(times) = CpuTimes::parse(splits) {
+            cores.insert(id, times);
+        }
+    }
+
+    Some(
+        CpuSample {
+            total: total?,
+            cores
+        }
+    )
+}
+#[test]
+fn test_cpu_deltas() {
+    let first = parse_stat("cpu  100 0 100 800 0 0 0 0 0 0\ncpu0 50 0 50 400 0 0 0 0 0 0\ncpu1 50 0 50 400 0 0 0 0 0 0\nintr 1 2 3\n").unwrap();
+    let second = parse_stat("cpu  150 0 150 900 0 0 0 0 0 0\ncpu0 100 0 50 400 0 0 0 0 0 0\ncpu1 50 0 100 450 0 0 0 0 0 0\nintr 1 2 3\n").unwrap();
+
+    let (total, cores) = second.since(&first).unwrap();
+    assert_eq!(total.user, 25.0);
+    assert_eq!(total.system, 25.0);
+    assert_eq!(total.idle, 50.0);
+
+    assert_eq!(cores.len(), 2);
+    assert_eq!(cores[0].id, 0);
+    assert_eq!(cores[0].usage.user, 100.0);
+    assert_eq!(cores[1].usage.system, 50.0);
+
+    assert!(second.since(&second).is_none());
+}
+
+/// Parses `/proc/loadavg`, which has the format `[1 min] [5 min] [15 min] [running/total] [last pid]`.
+fn parse_loadavg(contents: &str) -> Option<LoadAverage> {
+    let mut splits = contents.split_whitespace()
+        .map(|x| x.parse::<f64>().ok());
+
+    Some(
+        LoadAverage {
+            one: splits.next()??,
+            five: splits.next()??,
+            fifteen: splits.next()??
+        }
+    )
+}
+#[test]
+fn test_loadavg_parsing() {
+    assert_eq!(parse_loadavg("0.52 0.58 0.59 1/467 12345\n"), Some(LoadAverage { one: 0.52, five: 0.58, fifteen: 0.59 }));
+    assert_eq!(parse_loadavg("0.52"), None);
+}
+
+/// Parses the contents of `/proc/meminfo` into the main memory and swap sections. The swap section is omitted if the system has no swap.
+fn parse_meminfo(contents: &str) -> Vec<MemoryMetric> {
+    let targets = get_targets();
+
+    let mut values: HashMap<&str, u64> = HashMap::new();
+    for line in contents.lines() {
+        let (key, rest) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue
+        };
+        if !targets.mem.contains(key) && !targets.swap.contains(key) {
+            continue;
+        }
+
+        let mut splits = rest.split_whitespace();
+        let value: u64 = match splits.next().and_then(|x| x.parse().ok()) {
+            Some(v) => v,
+            None => continue
+        };
+        let value = match splits.next() {
+            Some("kB") => value * 1024,
+            _ => value
+        };
+
+        values.insert(key, value);
+    }
+
+    let get = |key: &str| ByteCount(values.get(key).copied().unwrap_or(0));
+    let mut result = vec![];
+    if values.contains_key("MemTotal") {
+        result.push(
+            MemoryMetric {
+                device: "Mem".to_string(),
+                total: get("MemTotal"),
+                free: get("MemFree"),
+                available: get("MemAvailable"),
+                buff: get("Buffers"),
+                cached: get("Cached")
+            }
+        );
+    }
+    if values.get("SwapTotal").is_some_and(|x| *x != 0) {
+        result.push(
+            MemoryMetric {
+                device: "Swap".to_string(),
+                total: get("SwapTotal"),
+                free: get("SwapFree"),
+                available: get("SwapFree"),
+                buff: ByteCount(0),
+                cached: get("SwapCached")
+            }
+        );
+    }
+
+    result
+}
+
+/// Parses `/proc/net/dev` into the interface name, and the receiving & transmitting sections.
+fn parse_net_dev(contents: &str) -> Vec<(String, NetworkMetricSection, NetworkMetricSection)> {
+    let mut result = vec![];
+    for line in contents.lines().skip(2) {
+        let (name, rest) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue
+        };
+
+        /*
+            Format of the values:
+            RX: [bytes] [packets] [errs] [drop] [fifo] [frame] [compressed] [multicast]
+            TX: [bytes] [packets] [errs] [drop] [fifo] [colls] [carrier] [compressed]
+         */
+        let values: Vec<u64> = match rest.split_whitespace().map(|x| x.parse::<u64>()).collect() {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+        if values.len() < 16 {
+            continue;
+        }
+
+        let rx = NetworkMetricSection::try_from(values[0..5].to_vec());
+        let tx = NetworkMetricSection::try_from(values[8..13].to_vec());
+        if let (Ok(rx), Ok(tx)) = (rx, tx) {
+            result.push((name.trim().to_string(), rx, tx));
+        }
+    }
+
+    result
+}
+
+/// Decodes the octal escapes (`\040` for a space, etc.) used by the kernel in `/proc/self/mounts`.
+fn unescape_mount(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut result: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0usize;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4)
+            .filter(|x| bytes[i] == b'\\' && x.iter().all(|b| (b'0'..=b'7').contains(b)));
+
+        if let Some(digits) = escape {
+            result.push(digits.iter().fold(0u8, |acc, b| acc.wrapping_mul(8).wrapping_add(b - b'0')));
+            i += 4;
+        }
+        else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&result).to_string()
+}
+
+/// Parses `/proc/self/mounts` into the device & mount point of each block-device backed filesystem.
+fn parse_mounts(contents: &str) -> Vec<(String, String)> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut result = vec![];
+    for line in contents.lines() {
+        let mut splits = line.split_whitespace();
+        let (device, mount) = match (splits.next(), splits.next()) {
+            (Some(d), Some(m)) => (d, unescape_mount(m)),
+            _ => continue
+        };
+
+        if !device.starts_with("/dev/") || !seen.insert(mount.clone()) {
+            continue;
+        }
+
+        result.push((device.to_string(), mount));
+    }
+
+    result
+}
+#[test]
+fn test_mount_parsing() {
+    let contents = "proc /proc proc rw 0 0\n/dev/sda1 / ext4 rw 0 0\n/dev/sdb1 /mnt/my\\040disk ext4 rw 0 0\n/dev/sda1 / ext4 rw 0 0\n";
+
+    let mounts = parse_mounts(contents);
+    assert_eq!(mounts, vec![
+        ("/dev/sda1".to_string(), "/".to_string()),
+        ("/dev/sdb1".to_string(), "/mnt/my disk".to_string())
+    ]);
+}
+
+/// Runs `statvfs` on a specific mount point. This call blocks, and should not be run on the async runtime directly.
+fn stat_filesystem(system: String, mount: String) -> Option<StorageMetric> {
+    let path = CString::new(mount.as_bytes()).ok()?;
+    // SAFETY: `statvfs` is plain old data, so a zeroed value is valid, and it is only read after the call succeeds.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    let block = stat.f_frsize;
+    let size = stat.f_blocks * block;
+    let free = stat.f_bfree * block;
+    let availiable = stat.f_bavail * block;
+    let used = size.saturating_sub(free);
+
+    // Like df, the capacity is determined from what is usable by non-root users.
+    Some(
+        StorageMetric {
+            system,
+            mount,
+            size: ByteCount(size),
+            used: ByteCount(used),
+            availiable: ByteCount(availiable),
+            capacity: to_utilization(used, used + availiable)
+        }
+    )
+}
+
+/// Collects metrics from the procfs & sysfs trees of a Linux system.
+pub struct LinuxCollector {
+    proc_root: PathBuf,
+    sys_root: PathBuf,
+    last_cpu: Mutex<Option<CpuSample>>,
+    last_disks: Mutex<Option<DiskSample>>,
+    last_cgroups: Mutex<Option<CgroupSample>>,
+    /// The `statvfs` calls that did not finish before their deadline, keyed by mount point. A mount is not stat'd again until its previous call returns, so a hung mount cannot pile up blocked threads.
+    stuck_mounts: Mutex<HashMap<String, JoinHandle<Option<StorageMetric>>>>
+}
+impl Default for LinuxCollector {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROC_ROOT, DEFAULT_SYS_ROOT)
+    }
+}
+impl LinuxCollector {
+    /// Creates a collector that reads from the specified procfs & sysfs roots. Outside of testing, these should be `/proc` and `/sys`.
+    pub fn new(proc_root: impl Into<PathBuf>, sys_root: impl Into<PathBuf>) -> Self {
+        Self {
+            proc_root: proc_root.into(),
+            sys_root: sys_root.into(),
+            last_cpu: Mutex::new(None),
+            last_disks: Mutex::new(None),
+            last_cgroups: Mutex::new(None),
+            stuck_mounts: Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn read_proc(&self, path: &str) -> Option<String> {
+        read_to_string(self.proc_root.join(path)).await.ok()
+    }
+
+    /// Resolves a system file (such as `etc/passwd`) relative to the directory holding the procfs root, so that a collector reading another tree also reads its files.
+    fn system_path(&self, path: &str) -> PathBuf {
+        self.proc_root.parent().unwrap_or(&self.proc_root).join(path)
+    }
+
+    /// Reads the time since boot from `/proc/uptime`.
+    pub async fn uptime(&self) -> Option<Duration> {
+        let contents = self.read_proc("uptime").await?;
+        let secs: f64 = contents.split_whitespace().next()?.parse().ok()?;
+
+        Duration::try_from_secs_f64(secs).ok()
+    }
+}
+impl MetricsCollector for LinuxCollector {
+    /// Determines the CPU utilization since the last time this was called. The first call reports the utilization since boot.
+    async fn cpu(&self) -> Option<CpuMetric> {
+        let current = parse_stat(&self.read_proc("stat").await?)?;
+        let load = self.read_proc("loadavg").await
+            .as_deref()
+            .and_then(parse_loadavg);
+
+        let prev = {
+            let mut guard = match self.last_cpu.lock() {
+                Ok(g) => g,
+                Err(e) => e.into_inner()
+            };
+
+            guard.replace(current.clone()).unwrap_or_default()
+        };
+
+        let (total, cores) = current.since(&prev)?;
+        Some(
+            CpuMetric {
+                total,
+                cores,
+                load
+            }
+        )
+    }
+    async fn memory(&self) -> Vec<MemoryMetric> {
+        match self.read_proc("meminfo").await {
+            Some(v) => parse_meminfo(&v),
+            None => vec![]
+        }
+    }
+    async fn network(&self) -> Vec<NetworkMetric> {
+        let contents = match self.read_proc("net/dev").await {
+            Some(v) => v,
+            None => return vec![]
+        };
+
+        let mut result = vec![];
+        for (name, rx, tx) in parse_net_dev(&contents) {
+            let mtu_path = self.sys_root.join("class/net").join(&name).join("mtu");
+            let mtu = match read_to_string(mtu_path).await {
+                Ok(v) => v.trim().to_string(),
+                Err(_) => "unknown".to_string()
+            };
+
+            result.push(
+                NetworkMetric {
+                    name,
+                    mtu,
+                    rx,
+                    tx,
+                    rx_rate: None,
+                    tx_rate: None
+                }
+            );
+        }
+
+        result
+    }
+    /// Stats each mount on its own thread, so that a hung mount (such as a dead network share) only loses its own values.
+    async fn storage(&self, wait: Duration) -> (Vec<StorageMetric>, Vec<String>) {
+        let mounts = match self.read_proc("self/mounts").await {
+            Some(v) => parse_mounts(&v),
+            None => return (vec![], vec![])
+        };
+        let deadline = Instant::now() + wait;
+
+        let mut stuck = vec![];
+        let mut running = vec![];
+        {
+            let mut stuck_mounts = match self.stuck_mounts.lock() {
+                Ok(g) => g,
+                Err(e) => e.into_inner()
+            };
+
+            for (system, mount) in mounts {
+                if stuck_mounts.get(&mount).is_some_and(|x| !x.is_finished()) {
+                    stuck.push(mount);
+                    continue;
+                }
+                // The result of a call that finished late is stale, so it is discarded.
+                stuck_mounts.remove(&mount);
+
+                let key = mount.clone();
+                running.push( (key, spawn_blocking(move || stat_filesystem(system, mount))) );
+            }
+        }
+
+        let mut result = vec![];
+        for (mount, mut handle) in running {
+            match timeout_at(deadline, &mut handle).await {
+                Ok(Ok(Some(v))) => result.push(v),
+                Ok(_) => (),
+                Err(_) => {
+                    let mut stuck_mounts = match self.stuck_mounts.lock() {
+                        Ok(g) => g,
+                        Err(e) => e.into_inner()
+                    };
+                    stuck_mounts.insert(mount.clone(), handle);
+                    stuck.push(mount);
+                }
+            }
+        }
+
+        (result, stuck)
+    }
+    async fn disk_io(&self) -> Vec<DiskIoMetric> {
+        self.sample_disk_io().await
+    }
+    async fn sensors(&self) -> Vec<SensorMetric> {
+        self.read_hwmon().await
+    }
+    async fn cgroups(&self, tracked: &[String]) -> Vec<CgroupMetric> {
+        self.sample_cgroups(tracked).await
+    }
+    async fn pressure(&self) -> Vec<PressureMetric> {
+        self.read_pressure().await
+    }
+}
+
+#[tokio::test]
+async fn test_stuck_mounts() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("mounts");
+    create_dir_all(root.join("self")).unwrap();
+    write(root.join("self/mounts"), "/dev/sda1 / ext4 rw 0 0\n/dev/sdb1 /regisd-missing-mount ext4 rw 0 0\n/dev/sdc1 /mnt/stuck nfs rw 0 0\n").unwrap();
+
+    // The earlier stat of the stuck mount is still running, so it is skipped rather than stat'd again.
+    let collector = LinuxCollector::new(root.to_path_buf(), root.to_path_buf());
+    let hung = spawn_blocking(|| {
+        std::thread::sleep(Duration::from_millis(500));
+        None
+    });
+    collector.stuck_mounts.lock().unwrap().insert("/mnt/stuck".to_string(), hung);
+
+    let (storage, stuck) = collector.storage(Duration::from_secs(5)).await;
+    assert_eq!(storage.len(), 1);
+    assert_eq!(storage[0].mount, "/");
+    assert_eq!(stuck, vec!["/mnt/stuck".to_string()]);
+}
+
+#[tokio::test]
+async fn test_fixture_tree() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("linux");
+    let proc_root = root.join("proc");
+    let sys_root = root.join("sys");
+    create_dir_all(proc_root.join("net")).unwrap();
+    create_dir_all(sys_root.join("class/net/eth0")).unwrap();
+
+    write(proc_root.join("stat"), "cpu  10 0 10 80 0 0 0 0 0 0\ncpu0 10 0 10 80 0 0 0 0 0 0\n").unwrap();
+    write(proc_root.join("loadavg"), "1.00 0.50 0.25 1/100 1000\n").unwrap();
+    write(proc_root.join("meminfo"), "MemTotal:       1024 kB\nMemFree:         512 kB\nMemAvailable:    768 kB\nBuffers:          64 kB\nCached:          128 kB\nSwapCached:        0 kB\nSwapTotal:      2048 kB\nSwapFree:       2048 kB\n").unwrap();
+    write(
+        proc_root.join("net/dev"),
+        "Inter-|   Receive                                                |  Transmit\n face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n  eth0: 1000 10 1 2 3 0 0 0 2000 20 4 5 6 0 0 0\n"
+    ).unwrap();
+    write(sys_root.join("class/net/eth0/mtu"), "1500\n").unwrap();
+
+    let collector = LinuxCollector::new(&proc_root, &sys_root);
+
+    let cpu = collector.cpu().await.unwrap();
+    assert_eq!(cpu.total.user, 10.0);
+    assert_eq!(cpu.total.idle, 80.0);
+    assert_eq!(cpu.cores.len(), 1);
+    assert_eq!(cpu.load, Some(LoadAverage { one: 1.0, five: 0.5, fifteen: 0.25 }));
+
+    let memory = collector.memory().await;
+    assert_eq!(memory.len(), 2);
+    assert_eq!(memory[0].device, "Mem");
+    assert_eq!(memory[0].total, ByteCount(1024 * 1024));
+    assert_eq!(memory[1].device, "Swap");
+    assert_eq!(memory[1].free, ByteCount(2048 * 1024));
+
+    let network = collector.network().await;
+    assert_eq!(network.len(), 1);
+    assert_eq!(network[0].name, "eth0");
+    assert_eq!(network[0].mtu, "1500");
+    assert_eq!(network[0].rx, NetworkMetricSection { bytes: 1000, ok: 10, err: 1, drop: 2, overrun: 3 });
+    assert_eq!(network[0].tx, NetworkMetricSection { bytes: 2000, ok: 20, err: 4, drop: 5, overrun: 6 });
+}
@@ -0,0 +1,85 @@
+use common::metric::{PressureMetric, PressureResource, PressureValues};
+
+use super::LinuxCollector;
+
+/// Parses a single line of a pressure file, such as `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`, into its kind (`some` or `full`) and values.
+fn parse_pressure_line(line: &str) -> Option<(&str, PressureValues)> {
+    let mut splits = line.split_whitespace();
+    let kind = splits.next()?;
+
+    let mut avg10 = None;
+    let mut avg60 = None;
+    let mut avg300 = None;
+    let mut total = None;
+    for (key, value) in splits.filter_map(|x| x.split_once('=')) {
+        match key {
+            "avg10" => avg10 = value.parse().ok(),
+            "avg60" => avg60 = value.parse().ok(),
+            "avg300" => avg300 = value.parse().ok(),
+            "total" => total = value.parse().ok(),
+            _ => continue
+        }
+    }
+
+    Some(
+        (
+            kind,
+            PressureValues {
+                avg10: avg10?,
+                avg60: avg60?,
+                avg300: avg300?,
+                total: total?
+            }
+        )
+    )
+}
+
+/// Parses a file in `/proc/pressure`. The `full` line is not reported for CPU pressure by older kernels.
+fn parse_pressure(resource: PressureResource, contents: &str) -> Option<PressureMetric> {
+    let mut some = None;
+    let mut full = None;
+    for (kind, values) in contents.lines().filter_map(parse_pressure_line) {
+        match kind {
+            "some" => some = Some(values),
+            "full" => full = Some(values),
+            _ => continue
+        }
+    }
+
+    Some(
+        PressureMetric {
+            resource,
+            some: some?,
+            full
+        }
+    )
+}
+#[test]
+fn test_pressure_parsing() {
+    let contents = "some avg10=10.07 avg60=4.35 avg300=2.78 total=26533979\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+    let metric = parse_pressure(PressureResource::Cpu, contents).unwrap();
+    assert_eq!(metric.some, PressureValues { avg10: 10.07, avg60: 4.35, avg300: 2.78, total: 26533979 });
+    assert_eq!(metric.full, Some(PressureValues { avg10: 0.0, avg60: 0.0, avg300: 0.0, total: 0 }));
+
+    let old = parse_pressure(PressureResource::Cpu, "some avg10=1.00 avg60=2.00 avg300=3.00 total=100\n").unwrap();
+    assert_eq!(old.full, None);
+
+    assert_eq!(parse_pressure(PressureResource::Io, "some avg10=1.00\n"), None);
+}
+
+impl LinuxCollector {
+    /// Reads the CPU, memory, and IO pressure from `/proc/pressure`. This is empty if the kernel was built without PSI, or it was disabled at boot.
+    pub(super) async fn read_pressure(&self) -> Vec<PressureMetric> {
+        let mut result = vec![];
+        for (resource, file) in [(PressureResource::Cpu, "pressure/cpu"), (PressureResource::Memory, "pressure/memory"), (PressureResource::Io, "pressure/io")] {
+            let metric = self.read_proc(file).await
+                .and_then(|x| parse_pressure(resource, &x));
+
+            if let Some(metric) = metric {
+                result.push(metric);
+            }
+        }
+
+        result
+    }
+}
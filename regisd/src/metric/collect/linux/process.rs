@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::fs::{read, read_dir, read_to_string};
+use tokio::time::sleep;
+
+use common::metric::{ByteCount, ProcessMetric};
+use common::msg::ProcessSort;
+
+use super::LinuxCollector;
+
+/// The time between the two samples used to determine the CPU usage of each process.
+pub const PROCESS_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+const PASSWD_PATH: &str = "etc/passwd";
+
+/// The information read from a specific `/proc/[pid]` directory.
+#[derive(Clone, Debug, PartialEq)]
+struct ProcessSample {
+    state: char,
+    /// The user & system time (in clock ticks) spent by the process.
+    ticks: u64,
+    uid: u32,
+    rss: u64,
+    threads: u64,
+    command: String
+}
+
+/// Parses `/proc/[pid]/stat` into the state and the total clock ticks used. The command name is wrapped in parenthesis, and may contain spaces or parenthesis itself, so only the values after the last `)` are considered.
+fn parse_pid_stat(contents: &str) -> Option<(char, u64)> {
+    let (_, rest) = contents.rsplit_once(')')?;
+    let splits: Vec<&str> = rest.split_whitespace().collect();
+
+    /*
+        Format after the command:
+        [state] [ppid] [pgrp] [session] [tty] [tpgid] [flags] [minflt] [cminflt] [majflt] [cmajflt] [utime] [stime] ...
+     */
+    let state = splits.first()?.chars().next()?;
+    let utime: u64 = splits.get(11)?.parse().ok()?;
+    let stime: u64 = splits.get(12)?.parse().ok()?;
+
+    Some( (state, utime + stime) )
+}
+
+/// Parses `/proc/[pid]/status` into the process name, real user ID, resident memory (in bytes), and thread count.
+fn parse_pid_status(contents: &str) -> Option<(String, u32, u64, u64)> {
+    let mut name: Option<String> = None;
+    let mut uid: Option<u32> = None;
+    let mut rss: u64 = 0; // Kernel threads do not report VmRSS.
+    let mut threads: u64 = 1;
+    for line in contents.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue
+        };
+        let value = value.trim();
+
+        match key {
+            "Name" => name = Some(value.to_string()),
+            "Uid" => uid = value.split_whitespace().next().and_then(|x| x.parse().ok()),
+            "VmRSS" => {
+                rss = value.split_whitespace()
+                    .next()
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .unwrap_or(0) * 1024
+            },
+            "Threads" => threads = value.parse().unwrap_or(1),
+            _ => continue
+        }
+    }
+
+    Some( (name?, uid?, rss, threads) )
+}
+#[test]
+fn test_pid_parsing() {
+    let stat = "1234 (my (weird) proc) S 1 1234 1234 0 -1 4194560 100 0 0 0 25 15 0 0 20 0 3 0 100 1000 200";
+    assert_eq!(parse_pid_stat(stat), Some(('S', 40)));
+
+    let status = "Name:\tmy (weird) proc\nState:\tS (sleeping)\nUid:\t1000\t1000\t1000\t1000\nVmRSS:\t    2048 kB\nThreads:\t3\n";
+    assert_eq!(parse_pid_status(status), Some(("my (weird) proc".to_string(), 1000, 2048 * 1024, 3)));
+
+    assert_eq!(parse_cmdline(b"/usr/bin/regisd\0--daemon\0", "regisd"), "/usr/bin/regisd --daemon");
+    assert_eq!(parse_cmdline(b"", "kworker/0:1"), "[kworker/0:1]");
+}
+
+/// Converts the NUL separated contents of `/proc/[pid]/cmdline` into a single line command. Kernel threads have no command line, so the name is used instead.
+fn parse_cmdline(contents: &[u8], name: &str) -> String {
+    let args: Vec<String> = contents.split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).to_string())
+        .collect();
+
+    if args.is_empty() {
+        format!("[{name}]")
+    }
+    else {
+        args.join(" ").replace(|x: char| x.is_control(), " ")
+    }
+}
+
+/// Parses `/etc/passwd` into a lookup from user ID to user name.
+fn parse_passwd(contents: &str) -> HashMap<u32, String> {
+    let mut result = HashMap::new();
+    for line in contents.lines() {
+        let mut splits = line.split(':');
+        let (name, uid) = match (splits.next(), splits.nth(1)) {
+            (Some(n), Some(u)) => (n, u),
+            _ => continue
+        };
+
+        if let Ok(uid) = uid.parse::<u32>() {
+            result.insert(uid, name.to_string());
+        }
+    }
+
+    result
+}
+
+/// Reads the stat, status, and cmdline files for a specific process. This returns `None` if the process exited while being read.
+async fn sample_process(dir: &Path) -> Option<ProcessSample> {
+    let (state, ticks) = parse_pid_stat(&read_to_string(dir.join("stat")).await.ok()?)?;
+    let (name, uid, rss, threads) = parse_pid_status(&read_to_string(dir.join("status")).await.ok()?)?;
+    let cmdline = read(dir.join("cmdline")).await.unwrap_or_default();
+
+    Some(
+        ProcessSample {
+            state,
+            ticks,
+            uid,
+            rss,
+            threads,
+            command: parse_cmdline(&cmdline, &name)
+        }
+    )
+}
+
+/// Walks every `/proc/[pid]` directory under the procfs root.
+async fn sample_processes(proc_root: &Path) -> HashMap<u32, ProcessSample> {
+    let mut result = HashMap::new();
+    let mut entries = match read_dir(proc_root).await {
+        Ok(v) => v,
+        Err(_) => return result
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let pid: u32 = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+            Some(v) => v,
+            None => continue
+        };
+
+        if let Some(sample) = sample_process(&entry.path()).await {
+            result.insert(pid, sample);
+        }
+    }
+
+    result
+}
+
+fn clock_ticks() -> f64 {
+    // SAFETY: `sysconf` has no preconditions.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 {
+        ticks as f64
+    }
+    else {
+        100.0
+    }
+}
+
+/// Sorts the processes (highest first) and keeps the first `limit` of them. Each entry is the PID, the sample, and the CPU usage.
+fn sort_processes(processes: &mut Vec<(u32, ProcessSample, f64)>, sort: ProcessSort, limit: usize) {
+    match sort {
+        ProcessSort::Cpu => processes.sort_by(|a, b| b.2.total_cmp(&a.2).then(b.1.rss.cmp(&a.1.rss))),
+        ProcessSort::Memory => processes.sort_by(|a, b| b.1.rss.cmp(&a.1.rss).then(b.2.total_cmp(&a.2)))
+    }
+
+    processes.truncate(limit);
+}
+
+impl LinuxCollector {
+    /// Samples every process twice, `PROCESS_SAMPLE_INTERVAL` apart, and determines their CPU usage from the change in clock ticks.
+    pub async fn processes(&self, sort: ProcessSort, limit: usize) -> Vec<ProcessMetric> {
+        let first = sample_processes(&self.proc_root).await;
+        let start = Instant::now();
+        sleep(PROCESS_SAMPLE_INTERVAL).await;
+        let second = sample_processes(&self.proc_root).await;
+        let elapsed = start.elapsed().as_secs_f64() * clock_ticks();
+
+        let mut measured: Vec<(u32, ProcessSample, f64)> = second.into_iter()
+            .map(|(pid, sample)| {
+                // Processes that started between the samples are measured over their lifetime so far.
+                let prev_ticks = first.get(&pid).map(|x| x.ticks).unwrap_or(0);
+                let cpu = if elapsed > 0.0 {
+                    sample.ticks.saturating_sub(prev_ticks) as f64 / elapsed * 100.0
+                }
+                else {
+                    0.0
+                };
+
+                (pid, sample, cpu)
+            })
+            .collect();
+        sort_processes(&mut measured, sort, limit);
+
+        let users = read_to_string(self.system_path(PASSWD_PATH)).await
+            .map(|x| parse_passwd(&x))
+            .unwrap_or_default();
+
+        measured.into_iter()
+            .map(|(pid, sample, cpu)| {
+                ProcessMetric {
+                    pid,
+                    user: users.get(&sample.uid).cloned().unwrap_or_else(|| sample.uid.to_string()),
+                    command: sample.command,
+                    rss: ByteCount(sample.rss),
+                    state: sample.state,
+                    threads: sample.threads,
+                    cpu
+                }
+            })
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_process_tree() {
+    use std::fs::{create_dir_all, write};
+
+    let root = crate::metric::fixture::TempDir::new("process");
+    let proc_root = root.join("proc");
+    create_dir_all(proc_root.join("42")).unwrap();
+    create_dir_all(root.join("etc")).unwrap();
+
+    write(proc_root.join("42/stat"), "42 (sleepy) S 1 42 42 0 -1 4194560 100 0 0 0 25 15 0 0 20 0 1 0 100 1000 200").unwrap();
+    write(proc_root.join("42/status"), "Name:\tsleepy\nUid:\t1000\t1000\t1000\t1000\nVmRSS:\t    8 kB\nThreads:\t1\n").unwrap();
+    write(proc_root.join("42/cmdline"), b"sleepy\0--long\0").unwrap();
+    // The users are read from the tree, rather than from the system running the test.
+    write(root.join("etc/passwd"), "root:x:0:0::/root:/bin/sh\nfixture:x:1000:1000::/home/fixture:/bin/sh\n").unwrap();
+
+    let collector = LinuxCollector::new(proc_root, root.join("sys"));
+    let processes = collector.processes(ProcessSort::Cpu, 10).await;
+
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0].pid, 42);
+    assert_eq!(processes[0].user, "fixture");
+    assert_eq!(processes[0].command, "sleepy --long");
+    assert_eq!(processes[0].rss, ByteCount(8 * 1024));
+}
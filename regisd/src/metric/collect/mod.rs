@@ -12,6 +12,17 @@
 use tokio::process::Command;
 
 pub use common::metric::*;
+use common::config::DaemonConfig;
+use common::msg::{HostInfo, ProcessSort};
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use tokio::time::timeout_at;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::REGISD_VERSION;
 
 pub mod prelude;
 
@@ -363,14 +374,165 @@ pub async fn collect_process_count() -> Option<ProcessCount> {
 }
 */
 
+#[cfg(target_os = "linux")]
+lazy_static::lazy_static! {
+    /// The collector shared by the metrics task and status requests. The sampled families (see `MetricFamily::is_sampled`) keep their previous sample here, so only the metrics task collects them.
+    static ref COLLECTOR: linux::LinuxCollector = linux::LinuxCollector::default();
+}
+
+/// Collects every enabled family into a new snapshot, except for plugins & the sampled families, which are only collected by the metrics task.
 pub async fn collect_all_snapshots() -> CollectedMetrics {
+    use crate::config::CONFIG;
+
+    let config = CONFIG.access().access().cloned().unwrap_or_default();
+    let families: Vec<MetricFamily> = MetricFamily::ALL.into_iter()
+        .filter(|x| *x != MetricFamily::Plugins && !x.is_sampled() && config.collectors.get(*x).enabled)
+        .collect();
+
+    let mut result = CollectedMetrics {
+        time: chrono::Utc::now(),
+        ..Default::default()
+    };
+    for (values, status) in collect_families(&families, &config).await {
+        result.replace_family(status.family, values);
+        result.set_status(status);
+    }
+
+    result
+}
+
+/// The values of a single family (the other families are left empty), the parts of it that could not be collected, and how long it took to collect them.
+type FamilyOutput = (CollectedMetrics, Option<String>, Duration);
+
+lazy_static::lazy_static! {
+    /// Collections that did not finish before their deadline. A family is not collected again until its previous collection finishes, so a hung source cannot pile up tasks.
+    static ref IN_FLIGHT: Mutex<HashMap<MetricFamily, JoinHandle<FamilyOutput>>> = Mutex::new(HashMap::new());
+}
+
+/// Collects each of the families on its own task, waiting at most each family's timeout for it to finish. A family that hangs (such as a dead network mount) is reported as timed out, and does not hold up the others.
+pub async fn collect_families(families: &[MetricFamily], config: &DaemonConfig) -> Vec<(CollectedMetrics, CollectorStatus)> {
+    let start = Instant::now();
     let time = chrono::Utc::now();
 
-    CollectedMetrics {
-        time,
-        memory: vec![],
-        storage: vec![],
-        cpu: None,
-        network: vec![],
+    let mut running = vec![];
+    let mut result = vec![];
+    {
+        let mut in_flight = match IN_FLIGHT.lock() {
+            Ok(g) => g,
+            Err(e) => e.into_inner()
+        };
+
+        for family in families.iter().copied() {
+            if in_flight.get(&family).is_some_and(|x| !x.is_finished()) {
+                let status = CollectorStatus {
+                    family,
+                    state: CollectorState::TimedOut,
+                    time,
+                    duration: 0.0
+                };
+                result.push( (CollectedMetrics::default(), status) );
+                continue;
+            }
+            // Any finished, abandoned collection is stale, so it is discarded.
+            in_flight.remove(&family);
+
+            let config = config.clone();
+            let handle = tokio::spawn(async move {
+                let start = Instant::now();
+                let mut values = CollectedMetrics::default();
+                let failed = collect_family(family, &config, &mut values).await;
+
+                (values, failed, start.elapsed())
+            });
+            running.push( (family, handle) );
+        }
+    }
+
+    for (family, mut handle) in running {
+        let deadline = start + Duration::from_secs(config.collectors.get(family).timeout);
+        let (values, state, duration) = match timeout_at(deadline.into(), &mut handle).await {
+            Ok(Ok((values, None, duration))) => (values, CollectorState::Ok, duration),
+            // Part of the family failed, but the rest of its values are kept.
+            Ok(Ok((values, Some(failed), duration))) => (values, CollectorState::Error(failed), duration),
+            Ok(Err(e)) => (CollectedMetrics::default(), CollectorState::Error(e.to_string()), start.elapsed()),
+            Err(_) => {
+                let mut in_flight = match IN_FLIGHT.lock() {
+                    Ok(g) => g,
+                    Err(e) => e.into_inner()
+                };
+                in_flight.insert(family, handle);
+
+                (CollectedMetrics::default(), CollectorState::TimedOut, start.elapsed())
+            }
+        };
+
+        let status = CollectorStatus {
+            family,
+            state,
+            time,
+            duration: duration.as_secs_f64() * 1000.0
+        };
+        result.push( (values, status) );
+    }
+
+    result.sort_by_key(|x| x.1.family);
+    result
+}
+
+/// Collects a single metric family into `metrics`, replacing its previous values. This describes the parts of the family that could not be collected, if any.
+#[cfg(target_os = "linux")]
+pub async fn collect_family(family: MetricFamily, config: &DaemonConfig, metrics: &mut CollectedMetrics) -> Option<String> {
+    use prelude::MetricsCollector;
+
+    COLLECTOR.collect_family(family, config, metrics).await
+}
+
+#[cfg(target_os = "linux")]
+pub async fn collect_processes(sort: ProcessSort, limit: usize) -> Vec<ProcessMetric> {
+    COLLECTOR.processes(sort, limit).await
+}
+
+/// The host information is static, so it is only gathered once.
+static HOST_INFO: OnceCell<HostInfo> = OnceCell::const_new();
+
+/// Gathers the host information the first time it is called, and reuses it afterwards. Only the uptime is updated on each call.
+pub async fn host_info() -> HostInfo {
+    let mut info = HOST_INFO.get_or_init(gather_host_info).await.clone();
+    info.uptime = uptime().await;
+
+    info
+}
+
+#[cfg(target_os = "linux")]
+async fn gather_host_info() -> HostInfo {
+    COLLECTOR.host_info(REGISD_VERSION).await
+}
+
+/// In seconds, how long the host has been running, if it can be determined.
+#[cfg(target_os = "linux")]
+async fn uptime() -> Option<u64> {
+    COLLECTOR.uptime().await.map(|x| x.as_secs())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn uptime() -> Option<u64> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn gather_host_info() -> HostInfo {
+    HostInfo {
+        daemon_version: REGISD_VERSION.to_string(),
+        ..Default::default()
     }
 }
+
+#[cfg(not(target_os = "linux"))]
+pub async fn collect_family(_family: MetricFamily, _config: &DaemonConfig, _metrics: &mut CollectedMetrics) -> Option<String> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn collect_processes(_sort: ProcessSort, _limit: usize) -> Vec<ProcessMetric> {
+    vec![]
+}
@@ -1,20 +1,41 @@
-
-pub use common::metric::{MemoryMetric, NetworkMetric, CpuMetric, CollectedMetrics, StorageMetric};
-use chrono::Utc;
+pub use common::metric::{MemoryMetric, NetworkMetric, CpuMetric, CollectedMetrics, StorageMetric, DiskIoMetric, SensorMetric, CgroupMetric, PressureMetric, MetricFamily};
+use common::config::DaemonConfig;
+use std::time::Duration;
 
 pub(crate) trait MetricsCollector {
-    async fn memory() -> Vec<MemoryMetric>;
-    async fn network() -> Vec<NetworkMetric>;
-    async fn cpu() -> Option<CpuMetric>;
-    async fn storage() -> Vec<StorageMetric>;
+    async fn memory(&self) -> Vec<MemoryMetric>;
+    async fn network(&self) -> Vec<NetworkMetric>;
+    async fn cpu(&self) -> Option<CpuMetric>;
+    /// Collects every filesystem, waiting at most `wait` for each. The mount points that did not finish in time are returned alongside the values.
+    async fn storage(&self, wait: Duration) -> (Vec<StorageMetric>, Vec<String>);
+    async fn disk_io(&self) -> Vec<DiskIoMetric>;
+    async fn sensors(&self) -> Vec<SensorMetric>;
+    async fn cgroups(&self, tracked: &[String]) -> Vec<CgroupMetric>;
+    async fn pressure(&self) -> Vec<PressureMetric>;
 
-    async fn collect() -> CollectedMetrics {
-        CollectedMetrics {
-            time: Utc::now(),
-            memory: Self::memory().await,
-            storage: Self::storage().await,
-            cpu: Self::cpu().await,
-            network: Self::network().await
+    /// Collects a single family into `metrics`, replacing the previous values of that family. Plugins are run by the metrics task, so they are ignored here.
+    /// This describes the parts of the family that could not be collected, if any.
+    async fn collect_family(&self, family: MetricFamily, config: &DaemonConfig, metrics: &mut CollectedMetrics) -> Option<String> {
+        match family {
+            MetricFamily::Cpu => metrics.cpu = self.cpu().await,
+            MetricFamily::Memory => metrics.memory = self.memory().await,
+            MetricFamily::Storage => {
+                // Mounts are given half of the family's timeout, so that the healthy ones are reported before the family itself times out.
+                let wait = Duration::from_secs(config.collectors.get(family).timeout) / 2;
+                let stuck;
+                (metrics.storage, stuck) = self.storage(wait).await;
+                if !stuck.is_empty() {
+                    return Some(format!("timed out mounts: {}", stuck.join(", ")));
+                }
+            },
+            MetricFamily::DiskIo => metrics.disk_io = self.disk_io().await,
+            MetricFamily::Network => metrics.network = self.network().await,
+            MetricFamily::Sensors => metrics.sensors = self.sensors().await,
+            MetricFamily::Cgroups => metrics.cgroups = self.cgroups(&config.cgroups).await,
+            MetricFamily::Pressure => metrics.pressure = self.pressure().await,
+            MetricFamily::Plugins => ()
         }
+
+        None
     }
 }
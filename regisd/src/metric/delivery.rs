@@ -0,0 +1,221 @@
+/*
+    Delivery tasks
+
+    Exporter sinks and alert notifiers both hand data to destinations that may be slow or unreachable. Each
+    destination runs on its own task, fed through a bounded channel, and is retried with an exponential backoff
+    while it is failing. This holds what they share: the backoff, the task loop, and the list of running tasks.
+*/
+
+use tokio::select;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+use exdisj::io::log::{ConstructableLogger, Logger};
+use exdisj::{log_error, log_warning};
+
+/// How long a single delivery may take, including connecting or running a program.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// The first delay after a failed delivery. This doubles with each failure, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// When a failing destination should next be tried.
+#[derive(Default, Debug)]
+pub struct Backoff {
+    delay: Duration,
+    /// When the destination should next be retried, if it is currently failing.
+    retry_at: Option<Instant>
+}
+impl Backoff {
+    /// A backoff that is due right away, such as for data left over from a previous run.
+    pub fn immediate() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            retry_at: Some(Instant::now())
+        }
+    }
+
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    pub fn is_backing_off(&self) -> bool {
+        self.retry_at.is_some_and(|x| x > Instant::now())
+    }
+
+    /// Records a failed delivery, giving back how long to wait before the next one.
+    pub fn failed(&mut self) -> Duration {
+        self.delay = (self.delay * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+        self.retry_at = Some(Instant::now() + self.delay);
+
+        self.delay
+    }
+
+    pub fn succeeded(&mut self) {
+        self.delay = Duration::ZERO;
+        self.retry_at = None;
+    }
+}
+
+/// A destination that is run on its own task by `Workers`.
+pub(crate) trait Destination: Send + 'static {
+    /// What is handed to the task, such as a snapshot.
+    type Item: Clone + Send + 'static;
+    /// Names the kind of destination in log messages, such as `Sink`.
+    const KIND: &'static str;
+
+    fn name(&self) -> &str;
+    // These are spelled out, since the futures must be `Send` to run on a task.
+    /// Takes an item handed to the task.
+    fn receive(&mut self, logger: &impl Logger, item: Self::Item) -> impl Future<Output = ()> + Send;
+    /// Tries the destination again, once the backoff has passed.
+    fn retry(&mut self, logger: &impl Logger) -> impl Future<Output = ()> + Send;
+    /// When `retry` should next be called, if at all.
+    fn retry_at(&self) -> Option<Instant>;
+}
+
+/// Hands the items sent to `recv` to the destination until it is closed, retrying it as its backoff allows. The destination is then given back.
+async fn worker<D>(logger: impl Logger, mut destination: D, mut recv: Receiver<D::Item>) -> D
+where D: Destination {
+    loop {
+        let retry_at = destination.retry_at();
+        select! {
+            item = recv.recv() => {
+                match item {
+                    Some(item) => destination.receive(&logger, item).await,
+                    None => break
+                }
+            },
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                destination.retry(&logger).await;
+            }
+        }
+    }
+
+    destination
+}
+
+/// A running task, along with the settings it was started with.
+struct Task<C, D: Destination> {
+    config: C,
+    name: String,
+    sender: Sender<D::Item>,
+    handle: JoinHandle<D>
+}
+impl<C, D: Destination> Task<C, D> {
+    /// Closes the channel of the task, and waits for it to give back its destination. If it does not stop in time, it is aborted, so that it cannot touch the destination's state once it is started again.
+    async fn stop(self, logger: &impl Logger) -> Option<D> {
+        let (name, mut handle) = (self.name, self.handle);
+        let kind = D::KIND.to_lowercase();
+
+        drop(self.sender);
+        match timeout(SEND_TIMEOUT * 2, &mut handle).await {
+            Ok(Ok(v)) => Some(v),
+            Ok(Err(e)) => {
+                log_warning!(logger, "The task of {kind} '{name}' failed '{e}'.");
+                None
+            },
+            Err(_) => {
+                log_warning!(logger, "The task of {kind} '{name}' did not stop in time, so it was aborted.");
+                handle.abort();
+                let _ = handle.await;
+                None
+            }
+        }
+    }
+}
+
+/// The running tasks for a kind of destination.
+pub(crate) struct Workers<C, D: Destination> {
+    tasks: Vec<Task<C, D>>,
+    /// How many items may wait for a task before new ones are dropped.
+    channel_size: usize
+}
+impl<C, D> Workers<C, D>
+where C: PartialEq, D: Destination {
+    pub fn new(channel_size: usize) -> Self {
+        Self {
+            tasks: vec![],
+            channel_size
+        }
+    }
+
+    /// If a task was started with exactly these settings.
+    pub fn contains(&self, config: &C) -> bool {
+        self.tasks.iter().any(|x| x.config == *config)
+    }
+
+    /// Runs a task for the destination, logging to its own channel.
+    pub fn spawn<L>(&mut self, logger: &L, config: C, destination: D)
+    where L: ConstructableLogger + 'static {
+        let name = destination.name().to_string();
+        let their_logger = match logger.make_channel( format!("{} {name}", D::KIND).into() ) {
+            Ok(v) => v,
+            Err(e) => {
+                log_error!(logger, "Unable to make a channel for {} '{name}': '{e:?}'", D::KIND.to_lowercase());
+                return;
+            }
+        };
+
+        let (sender, recv) = channel(self.channel_size);
+        let handle = tokio::spawn(worker(their_logger, destination, recv));
+        self.tasks.push( Task { config, name, sender, handle } );
+    }
+
+    /// Stops the tasks whose settings should not be kept, giving back their destinations. The other tasks keep running.
+    pub async fn retain(&mut self, logger: &impl Logger, keep: impl Fn(&C) -> bool) -> Vec<D> {
+        let mut result = vec![];
+        for task in std::mem::take(&mut self.tasks) {
+            if keep(&task.config) {
+                self.tasks.push(task);
+                continue;
+            }
+
+            if let Some(destination) = task.stop(logger).await {
+                result.push(destination);
+            }
+        }
+
+        result
+    }
+
+    /// Hands an item to every task. If a task has fallen too far behind, the item is dropped for it.
+    pub fn send(&self, logger: &impl Logger, item: &D::Item) {
+        for task in &self.tasks {
+            let name = &task.name;
+            match task.sender.try_send(item.clone()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => log_warning!(logger, "{} '{name}' has fallen behind, an item was dropped.", D::KIND),
+                Err(TrySendError::Closed(_)) => log_warning!(logger, "{} '{name}' has stopped unexpectedly.", D::KIND)
+            }
+        }
+    }
+
+    /// Stops every task, giving back the destinations that stopped in time.
+    pub async fn stop(self, logger: &impl Logger) -> Vec<D> {
+        let mut result = vec![];
+        for task in self.tasks {
+            if let Some(destination) = task.stop(logger).await {
+                result.push(destination);
+            }
+        }
+
+        result
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::default();
+    assert!(!backoff.is_backing_off());
+    assert!(Backoff::immediate().retry_at().is_some() && !Backoff::immediate().is_backing_off());
+
+    let delays: Vec<Duration> = (0..11).map(|_| backoff.failed()).collect();
+    assert_eq!(delays[..3], [MIN_BACKOFF, MIN_BACKOFF * 2, MIN_BACKOFF * 4]);
+    assert_eq!(delays[10], MAX_BACKOFF);
+    assert!(backoff.is_backing_off());
+
+    backoff.succeeded();
+    assert_eq!((backoff.retry_at(), backoff.failed()), (None, MIN_BACKOFF));
+}
@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+use common::metric::CollectedMetrics;
+
+use crate::metric::series::{samples, FieldValue};
+
+/// Escapes the characters that are special within a line protocol key or tag.
+fn escape_influx(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ',' | '=' | ' ' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            },
+            '\n' | '\r' => result.push_str("\\ "),
+            c => result.push(c)
+        }
+    }
+
+    result
+}
+
+/// Encodes a snapshot as InfluxDB line protocol, one line per sample, with nanosecond timestamps. Measurements are prefixed with `regis_`, and tagged with the host.
+pub fn influx_lines(metrics: &CollectedMetrics, host: &str) -> String {
+    let timestamp = metrics.time.timestamp_nanos_opt().unwrap_or_default();
+    let host = escape_influx(host);
+
+    let mut out = String::new();
+    for sample in samples(metrics) {
+        let _ = write!(out, "regis_{},host={host}", sample.measurement);
+        for (key, value) in &sample.tags {
+            // Empty tag values are not allowed.
+            if !value.is_empty() {
+                let _ = write!(out, ",{key}={}", escape_influx(value));
+            }
+        }
+
+        let fields: Vec<String> = sample.fields.iter()
+            .map(|(key, value)| match value {
+                FieldValue::Float(v) => format!("{key}={v}"),
+                FieldValue::Integer(v) => format!("{key}={v}i")
+            })
+            .collect();
+        let _ = writeln!(out, " {} {timestamp}", fields.join(","));
+    }
+
+    out
+}
+
+/// Replaces the characters that would split or break a Graphite path.
+fn sanitize_graphite(value: &str) -> String {
+    let result: String = value.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+
+    if result.is_empty() {
+        "_".to_string()
+    }
+    else {
+        result
+    }
+}
+
+/// Encodes a snapshot as Graphite plaintext, one line per value, with the path `prefix.host.measurement.tags....field`.
+pub fn graphite_lines(metrics: &CollectedMetrics, prefix: &str, host: &str) -> String {
+    let timestamp = metrics.time.timestamp();
+    let mut base = String::new();
+    if !prefix.is_empty() {
+        base.push_str(prefix.trim_end_matches('.'));
+        base.push('.');
+    }
+    base.push_str(&sanitize_graphite(host));
+
+    let mut out = String::new();
+    for sample in samples(metrics) {
+        let mut path = format!("{base}.{}", sample.measurement);
+        for (_, value) in &sample.tags {
+            path.push('.');
+            path.push_str(&sanitize_graphite(value));
+        }
+
+        for (key, value) in &sample.fields {
+            let value = match value {
+                FieldValue::Float(v) => v.to_string(),
+                FieldValue::Integer(v) => v.to_string()
+            };
+            let _ = writeln!(out, "{path}.{key} {value} {timestamp}");
+        }
+    }
+
+    out
+}
+
+#[test]
+fn test_export_formats() {
+    use chrono::{TimeZone, Utc};
+    use common::metric::{ByteCount, CustomMetric, StorageMetric, Utilization};
+
+    let metrics = CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        storage: vec![
+            StorageMetric {
+                system: "/dev/sda1".to_string(),
+                mount: "/mnt/my disk".to_string(),
+                size: ByteCount(100),
+                used: ByteCount(40),
+                availiable: ByteCount(60),
+                capacity: Utilization::new_unwrap(40)
+            }
+        ],
+        custom: vec![
+            CustomMetric { plugin: "queue".to_string(), name: "depth".to_string(), value: 3.0 },
+            CustomMetric { plugin: "queue".to_string(), name: "broken".to_string(), value: f64::NAN }
+        ],
+        ..Default::default()
+    };
+
+    assert_eq!(
+        influx_lines(&metrics, "web 1"),
+        "regis_storage,host=web\\ 1,device=/dev/sda1,mount=/mnt/my\\ disk size=100,used=40,available=60,used_percent=40 1700000000000000000\n\
+        regis_custom,host=web\\ 1,plugin=queue,series=depth value=3 1700000000000000000\n"
+    );
+
+    assert_eq!(
+        graphite_lines(&metrics, "regis.", "web.example.com"),
+        "regis.web_example_com.storage._dev_sda1._mnt_my_disk.size 100 1700000000\n\
+        regis.web_example_com.storage._dev_sda1._mnt_my_disk.used 40 1700000000\n\
+        regis.web_example_com.storage._dev_sda1._mnt_my_disk.available 60 1700000000\n\
+        regis.web_example_com.storage._dev_sda1._mnt_my_disk.used_percent 40 1700000000\n\
+        regis.web_example_com.custom.queue.depth.value 3 1700000000\n"
+    );
+}
@@ -0,0 +1,393 @@
+/*
+    Time series exporters
+
+    Every snapshot is pushed to the configured sinks, as InfluxDB line protocol (over HTTP or UDP) or Graphite
+    plaintext (over TCP). Each sink runs on its own task, so that a slow sink does not hold up collection.
+
+    Snapshots are encoded as they arrive and sent in batches. When a batch cannot be sent, it is written to the
+    sink's spill queue on disk, and the sink is retried with an exponential backoff. Once it is reachable again, the
+    spilled batches are sent (oldest first) before any new data.
+*/
+
+pub mod format;
+pub mod spill;
+
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
+
+use common::config::{ExportProtocol, ExporterConfig};
+use common::loc::DAEMON_SPILL_DIR;
+use common::metric::CollectedMetrics;
+use exdisj::io::log::{ConstructableLogger, Logger};
+use exdisj::{log_debug, log_error, log_info, log_warning};
+
+use format::{graphite_lines, influx_lines};
+use spill::SpillQueue;
+use super::collect::host_info;
+use super::delivery::{Backoff, Destination, Workers, SEND_TIMEOUT};
+
+/// The largest UDP datagram sent, so that datagrams are not fragmented on most links.
+const MAX_DATAGRAM: usize = 1400;
+/// How many snapshots may wait for a sink task before new ones are dropped.
+const CHANNEL_SIZE: usize = 32;
+
+/// Sends `body` as an HTTP POST request to `path` at `address`, expecting a 2xx status. Each header is a whole `Name: value` line.
+pub async fn http_post(address: &str, path: &str, content_type: &str, headers: &[String], body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {address}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Only the status line is needed.
+    let mut response = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    while !response.contains(&b'\n') {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&chunk[..read]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines()
+        .next()
+        .and_then(|x| x.split_whitespace().nth(1))
+        .unwrap_or_default();
+    if status.starts_with('2') {
+        Ok(())
+    }
+    else {
+        Err( std::io::Error::other(format!("the server responded with '{}'", response.lines().next().unwrap_or_default())) )
+    }
+}
+
+/// Sends a batch of line protocol as an HTTP write request.
+async fn send_influx_http(config: &ExporterConfig, payload: &str) -> std::io::Result<()> {
+    let headers: Vec<String> = config.token.as_deref()
+        .map(|x| format!("Authorization: Token {x}"))
+        .into_iter()
+        .collect();
+
+    http_post(&config.address, &config.path, "text/plain; charset=utf-8", &headers, payload).await
+}
+
+/// Sends a batch of line protocol as UDP datagrams, splitting it on line boundaries.
+async fn send_influx_udp(address: &str, payload: &str) -> std::io::Result<()> {
+    let target = match lookup_host(address).await?.next() {
+        Some(v) => v,
+        None => return Err( std::io::Error::other(format!("unable to resolve '{address}'")) )
+    };
+    let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await?;
+
+    let mut datagram = String::with_capacity(MAX_DATAGRAM);
+    for line in payload.split_inclusive('\n') {
+        if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+            socket.send_to(datagram.as_bytes(), target).await?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        socket.send_to(datagram.as_bytes(), target).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends a batch of Graphite plaintext over a new TCP connection.
+async fn send_graphite(address: &str, payload: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(payload.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// A specific export destination, along with its pending batch, spill queue & backoff.
+pub struct Sink {
+    config: ExporterConfig,
+    host: String,
+    /// The encoded snapshots that have not been sent yet.
+    batch: Vec<String>,
+    spill: SpillQueue,
+    backoff: Backoff
+}
+impl Sink {
+    /// Opens the sink & its spill queue. Data spilled by a previous run will be sent once the sink is reachable.
+    pub async fn open(config: ExporterConfig, host: String, spill_dir: PathBuf) -> std::io::Result<Self> {
+        let spill = SpillQueue::open(spill_dir, config.spill_limit).await?;
+        let backoff = if spill.is_empty() { Backoff::default() } else { Backoff::immediate() };
+
+        Ok(
+            Self {
+                config,
+                host,
+                batch: vec![],
+                spill,
+                backoff
+            }
+        )
+    }
+
+    fn encode(&self, metrics: &CollectedMetrics) -> String {
+        match self.config.protocol {
+            ExportProtocol::InfluxHttp | ExportProtocol::InfluxUdp => influx_lines(metrics, &self.host),
+            ExportProtocol::Graphite => graphite_lines(metrics, &self.config.prefix, &self.host)
+        }
+    }
+
+    async fn send(&self, payload: &str) -> std::io::Result<()> {
+        let result = match self.config.protocol {
+            ExportProtocol::InfluxHttp => timeout(SEND_TIMEOUT, send_influx_http(&self.config, payload)).await,
+            ExportProtocol::InfluxUdp => timeout(SEND_TIMEOUT, send_influx_udp(&self.config.address, payload)).await,
+            ExportProtocol::Graphite => timeout(SEND_TIMEOUT, send_graphite(&self.config.address, payload)).await
+        };
+
+        match result {
+            Ok(v) => v,
+            Err(_) => Err( std::io::Error::new(std::io::ErrorKind::TimedOut, "the sink did not respond in time") )
+        }
+    }
+
+    fn failed(&mut self, logger: &impl Logger, error: std::io::Error) {
+        let delay = self.backoff.failed();
+        log_warning!(logger, "Unable to send to sink '{}' ('{error}'), retrying in {}s.", self.name(), delay.as_secs());
+    }
+
+    async fn spill(&mut self, logger: &impl Logger, payload: &str) {
+        match self.spill.push(payload).await {
+            Ok(0) => log_debug!(logger, "Spilled a batch for sink '{}' ({} waiting).", self.name(), self.spill.len()),
+            Ok(n) => log_warning!(logger, "The spill queue of sink '{}' is full, {n} of the oldest batches were discarded.", self.name()),
+            Err(e) => log_error!(logger, "Unable to spill a batch for sink '{}', it is lost ('{e}').", self.name())
+        }
+    }
+
+    /// Sends the spilled batches, oldest first, stopping at the first failure.
+    async fn drain(&mut self, logger: &impl Logger) -> std::io::Result<()> {
+        let mut sent = 0usize;
+        while let Some(payload) = self.spill.front().await? {
+            self.send(&payload).await?;
+            self.spill.pop().await?;
+            sent += 1;
+        }
+
+        if sent != 0 {
+            log_info!(logger, "Sent {sent} spilled batches to sink '{}'.", self.name());
+        }
+        Ok(())
+    }
+
+    /// Encodes a snapshot into the pending batch, and sends the batch once it is full.
+    pub async fn push(&mut self, logger: &impl Logger, metrics: &CollectedMetrics) {
+        self.batch.push(self.encode(metrics));
+        if self.batch.len() >= self.config.batch.max(1) {
+            self.flush(logger).await;
+        }
+    }
+
+    /// Sends the pending batch, after any spilled batches. If the sink is failing, the batch is spilled instead.
+    pub async fn flush(&mut self, logger: &impl Logger) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let payload = self.batch.concat();
+        self.batch.clear();
+
+        if self.backoff.is_backing_off() {
+            self.spill(logger, &payload).await;
+            return;
+        }
+
+        let result = match self.drain(logger).await {
+            Ok(()) => self.send(&payload).await,
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(()) => self.backoff.succeeded(),
+            Err(e) => {
+                self.spill(logger, &payload).await;
+                self.failed(logger, e);
+            }
+        }
+    }
+
+    /// Spills the pending batch, so that it is sent once the sink is started again.
+    pub async fn close(mut self, logger: &impl Logger) {
+        log_debug!(logger, "Closing sink '{}'.", self.name());
+        if !self.batch.is_empty() {
+            let payload = self.batch.concat();
+            self.spill(logger, &payload).await;
+        }
+    }
+}
+
+impl Destination for Sink {
+    type Item = CollectedMetrics;
+    const KIND: &'static str = "Sink";
+
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn receive(&mut self, logger: &impl Logger, item: CollectedMetrics) {
+        self.push(logger, &item).await;
+    }
+
+    /// Tries to send the spilled batches, once the backoff has passed.
+    async fn retry(&mut self, logger: &impl Logger) {
+        match self.drain(logger).await {
+            Ok(()) => self.backoff.succeeded(),
+            Err(e) => self.failed(logger, e)
+        }
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.backoff.retry_at()
+    }
+}
+
+/// The directory name used for a sink's spill queue.
+fn spill_dir(name: &str) -> PathBuf {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+
+    Path::new(DAEMON_SPILL_DIR).join(name)
+}
+
+/// Runs a task for every configured sink, and hands each new snapshot to them.
+pub struct ExportManager {
+    host: String,
+    sinks: Workers<ExporterConfig, Sink>
+}
+impl ExportManager {
+    pub async fn start<L>(logger: &L, configs: &[ExporterConfig]) -> Self
+    where L: ConstructableLogger + 'static {
+        let mut result = Self {
+            host: String::new(),
+            sinks: Workers::new(CHANNEL_SIZE)
+        };
+        result.reload(logger, configs).await;
+
+        result
+    }
+
+    /// Opens the sink's spill queue, and runs a task for it.
+    async fn spawn<L>(&mut self, logger: &L, config: &ExporterConfig)
+    where L: ConstructableLogger + 'static {
+        let sink = match Sink::open(config.clone(), self.host.clone(), spill_dir(&config.name)).await {
+            Ok(v) => v,
+            Err(e) => {
+                log_error!(logger, "Unable to open the spill queue of sink '{}' ('{e}'), it will not be used.", &config.name);
+                return;
+            }
+        };
+
+        log_info!(logger, "Exporting to sink '{}' ({:?} at '{}').", &config.name, config.protocol, &config.address);
+        self.sinks.spawn(logger, config.clone(), sink);
+    }
+
+    /// Applies new settings. Only the sinks whose own settings changed are restarted, and sinks that were removed are stopped. Pending batches are spilled by the old sinks, and picked up by the new ones.
+    pub async fn reload<L>(&mut self, logger: &L, configs: &[ExporterConfig])
+    where L: ConstructableLogger + 'static {
+        // Sinks share a spill queue if their names are the same once sanitised, so only the first of them is used.
+        let mut accepted: Vec<&ExporterConfig> = vec![];
+        for config in configs {
+            match accepted.iter().find(|x| spill_dir(&x.name) == spill_dir(&config.name)) {
+                Some(other) => log_error!(logger, "Sink '{}' would share the spill queue of sink '{}', rename it. It will not be used.", &config.name, &other.name),
+                None => accepted.push(config)
+            }
+        }
+
+        if self.host.is_empty() && !accepted.is_empty() {
+            self.host = host_info().await.hostname;
+        }
+
+        for sink in self.sinks.retain(logger, |x| accepted.contains(&x)).await {
+            sink.close(logger).await;
+        }
+
+        for config in accepted {
+            if self.sinks.contains(config) {
+                continue;
+            }
+
+            self.spawn(logger, config).await;
+        }
+    }
+
+    /// Hands a snapshot to every sink. If a sink has fallen too far behind, the snapshot is dropped for that sink.
+    pub fn send(&self, logger: &impl Logger, metrics: &CollectedMetrics) {
+        self.sinks.send(logger, metrics);
+    }
+
+    /// Stops every sink, waiting for their pending batches to be spilled.
+    pub async fn stop(self, logger: &impl Logger) {
+        for sink in self.sinks.stop(logger).await {
+            sink.close(logger).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_sink_delivery() {
+    use tokio::net::TcpListener;
+
+    let dir = super::fixture::TempDir::new("sink");
+    let logger = exdisj::io::log::NullLogger;
+
+    // The sink is down until something listens on the port.
+    let addr = super::fixture::closed_port().await;
+    let config = ExporterConfig {
+        batch: 2,
+        ..ExporterConfig::new("graphite".to_string(), ExportProtocol::Graphite, addr.to_string())
+    };
+    let mut sink = Sink::open(config, "web".to_string(), dir.to_path_buf()).await.unwrap();
+
+    let snapshot = |value: f64| CollectedMetrics {
+        custom: vec![common::metric::CustomMetric { plugin: "p".to_string(), name: "v".to_string(), value }],
+        ..Default::default()
+    };
+
+    sink.push(&logger, &snapshot(1.0)).await;
+    assert!(sink.spill.is_empty());
+    sink.push(&logger, &snapshot(2.0)).await;
+    assert_eq!(sink.spill.len(), 1);
+    assert!(sink.backoff.retry_at().is_some());
+
+    // While backing off, full batches go straight to the spill queue.
+    sink.push(&logger, &snapshot(3.0)).await;
+    sink.push(&logger, &snapshot(4.0)).await;
+    assert_eq!(sink.spill.len(), 2);
+
+    // Once the sink is up, the spilled batches are sent in order.
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let received = tokio::spawn(async move {
+        let mut result = String::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_to_string(&mut result).await.unwrap();
+        }
+        result
+    });
+
+    sink.retry(&logger).await;
+    assert!(sink.spill.is_empty());
+    assert_eq!(sink.backoff.retry_at(), None);
+
+    let received = received.await.unwrap();
+    let values: Vec<&str> = received.lines().filter_map(|x| x.split(' ').nth(1)).collect();
+    assert_eq!(values, vec!["1", "2", "3", "4"]);
+}
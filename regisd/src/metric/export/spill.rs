@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use tokio::fs::{create_dir_all, read_dir, read_to_string, remove_file, write};
+
+/// Batches that could not be sent, stored on disk (one file per batch) so that they survive restarts. The total size is bounded, and the oldest batches are discarded first.
+pub struct SpillQueue {
+    dir: PathBuf,
+    limit: u64,
+    /// The id & size of every stored batch, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    size: u64,
+    next: u64
+}
+impl SpillQueue {
+    /// Opens the queue in `dir`, creating it if needed, and picks up any batches left from a previous run.
+    pub async fn open(dir: PathBuf, limit: u64) -> std::io::Result<Self> {
+        create_dir_all(&dir).await?;
+
+        let mut entries = vec![];
+        let mut reader = read_dir(&dir).await?;
+        while let Some(entry) = reader.next_entry().await? {
+            let id: u64 = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+                Some(v) => v,
+                None => continue
+            };
+            entries.push( (id, entry.metadata().await?.len()) );
+        }
+        entries.sort();
+
+        let mut result = Self {
+            next: entries.last().map(|x| x.0 + 1).unwrap_or_default(),
+            size: entries.iter().map(|x| x.1).sum(),
+            entries: entries.into(),
+            dir,
+            limit
+        };
+        result.trim().await?;
+
+        Ok(result)
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id:020}"))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Removes the oldest batches until the queue fits within its limit, returning how many were removed.
+    async fn trim(&mut self) -> std::io::Result<usize> {
+        let mut removed = 0;
+        while self.size > self.limit {
+            if !self.pop().await? {
+                break;
+            }
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Stores a batch at the back of the queue. Returns how many of the oldest batches were discarded to make room.
+    pub async fn push(&mut self, payload: &str) -> std::io::Result<usize> {
+        let id = self.next;
+        write(self.path(id), payload).await?;
+
+        self.next += 1;
+        self.size += payload.len() as u64;
+        self.entries.push_back( (id, payload.len() as u64) );
+
+        self.trim().await
+    }
+
+    /// Reads the oldest batch, without removing it. Batches whose files were removed externally are skipped.
+    pub async fn front(&mut self) -> std::io::Result<Option<String>> {
+        while let Some((id, _)) = self.entries.front() {
+            match read_to_string(self.path(*id)).await {
+                Ok(v) => return Ok(Some(v)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    self.pop().await?;
+                },
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Removes the oldest batch. Returns false if the queue was empty.
+    pub async fn pop(&mut self) -> std::io::Result<bool> {
+        let (id, size) = match self.entries.pop_front() {
+            Some(v) => v,
+            None => return Ok(false)
+        };
+        self.size -= size;
+
+        match remove_file(self.path(id)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_spill_queue() {
+    let dir = crate::metric::fixture::TempDir::new("spill");
+
+    let mut queue = SpillQueue::open(dir.to_path_buf(), 10).await.unwrap();
+    assert_eq!(queue.push("aaaa").await.unwrap(), 0);
+    assert_eq!(queue.push("bbbb").await.unwrap(), 0);
+    // This exceeds the limit, so the oldest batch is discarded.
+    assert_eq!(queue.push("cccc").await.unwrap(), 1);
+    assert_eq!(queue.len(), 2);
+
+    // The remaining batches are picked up in order when reopened.
+    drop(queue);
+    let mut queue = SpillQueue::open(dir.to_path_buf(), 10).await.unwrap();
+    assert_eq!(queue.front().await.unwrap().as_deref(), Some("bbbb"));
+    assert!(queue.pop().await.unwrap());
+    queue.push("dddd").await.unwrap();
+
+    assert_eq!(queue.front().await.unwrap().as_deref(), Some("cccc"));
+    assert!(queue.pop().await.unwrap());
+    assert_eq!(queue.front().await.unwrap().as_deref(), Some("dddd"));
+    assert!(queue.pop().await.unwrap());
+    assert!(queue.is_empty());
+    assert!(!queue.pop().await.unwrap());
+}
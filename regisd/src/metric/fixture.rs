@@ -0,0 +1,48 @@
+/*
+    Test fixtures
+
+    Helpers shared by the tests that need files on disk, or a port that nothing is listening on.
+*/
+
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use tokio::net::TcpListener;
+
+/// An empty directory under the system's temporary directory, which is removed along with its contents when dropped.
+pub struct TempDir {
+    path: PathBuf
+}
+impl TempDir {
+    /// Creates the directory. The name must be unique among the tests, as they share a process.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("regisd-{name}-fixture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("unable to create the fixture directory");
+
+        Self { path }
+    }
+}
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Self::Target {
+        &self.path
+    }
+}
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Reserves a port on loopback, and leaves it closed, so that connections to it are refused until something binds it.
+pub async fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("unable to reserve a port");
+    listener.local_addr().expect("unable to read the reserved port")
+}
@@ -0,0 +1,293 @@
+/*
+    Capacity forecasting
+
+    Every so often, the usage of every filesystem & of memory over the configured window is read from the rollups (the
+    raw history is never read, as it could be large), and a straight line is fitted to each series by least squares.
+    This is done on its own task, so that the recorder is not held up while the rollups are read. The slope of the
+    line is the rate of growth, from which the time until the capacity is used up is projected. The coefficient of
+    determination of the fit is reported as the confidence, since a noisy or changing trend makes for an unreliable
+    projection. The projected days until full are also provided as series (such as `storage.days_to_full`), so that
+    alert rules can trigger on them.
+*/
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::task::JoinHandle;
+
+use common::config::{DaemonConfig, ForecastConfig};
+use common::metric::{Forecast, Resolution, RollupPoint};
+
+use super::history::read_range;
+use super::rollup::rollup_dir;
+
+/// How frequently the forecasts are made.
+const FORECAST_INTERVAL: StdDuration = StdDuration::from_secs(15 * 60);
+/// Picks the rollup resolution to read: the finest that covers the window in at most this many points. Every point in the window is read.
+const FORECAST_POINTS: usize = 240;
+/// The fewest points that a trend is fitted to.
+const MIN_POINTS: usize = 8;
+/// The series that are projected, each a percentage of its capacity.
+const FORECAST_FIELDS: [&str; 2] = ["storage.used_percent", "memory.used_percent"];
+/// In days, the furthest projection. This is also the days until full of a series that is not filling, so that alerts on it resolve.
+pub const FORECAST_HORIZON_DAYS: f64 = 365.0;
+
+lazy_static::lazy_static! {
+    /// The forecasts as of the most recent run, so that they can be sent to clients.
+    static ref CURRENT_FORECASTS: RwLock<Vec<Forecast>> = RwLock::new(vec![]);
+}
+
+/// The forecasts as of the most recent run, soonest to be full first.
+pub fn current_forecasts() -> Vec<Forecast> {
+    match CURRENT_FORECASTS.read() {
+        Ok(v) => v.clone(),
+        Err(e) => e.into_inner().clone()
+    }
+}
+
+/// A line fitted by least squares.
+#[derive(PartialEq, Debug)]
+struct Trend {
+    /// The change in `y` per unit of `x`
+    slope: f64,
+    /// The coefficient of determination, from 0 to 1
+    r_squared: f64
+}
+
+/// Fits a line to the points. This is `None` if the points do not span a range of `x`.
+fn fit_trend(points: &[(f64, f64)]) -> Option<Trend> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|x| x.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|x| x.1).sum::<f64>() / n;
+
+    let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in points {
+        sxx += (x - mean_x).powi(2);
+        sxy += (x - mean_x) * (y - mean_y);
+        syy += (y - mean_y).powi(2);
+    }
+    if sxx == 0.0 {
+        return None;
+    }
+
+    // A constant series is fitted exactly by a flat line.
+    let r_squared = if syy == 0.0 { 1.0 } else { sxy * sxy / (sxx * syy) };
+    Some(
+        Trend {
+            slope: sxy / sxx,
+            r_squared
+        }
+    )
+}
+
+/// Projects when a series (oldest point first) will reach 100%.
+fn project(series: String, points: &[(DateTime<Utc>, f64)], time: DateTime<Utc>) -> Option<Forecast> {
+    if points.len() < MIN_POINTS {
+        return None;
+    }
+
+    let fitted: Vec<(f64, f64)> = points.iter()
+        .map(|(at, value)| ((*at - time).num_seconds() as f64, *value))
+        .collect();
+    let trend = fit_trend(&fitted)?;
+    let current = points.last()?.1;
+
+    let full_at = if current >= 100.0 {
+        Some(time)
+    }
+    else if trend.slope > 0.0 {
+        let seconds = (100.0 - current) / trend.slope;
+        (seconds <= FORECAST_HORIZON_DAYS * 86400.0).then(|| time + Duration::seconds(seconds as i64))
+    }
+    else {
+        None
+    };
+
+    Some(
+        Forecast {
+            series,
+            time,
+            current,
+            rate: trend.slope * 86400.0,
+            full_at,
+            confidence: trend.r_squared,
+            samples: points.len()
+        }
+    )
+}
+
+/// Projects every series of the points (oldest first), soonest to be full first.
+fn project_points(points: &[RollupPoint], time: DateTime<Utc>) -> Vec<Forecast> {
+    let mut by_series: BTreeMap<&str, Vec<(DateTime<Utc>, f64)>> = BTreeMap::new();
+    for point in points {
+        for (key, value) in &point.series {
+            by_series.entry(key).or_default().push( (point.time, value.avg) );
+        }
+    }
+
+    let mut result: Vec<Forecast> = by_series.into_iter()
+        .filter_map(|(key, values)| project(key.to_string(), &values, time))
+        .collect();
+    result.sort_by_key(|x| (x.full_at.is_none(), x.full_at));
+
+    result
+}
+
+/// The projected days until full of every forecast, keyed like `storage.days_to_full{device=/dev/sda1,mount=/}`. Forecasts less confident than `min_confidence` are treated as not filling.
+fn days_to_full_series(forecasts: &[Forecast], min_confidence: f64) -> Vec<(String, f64)> {
+    forecasts.iter()
+        .map(|x| {
+            let days = match x.days_to_full() {
+                Some(v) if x.confidence >= min_confidence => v.min(FORECAST_HORIZON_DAYS),
+                _ => FORECAST_HORIZON_DAYS
+            };
+
+            (x.series.replacen("used_percent", "days_to_full", 1), days)
+        })
+        .collect()
+}
+
+/// Reads the forecast series from the rollups in `dir` over `window` seconds, and projects them.
+async fn make_forecasts(dir: PathBuf, window: u64) -> std::io::Result<Vec<Forecast>> {
+    let to = Utc::now();
+    let mut points: Vec<RollupPoint> = read_range(&dir, to - Duration::seconds(window as i64), to).await?;
+    for point in &mut points {
+        point.series.retain(|key, _| FORECAST_FIELDS.contains(&key.split('{').next().unwrap_or(key)));
+    }
+
+    Ok( project_points(&points, to) )
+}
+
+/// Periodically forecasts the capacity of filesystems & memory.
+pub struct ForecastEngine {
+    config: ForecastConfig,
+    /// When the forecasts should next be made.
+    next_run: Instant,
+    /// The projected days until full of every forecast, for the alert rules.
+    derived: Vec<(String, f64)>,
+    /// The forecasts being made, if any.
+    running: Option<JoinHandle<std::io::Result<Vec<Forecast>>>>
+}
+impl ForecastEngine {
+    pub fn new(config: ForecastConfig) -> Self {
+        let mut result = Self {
+            config: ForecastConfig::default(),
+            next_run: Instant::now(),
+            derived: vec![],
+            running: None
+        };
+        result.set_config(config);
+
+        result
+    }
+
+    /// Replaces the settings. Any forecasts being made are abandoned, and they are made again on the next snapshot.
+    pub fn set_config(&mut self, config: ForecastConfig) {
+        if let Some(running) = self.running.take() {
+            running.abort();
+        }
+        if !config.enabled {
+            self.publish(vec![]);
+        }
+
+        self.config = config;
+        self.next_run = Instant::now();
+    }
+
+    fn publish(&mut self, forecasts: Vec<Forecast>) {
+        self.derived = days_to_full_series(&forecasts, self.config.min_confidence);
+        match CURRENT_FORECASTS.write() {
+            Ok(mut v) => *v = forecasts,
+            Err(e) => *e.into_inner() = forecasts
+        }
+    }
+
+    /// Determines if the forecasts should be made again.
+    pub fn is_due(&self) -> bool {
+        self.config.enabled && self.running.is_none() && self.next_run <= Instant::now()
+    }
+
+    /// The projected days until full of every forecast, keyed like `storage.days_to_full{device=/dev/sda1,mount=/}`.
+    pub fn derived(&self) -> &[(String, f64)] {
+        &self.derived
+    }
+
+    /// Starts making the forecasts on their own task, from the rollups of the history. The rollups must be enabled, since the raw history is never read; otherwise, there are no forecasts.
+    pub fn start(&mut self, config: &DaemonConfig) {
+        self.next_run = Instant::now() + FORECAST_INTERVAL;
+        if !config.rollups.enabled {
+            self.publish(vec![]);
+            return;
+        }
+
+        // The raw resolution is never read, so a short window uses the minute rollups.
+        let resolution = match Resolution::for_span(self.config.window, FORECAST_POINTS, config.metric_freq) {
+            Resolution::Raw => Resolution::Minute,
+            v => v
+        };
+        let dir = rollup_dir(&config.history.dir, resolution);
+        self.running = Some( tokio::spawn(make_forecasts(dir, self.config.window)) );
+    }
+
+    /// Publishes the forecasts once they have been made, returning how many series were projected. This is `None` if they are not finished (or were not started).
+    pub async fn finish(&mut self) -> Option<std::io::Result<usize>> {
+        if !self.running.as_ref().is_some_and(|x| x.is_finished()) {
+            return None;
+        }
+
+        let result = match self.running.take()?.await {
+            Ok(Ok(forecasts)) => {
+                let projected = forecasts.len();
+                self.publish(forecasts);
+                Ok(projected)
+            },
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err( std::io::Error::other(e) )
+        };
+
+        Some(result)
+    }
+}
+
+#[test]
+fn test_capacity_forecast() {
+    use chrono::TimeZone;
+
+    let time = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    let hours_ago = |hours: i64| time - Duration::hours(hours);
+
+    assert_eq!(fit_trend(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]), Some(Trend { slope: 2.0, r_squared: 1.0 }));
+    assert_eq!(fit_trend(&[(1.0, 1.0), (1.0, 3.0)]), None);
+
+    // The root filesystem grows by 1% a day, steadily; the data filesystem is flat; memory is noisy.
+    let mut points = vec![];
+    for hour in (0..48).rev() {
+        let mut point = RollupPoint::new(hours_ago(hour), 3600);
+        point.add("storage.used_percent{device=sda1,mount=/}", 80.0 - hour as f64 / 24.0);
+        point.add("storage.used_percent{device=sdb1,mount=/data}", 40.0);
+        point.add("memory.used_percent{device=ram}", if hour % 2 == 0 { 30.0 } else { 60.0 });
+        points.push(point);
+    }
+    // Too few points to fit a trend.
+    points[40].add("storage.used_percent{device=sdc1,mount=/tmp}", 99.0);
+
+    let forecasts = project_points(&points, time);
+    assert_eq!(forecasts.len(), 3);
+    let root = &forecasts[0];
+    assert_eq!(root.series, "storage.used_percent{device=sda1,mount=/}");
+    assert!((root.rate - 1.0).abs() < 1e-9 && (root.confidence - 1.0).abs() < 1e-9);
+    assert!((root.days_to_full().unwrap() - 20.0).abs() < 0.01);
+    assert_eq!(forecasts[1].full_at, None);
+
+    let derived = days_to_full_series(&forecasts, 0.5);
+    assert_eq!(derived[0].0, "storage.days_to_full{device=sda1,mount=/}");
+    assert!((derived[0].1 - 20.0).abs() < 0.01);
+    assert!(derived[1..].iter().all(|x| x.1 == FORECAST_HORIZON_DAYS));
+}
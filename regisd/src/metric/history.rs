@@ -0,0 +1,316 @@
+/*
+    On-disk metric history
+
+    Snapshots evicted from the in-memory queue are appended to segment files, one JSON snapshot per line. Each
+    segment is named after the time of its first snapshot, and a new segment is started once the current one
+    reaches the configured size. The oldest segments are removed once the history exceeds its size or age limits.
+    The rollups are stored the same way, in their own directories.
+*/
+
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::fs::{create_dir_all, read_dir, read_to_string, remove_file, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use common::config::HistoryConfig;
+use exdisj::io::lock::OptionRwProvider;
+
+use super::collect::CollectedMetrics;
+use super::io::METRICS;
+use crate::config::CONFIG;
+
+const SEGMENT_EXTENSION: &str = ".jsonl";
+
+/// A specific segment file, identified by the time of its first snapshot (in milliseconds since the epoch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    start: i64,
+    size: u64
+}
+
+fn segment_path(dir: &Path, start: i64) -> PathBuf {
+    dir.join(format!("{start:020}{SEGMENT_EXTENSION}"))
+}
+
+/// Finds the segments in `dir`, oldest first. A missing directory has no segments.
+async fn list_segments(dir: &Path) -> std::io::Result<Vec<Segment>> {
+    let mut reader = match read_dir(dir).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e)
+    };
+
+    let mut result = vec![];
+    while let Some(entry) = reader.next_entry().await? {
+        let start: i64 = match entry.file_name().to_str().and_then(|x| x.strip_suffix(SEGMENT_EXTENSION)).and_then(|x| x.parse().ok()) {
+            Some(v) => v,
+            None => continue
+        };
+        result.push( Segment { start, size: entry.metadata().await?.len() } );
+    }
+
+    result.sort_by_key(|x| x.start);
+    Ok(result)
+}
+
+/// Something stored in segment files, which are ordered by time.
+pub trait Timestamped {
+    fn time(&self) -> DateTime<Utc>;
+}
+impl Timestamped for CollectedMetrics {
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+/// How large segments may grow, and how much is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentLimits {
+    /// In bytes, how large a segment may grow before a new one is started.
+    pub segment_size: u64,
+    /// In bytes, the most that is kept.
+    pub max_size: u64,
+    /// In seconds, how long entries are kept.
+    pub max_age: Option<u64>
+}
+
+/// Appends entries to the segment files in a directory, and enforces the retention limits.
+pub struct SegmentStore<T> {
+    dir: PathBuf,
+    limits: SegmentLimits,
+    segments: Vec<Segment>,
+    /// The newest segment, opened for appending.
+    writer: Option<File>,
+    _marker: PhantomData<T>
+}
+impl<T> SegmentStore<T> where T: Serialize + Timestamped {
+    /// Opens the segments in `dir`, creating it if needed. Segments from previous runs are kept, subject to the retention limits.
+    pub async fn open(dir: PathBuf, limits: SegmentLimits) -> std::io::Result<Self> {
+        create_dir_all(&dir).await?;
+        let segments = list_segments(&dir).await?;
+
+        let mut result = Self {
+            dir,
+            limits,
+            segments,
+            writer: None,
+            _marker: PhantomData
+        };
+        result.retain(Utc::now()).await?;
+
+        Ok(result)
+    }
+
+    /// The total size of the segment files, in bytes.
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|x| x.size).sum()
+    }
+
+    /// Removes the oldest segments until the store fits within its limits, returning how many were removed. The newest segment is never removed.
+    async fn retain(&mut self, now: DateTime<Utc>) -> std::io::Result<usize> {
+        let cutoff = self.limits.max_age.map(|x| (now - Duration::seconds(x as i64)).timestamp_millis());
+
+        let mut removed = 0;
+        while self.segments.len() > 1 {
+            let too_large = self.size() > self.limits.max_size;
+            // Every entry in the oldest segment is older than the start of the next one.
+            let too_old = cutoff.is_some_and(|x| self.segments[1].start <= x);
+            if !too_large && !too_old {
+                break;
+            }
+
+            let segment = self.segments.remove(0);
+            match remove_file(segment_path(&self.dir, segment.start)).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e)
+            }
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Appends an entry, starting a new segment if the current one is full.
+    pub async fn append(&mut self, value: &T) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(value)?;
+        line.push('\n');
+
+        let rotate = match self.segments.last() {
+            Some(v) => v.size >= self.limits.segment_size,
+            None => true
+        };
+        if rotate {
+            self.writer = None;
+
+            let mut start = value.time().timestamp_millis();
+            if let Some(last) = self.segments.last() {
+                start = start.max(last.start + 1);
+            }
+            self.segments.push( Segment { start, size: 0 } );
+        }
+
+        let segment = match self.segments.last_mut() {
+            Some(v) => v,
+            None => return Err( std::io::Error::other("the store has no segments") )
+        };
+        if self.writer.is_none() {
+            self.writer = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(segment_path(&self.dir, segment.start))
+                    .await?
+            );
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await?;
+            segment.size += line.len() as u64;
+        }
+
+        if rotate {
+            self.retain(value.time()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends evicted snapshots to the history.
+pub struct HistoryStore {
+    config: HistoryConfig,
+    store: SegmentStore<CollectedMetrics>
+}
+impl HistoryStore {
+    pub async fn open(config: HistoryConfig) -> std::io::Result<Self> {
+        let limits = SegmentLimits {
+            segment_size: config.segment_size,
+            max_size: config.max_size,
+            max_age: config.max_age
+        };
+        let store = SegmentStore::open(config.dir.clone(), limits).await?;
+
+        Ok( Self { config, store } )
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    pub async fn append(&mut self, metrics: &CollectedMetrics) -> std::io::Result<()> {
+        self.store.append(metrics).await
+    }
+}
+
+/// Reads the entries stored in `dir` between `from` and `to` (inclusive), oldest first. Lines that cannot be read (such as one being written) are skipped.
+pub async fn read_range<T>(dir: &Path, from: DateTime<Utc>, to: DateTime<Utc>) -> std::io::Result<Vec<T>>
+where T: DeserializeOwned + Timestamped {
+    let segments = list_segments(dir).await?;
+    let (from_ms, to_ms) = (from.timestamp_millis(), to.timestamp_millis());
+
+    let mut result = vec![];
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.start > to_ms {
+            break;
+        }
+        // Every entry in a segment is older than the start of the next one.
+        if segments.get(i + 1).is_some_and(|x| x.start < from_ms) {
+            continue;
+        }
+
+        let contents = match read_to_string(segment_path(dir, segment.start)).await {
+            Ok(v) => v,
+            // The segment was removed by the retention limits since it was listed.
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        result.extend(
+            contents.lines()
+                .filter_map(|x| serde_json::from_str::<T>(x).ok())
+                .filter(|x| x.time() >= from && x.time() <= to)
+        );
+    }
+
+    Ok(result)
+}
+
+/// The snapshots taken between `from` and `to` (inclusive), oldest first. Snapshots that were evicted from memory are read from the history on disk.
+pub async fn snapshots_between(from: DateTime<Utc>, to: DateTime<Utc>) -> Option<Vec<CollectedMetrics>> {
+    let mut result = METRICS.between(from, to)?;
+
+    let config = CONFIG.access()
+        .access()
+        .map(|x| x.history.clone())?;
+    if !config.enabled {
+        return Some(result);
+    }
+
+    // Anything on disk was evicted from memory, so it is older than every snapshot in memory.
+    let to = match result.first() {
+        Some(v) => v.time - Duration::milliseconds(1),
+        None => to
+    };
+    if let Ok(mut older) = read_range(&config.dir, from, to).await {
+        older.append(&mut result);
+        result = older;
+    }
+
+    Some(result)
+}
+
+#[tokio::test]
+async fn test_history_segments() {
+    use chrono::TimeZone;
+
+    let dir = super::fixture::TempDir::new("history");
+
+    let at = |secs: i64| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap(),
+        ..Default::default()
+    };
+    let line_size = serde_json::to_string(&at(0)).unwrap().len() as u64 + 1;
+    let config = HistoryConfig {
+        enabled: true,
+        dir: dir.to_path_buf(),
+        // Two snapshots per segment, and at most three segments.
+        segment_size: line_size * 2,
+        max_size: line_size * 6,
+        max_age: None
+    };
+
+    let mut store = HistoryStore::open(config.clone()).await.unwrap();
+    for secs in 0..7 {
+        store.append(&at(secs)).await.unwrap();
+    }
+    // The first segment was removed to make room for the fourth.
+    assert_eq!(store.store.segments.len(), 3);
+
+    let times = |x: Vec<CollectedMetrics>| x.into_iter().map(|x| x.time.timestamp() - 1_700_000_000).collect::<Vec<_>>();
+    let range = |from: i64, to: i64| read_range::<CollectedMetrics>(&dir, at(from).time, at(to).time);
+    assert_eq!(times(range(4, 6).await.unwrap()), vec![4, 5, 6]);
+    assert_eq!(times(range(3, 4).await.unwrap()), vec![3, 4]);
+    assert_eq!(times(range(-100, 100).await.unwrap()), vec![2, 3, 4, 5, 6]);
+    assert!(range(7, 100).await.unwrap().is_empty());
+
+    // History survives reopening, and the age limit removes segments whose snapshots are all too old.
+    drop(store);
+    let mut store = HistoryStore::open(HistoryConfig { max_age: Some(1), ..config }).await.unwrap();
+    assert_eq!(store.store.segments.len(), 1);
+    store.append(&at(7)).await.unwrap();
+    assert_eq!(times(range(-100, 100).await.unwrap()), vec![6, 7]);
+}
+
+#[test]
+fn test_older_snapshots() {
+    // A snapshot written before the disk IO, sensor, cgroup, pressure, plugin & status families were added.
+    let line = r#"{"time":"2023-11-14T22:13:20Z","memory":[],"storage":[],"cpu":null,"network":[]}"#;
+    let snapshot: CollectedMetrics = serde_json::from_str(line).unwrap();
+
+    assert_eq!(snapshot.time.timestamp(), 1_700_000_000);
+    assert!(snapshot.disk_io.is_empty() && snapshot.custom.is_empty() && snapshot.collectors.is_empty());
+}
@@ -2,16 +2,21 @@ use super::{collect::CollectedMetrics, storage::LimitedQueue};
 use exdisj::io::lock::{RwProvider, RwProviderAccess, ProtectedAccess};
 use std::sync::{Arc, RwLock};
 
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
 use lazy_static::lazy_static;
 
 pub const METRICS_HOLDING: usize = 50;
+/// How many snapshots a subscriber can fall behind before it misses some.
+pub const SUBSCRIBER_BACKLOG: usize = 16;
 
 type Storage = LimitedQueue<CollectedMetrics>;
 
-// Thing to improve: Include a mechanism that will eventually hold the discarded items from memory. After that buffer fills up, write it to a file, with a specified number of files kept. This way there is some more historical data, but not stored in memory all of the time.
-
 pub struct MetricProvider {
-    inner: Arc<RwLock<LimitedQueue<CollectedMetrics>>>
+    inner: Arc<RwLock<LimitedQueue<CollectedMetrics>>>,
+    /// Every inserted snapshot is also sent to the subscribed clients.
+    updates: broadcast::Sender<CollectedMetrics>
 }
 impl Default for MetricProvider {
     fn default() -> Self {
@@ -20,7 +25,8 @@ impl Default for MetricProvider {
                 RwLock::new(
                     LimitedQueue::new(METRICS_HOLDING)
                 )
-            )
+            ),
+            updates: broadcast::channel(SUBSCRIBER_BACKLOG).0
         }
     }
 }
@@ -46,18 +52,45 @@ impl MetricProvider {
         self.inner.clear_poison();
     }
     
-    pub fn push(&self, data: CollectedMetrics) -> bool {
+    /// Inserts a snapshot, giving back the oldest snapshot if it was evicted to make room. This is `None` if the storage could not be accessed.
+    pub fn push(&self, data: CollectedMetrics) -> Option<Option<CollectedMetrics>> {
+        if self.updates.receiver_count() != 0 {
+            // This only fails when there are no subscribers.
+            let _ = self.updates.send(data.clone());
+        }
+
         self.access_mut()
             .access()
             .map(|x| x.insert(data))
-            .is_some()
     }
+    /// Receives every snapshot inserted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<CollectedMetrics> {
+        self.updates.subscribe()
+    }
+    /// Clones the most recently inserted snapshot, if there is one.
+    pub fn latest(&self) -> Option<CollectedMetrics> {
+        self.access()
+            .access()
+            .and_then(|x| x.back().cloned())
+    }
+    /// Clones the snapshots taken between `from` and `to` (inclusive), oldest first.
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<Vec<CollectedMetrics>> {
+        self.access()
+            .access()
+            .map(|x| {
+                x.iter()
+                    .filter(|x| x.time >= from && x.time <= to)
+                    .cloned()
+                    .collect()
+            })
+    }
+    /// Clones the `n` most recent snapshots, oldest first.
     pub fn view(&self, n: usize) -> Option<Vec<CollectedMetrics>> {
         self.access()
             .access()
             .map(|x| {
-                x.get(n)
-                    .into_iter()
+                x.iter()
+                    .skip(x.len().saturating_sub(n))
                     .cloned()
                     .collect()
             })
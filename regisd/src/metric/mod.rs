@@ -1,30 +1,80 @@
+pub mod alert;
+pub mod anomaly;
 pub mod collect;
+pub mod delivery;
+pub mod export;
+#[cfg(test)]
+mod fixture;
+pub mod forecast;
+pub mod history;
 pub mod io;
+pub mod notify;
+pub mod plugin;
+pub mod prometheus;
+pub mod query;
+pub mod record;
+pub mod rollup;
+pub mod schedule;
+pub mod series;
 pub mod storage;
+pub mod subscribe;
 
-use collect::collect_all_snapshots;
+use collect::{collect_families, CollectedMetrics, CollectorState, CollectorStatus, MetricFamily, NetworkMetric};
+use export::ExportManager;
 use io::METRICS;
+use plugin::{PluginManager, PluginRun, PluginRunner};
+use record::Recorder;
+use schedule::CollectionSchedule;
 
-use exdisj::{log_info, log_debug, log_warning};
+use exdisj::{log_info, log_debug, log_error, log_warning};
 use exdisj::io::lock::OptionRwProvider;
-use exdisj::io::log::Logger;
+use exdisj::io::log::ConstructableLogger;
 use exdisj::task::{ChildComm, TaskMessage};
 use tokio::select;
 use tokio::time::interval;
 
-use std::time::Duration;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use common::loc::DAEMON_PLUGIN_DIR;
 use crate::{config::CONFIG, msg::{SimpleComm, WorkerTaskResult}};
 
-pub async fn metrics_entry(logger: impl Logger, mut recv: ChildComm<SimpleComm>) -> WorkerTaskResult {
-    let mut freq = match CONFIG.access().access() {
-        Some(v) => v.metric_freq,
+/// Determines the throughput of each link in `current`, using the values from `elapsed_secs` ago.
+pub fn apply_network_rates(current: &mut [NetworkMetric], prev: &[NetworkMetric], elapsed_secs: f64) {
+    for link in current {
+        if let Some(old) = prev.iter().find(|x| x.name == link.name) {
+            link.compute_rates(old, elapsed_secs);
+        }
+    }
+}
+
+/// Fills in the values of `current` that are determined between snapshots (network throughput), using the previous snapshot.
+pub fn apply_rates(current: &mut CollectedMetrics, prev: &CollectedMetrics) {
+    let elapsed = (current.time - prev.time).num_milliseconds() as f64 / 1000.0;
+    apply_network_rates(&mut current.network, &prev.network, elapsed);
+}
+
+pub async fn metrics_entry<L: ConstructableLogger + 'static>(logger: L, mut recv: ChildComm<SimpleComm>) -> WorkerTaskResult {
+    let mut config = match CONFIG.access().access() {
+        Some(v) => v.clone(),
         None => return WorkerTaskResult::Configuration
     };
+    let recorder = match Recorder::start(&logger, &config) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!(&logger, "Unable to make a channel for the recorder: '{e:?}'");
+            return WorkerTaskResult::Failure;
+        }
+    };
+    let mut plugins = PluginRunner::new(PluginManager::load(&logger, Path::new(DAEMON_PLUGIN_DIR), &config.plugins).await);
+    let mut schedule = CollectionSchedule::new(&config);
+    let mut exporters = ExportManager::start(&logger, &config.exporters).await;
 
-    log_info!(&logger, "Started recording with frequency {freq} seconds.");
+    log_info!(&logger, "Started recording with frequency {} seconds.", config.metric_freq);
 
-    let mut intv = interval(Duration::from_secs(freq));
+    let mut intv = interval(schedule.tick());
+    // The most recent values of every family. Each family is replaced as it is sampled, and snapshots are copied from this.
+    let mut current = CollectedMetrics::default();
 
     loop {
         select! {
@@ -33,28 +83,130 @@ pub async fn metrics_entry(logger: impl Logger, mut recv: ChildComm<SimpleComm>)
                     TaskMessage::Poll => continue,
                     TaskMessage::Kill => {
                         log_info!(&logger, "Got kill message from Orch.");
+                        exporters.stop(&logger).await;
+                        recorder.stop(&logger).await;
                         break;
                     }
                     TaskMessage::Inner(SimpleComm::ReloadConfiguration) => {
-                        freq = match CONFIG.access().access() {
-                            Some(v) => v.metric_freq,
+                        config = match CONFIG.access().access() {
+                            Some(v) => v.clone(),
                             None => {
                                 log_warning!(&logger, "Unable to reload from configuration. Aboriting.");
                                 return WorkerTaskResult::Configuration;
                             }
                         };
-                        intv = interval(Duration::from_secs(freq));
+                        schedule = CollectionSchedule::new(&config);
+                        intv = interval(schedule.tick());
+                        // Families that were disabled should no longer be reported.
+                        current = CollectedMetrics::default();
+                        // Any persistent plugins are stopped when the old runner is dropped.
+                        plugins = PluginRunner::new(PluginManager::load(&logger, Path::new(DAEMON_PLUGIN_DIR), &config.plugins).await);
+                        exporters.reload(&logger, &config.exporters).await;
+                        recorder.reload(&logger, &config).await;
                         log_info!(&logger, "Configuration reloaded");
                         continue;
                     }
                 }
             },
-            _ = intv.tick() => {
-                log_debug!(&logger, "Collecting metrics.");
-                let metrics = collect_all_snapshots().await;
-                if !METRICS.push(metrics) {
-                    log_warning!(&logger, "Unable to insert into metrics. Resetting provider...");
-                    METRICS.reset();
+            now = intv.tick() => {
+                let now = now.into_std();
+                let due = schedule.due(now);
+                let families: Vec<MetricFamily> = due.iter()
+                    .map(|x| x.0)
+                    .filter(|x| *x != MetricFamily::Plugins)
+                    .collect();
+
+                // The plugins run alongside the other families, and are held to the same kind of deadline.
+                let plugins_due = due.iter().any(|x| x.0 == MetricFamily::Plugins) && !plugins.is_empty();
+                let plugins_time = chrono::Utc::now();
+                let plugins_started = if plugins_due {
+                    let freq = schedule.interval(MetricFamily::Plugins).map(|x| x.as_secs()).unwrap_or(config.metric_freq);
+                    plugins.start(freq).await
+                }
+                else {
+                    false
+                };
+
+                log_debug!(&logger, "Collecting {} metric families.", families.len());
+                for (values, status) in collect_families(&families, &config).await {
+                    if status.state != CollectorState::Ok {
+                        log_warning!(&logger, "Collecting {} metrics failed ({}) after {:.1}ms.", status.family, &status.state, status.duration);
+                    }
+
+                    if status.family == MetricFamily::Network {
+                        let prev = std::mem::take(&mut current.network);
+                        current.replace_family(status.family, values);
+
+                        let elapsed = due.iter().find(|x| x.0 == MetricFamily::Network).and_then(|x| x.1);
+                        if let Some(elapsed) = elapsed {
+                            apply_network_rates(&mut current.network, &prev, elapsed.as_secs_f64());
+                        }
+                    }
+                    else {
+                        current.replace_family(status.family, values);
+                    }
+                    current.set_status(status);
+                }
+
+                if plugins_due {
+                    let run = if plugins_started {
+                        let deadline = now + Duration::from_secs(config.collectors.get(MetricFamily::Plugins).timeout);
+                        plugins.finish(deadline).await
+                    }
+                    else {
+                        PluginRun::TimedOut
+                    };
+
+                    let (values, state) = match run {
+                        PluginRun::Finished(values, failed) => {
+                            for (name, e) in &failed {
+                                log_warning!(&logger, "Plugin '{name}' failed: {e}.");
+                            }
+
+                            let state = if failed.is_empty() {
+                                CollectorState::Ok
+                            }
+                            else {
+                                let names: Vec<&str> = failed.iter().map(|x| x.0.as_str()).collect();
+                                CollectorState::Error(format!("failed plugins: {}", names.join(", ")))
+                            };
+                            (values, state)
+                        },
+                        PluginRun::TimedOut => {
+                            log_warning!(&logger, "The plugins did not respond in time.");
+                            (vec![], CollectorState::TimedOut)
+                        },
+                        PluginRun::Failed(e) => {
+                            log_error!(&logger, "The plugin task failed '{e}', no plugins will be run until the configuration is reloaded.");
+                            (vec![], CollectorState::Error(e.to_string()))
+                        }
+                    };
+
+                    current.custom = values;
+                    current.set_status(
+                        CollectorStatus {
+                            family: MetricFamily::Plugins,
+                            state,
+                            time: plugins_time,
+                            duration: Instant::now().duration_since(now).as_secs_f64() * 1000.0
+                        }
+                    );
+                }
+
+                if !schedule.snapshot_due(now) {
+                    continue;
+                }
+
+                let mut snapshot = current.clone();
+                snapshot.time = chrono::Utc::now();
+                exporters.send(&logger, &snapshot);
+                match METRICS.push(snapshot.clone()) {
+                    Some(evicted) => recorder.record(&logger, snapshot, evicted),
+                    None => {
+                        log_warning!(&logger, "Unable to insert into metrics. Resetting provider...");
+                        METRICS.reset();
+                        recorder.record(&logger, snapshot, None);
+                    }
                 }
 
                 //log_debug!("(Metrics) Metrics inserted");
@@ -0,0 +1,595 @@
+/*
+    Alert notifications
+
+    Whenever an alert changes state, it is handed to every configured notifier: a JSON webhook, an email through an
+    SMTP relay, or a local program. Each notifier runs on its own task with a queue of notifications, delivered in
+    order. A failed delivery is retried with an exponential backoff, and discarded once the notifier's retries are
+    used up. Notifications that were delivered (or queued) within the past hour are skipped, and each notifier only
+    delivers a limited number per hour, so that a flapping series does not flood it.
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::process::Stdio;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac as _};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration, Instant};
+
+use common::alert::{Alert, AlertSeverity, AlertState};
+use common::config::{NotifierConfig, NotifierTarget};
+use exdisj::io::log::{ConstructableLogger, Logger};
+use exdisj::{log_debug, log_error, log_info, log_warning};
+
+use super::collect::host_info;
+use super::delivery::{Backoff, Destination, Workers, SEND_TIMEOUT};
+use super::export::http_post;
+
+/// How long delivered notifications are remembered, for both deduplication & rate limiting.
+const RECENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// How many notifications may wait for a notifier task before new ones are dropped.
+const CHANNEL_SIZE: usize = 64;
+
+/// The alert change delivered to notifiers. This is the body of webhook requests.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub host: String,
+    pub alert: Alert
+}
+impl Notification {
+    /// Identifies a specific change of a specific alert.
+    fn key(&self) -> (&str, &str, AlertState, AlertSeverity, DateTime<Utc>) {
+        (&self.alert.rule, &self.alert.series, self.alert.state, self.alert.severity, self.alert.since)
+    }
+
+    fn subject(&self) -> String {
+        format!("[regis] {} {} '{}' on {}", self.alert.state, self.alert.severity, &self.alert.rule, &self.host)
+    }
+
+    /// The plain text description of the alert, one field per line.
+    fn describe(&self) -> String {
+        let alert = &self.alert;
+        let mut result = format!(
+            "Host: {}\nRule: {}\nSeries: {}\nState: {}\nSeverity: {}\nValue: {:.2}\nSince: {}\n",
+            &self.host,
+            &alert.rule,
+            &alert.series,
+            alert.state,
+            alert.severity,
+            alert.value,
+            alert.since
+        );
+        if let Some(fired) = alert.fired {
+            result.push_str(&format!("Fired: {fired}\n"));
+        }
+        if let Some(resolved) = alert.resolved {
+            result.push_str(&format!("Resolved: {resolved}\n"));
+        }
+
+        result
+    }
+}
+
+/// The hex encoded HMAC-SHA256 of `body`.
+fn sign(secret: &str, body: &str) -> std::io::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| std::io::Error::other("the webhook secret is invalid"))?;
+    mac.update(body.as_bytes());
+
+    Ok( mac.finalize().into_bytes().iter().map(|x| format!("{x:02x}")).collect() )
+}
+
+/// Splits a plain HTTP URL into the address to connect to, and the path.
+fn split_url(url: &str) -> std::io::Result<(String, String)> {
+    let rest = match url.strip_prefix("http://") {
+        Some(v) => v,
+        None => return Err( std::io::Error::other(format!("'{url}' is not a plain HTTP URL")) )
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+
+    // IPv6 hosts are bracketed, so only a colon after the bracket is a port.
+    let has_port = match host.rfind(']') {
+        Some(i) => host[i..].contains(':'),
+        None => host.contains(':')
+    };
+    let address = if has_port { host.to_string() } else { format!("{host}:80") };
+
+    Ok( (address, path.to_string()) )
+}
+
+/// POSTs the notification as JSON, signed with `secret` if present.
+async fn send_webhook(url: &str, secret: Option<&str>, notification: &Notification) -> std::io::Result<()> {
+    let (address, path) = split_url(url)?;
+    let body = serde_json::to_string(notification)?;
+
+    let mut headers = vec![];
+    if let Some(secret) = secret {
+        headers.push( format!("X-Regis-Signature: sha256={}", sign(secret, &body)?) );
+    }
+
+    http_post(&address, &path, "application/json", &headers, &body).await
+}
+
+/// Sends an SMTP command (if any), and reads the reply. The reply code must start with `expected`.
+async fn smtp_step<R, W>(read: &mut R, write: &mut W, command: Option<&str>, expected: char) -> std::io::Result<()>
+where R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin {
+    if let Some(command) = command {
+        write.write_all(format!("{command}\r\n").as_bytes()).await?;
+    }
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 {
+            return Err( std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the server closed the connection") );
+        }
+        // Every line but the last of a reply has a dash after the code.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+
+    if line.starts_with(expected) {
+        Ok(())
+    }
+    else {
+        Err( std::io::Error::other(format!("the server responded with '{}'", line.trim_end())) )
+    }
+}
+
+/// Sends the notification as an email, through an SMTP relay that needs no authentication.
+async fn send_email(server: &str, from: &str, to: &[String], notification: &Notification) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(server).await?;
+    let (read, mut write) = stream.split();
+    let mut read = BufReader::new(read);
+
+    smtp_step(&mut read, &mut write, None, '2').await?;
+    smtp_step(&mut read, &mut write, Some(&format!("EHLO {}", &notification.host)), '2').await?;
+    smtp_step(&mut read, &mut write, Some(&format!("MAIL FROM:<{from}>")), '2').await?;
+    for recipient in to {
+        smtp_step(&mut read, &mut write, Some(&format!("RCPT TO:<{recipient}>")), '2').await?;
+    }
+    smtp_step(&mut read, &mut write, Some("DATA"), '3').await?;
+
+    let mut message = format!(
+        "From: <{from}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        to.iter().map(|x| format!("<{x}>")).collect::<Vec<_>>().join(", "),
+        notification.subject(),
+        Utc::now().to_rfc2822()
+    );
+    for line in notification.describe().lines() {
+        // Lines starting with a dot are escaped, so that they do not end the message.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    write.write_all(message.as_bytes()).await?;
+    smtp_step(&mut read, &mut write, None, '2').await?;
+
+    smtp_step(&mut read, &mut write, Some("QUIT"), '2').await
+}
+
+/// The environment variables that describe the notification to a program.
+fn alert_env(notification: &Notification) -> std::io::Result<Vec<(&'static str, String)>> {
+    let alert = &notification.alert;
+    let mut result = vec![
+        ("REGIS_HOST", notification.host.clone()),
+        ("REGIS_ALERT_RULE", alert.rule.clone()),
+        ("REGIS_ALERT_SERIES", alert.series.clone()),
+        ("REGIS_ALERT_STATE", alert.state.to_string()),
+        ("REGIS_ALERT_SEVERITY", alert.severity.to_string()),
+        ("REGIS_ALERT_VALUE", alert.value.to_string()),
+        ("REGIS_ALERT_SINCE", alert.since.to_rfc3339()),
+        ("REGIS_ALERT_JSON", serde_json::to_string(notification)?)
+    ];
+    if let Some(fired) = alert.fired {
+        result.push( ("REGIS_ALERT_FIRED", fired.to_rfc3339()) );
+    }
+    if let Some(resolved) = alert.resolved {
+        result.push( ("REGIS_ALERT_RESOLVED", resolved.to_rfc3339()) );
+    }
+
+    Ok(result)
+}
+
+/// Runs a program with the notification in its environment, expecting it to exit successfully.
+async fn send_exec(program: &std::path::Path, args: &[String], notification: &Notification) -> std::io::Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .envs(alert_env(notification)?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+
+    if status.success() {
+        Ok(())
+    }
+    else {
+        Err( std::io::Error::other(format!("the program exited with {status}")) )
+    }
+}
+
+/// Delivers a notification to a specific target.
+async fn send(target: &NotifierTarget, notification: &Notification) -> std::io::Result<()> {
+    let result = match target {
+        NotifierTarget::Webhook { url, secret } => timeout(SEND_TIMEOUT, send_webhook(url, secret.as_deref(), notification)).await,
+        NotifierTarget::Email { server, from, to } => timeout(SEND_TIMEOUT, send_email(server, from, to, notification)).await,
+        NotifierTarget::Exec { program, args } => timeout(SEND_TIMEOUT, send_exec(program, args, notification)).await
+    };
+
+    match result {
+        Ok(v) => v,
+        Err(_) => Err( std::io::Error::new(std::io::ErrorKind::TimedOut, "the notifier did not respond in time") )
+    }
+}
+
+/// A notification waiting to be delivered.
+struct Pending {
+    notification: Notification,
+    /// How many deliveries have failed so far.
+    failures: u32
+}
+
+/// A specific notification destination, along with its queue, backoff & recent deliveries.
+pub struct Notifier {
+    config: NotifierConfig,
+    queue: VecDeque<Pending>,
+    /// The notifications delivered within `RECENT_WINDOW`, oldest first.
+    recent: VecDeque<(Notification, Instant)>,
+    backoff: Backoff
+}
+impl Notifier {
+    pub fn new(config: NotifierConfig) -> Self {
+        Self {
+            config,
+            queue: VecDeque::new(),
+            recent: VecDeque::new(),
+            backoff: Backoff::default()
+        }
+    }
+
+    /// Replaces the settings, keeping the queued & recently delivered notifications. Queued notifications that are no longer accepted are dropped, and delivery is retried right away.
+    fn set_config(&mut self, config: NotifierConfig) {
+        self.queue.retain(|x| config.accepts(&x.notification.alert));
+        self.config = config;
+        self.backoff = Backoff::default();
+    }
+
+    /// Queues a notification, unless the notifier does not accept it, it is a duplicate, or too many were delivered in the past hour.
+    pub fn enqueue(&mut self, logger: &impl Logger, notification: Notification) {
+        if !self.config.accepts(&notification.alert) {
+            return;
+        }
+
+        while self.recent.front().is_some_and(|x| x.1.elapsed() >= RECENT_WINDOW) {
+            self.recent.pop_front();
+        }
+
+        let key = notification.key();
+        let duplicate = self.recent.iter().any(|x| x.0.key() == key) || self.queue.iter().any(|x| x.notification.key() == key);
+        if duplicate {
+            log_debug!(logger, "Skipping a duplicate notification for '{}' on '{}'.", &notification.alert.rule, &notification.alert.series);
+            return;
+        }
+
+        if self.recent.len() + self.queue.len() >= self.config.max_per_hour as usize {
+            log_warning!(logger, "Notifier '{}' has reached its limit of {} per hour, the notification '{}' was dropped.", self.name(), self.config.max_per_hour, &notification.alert);
+            return;
+        }
+
+        self.queue.push_back( Pending { notification, failures: 0 } );
+    }
+
+    /// Delivers the queued notifications in order, stopping at the first failure.
+    pub async fn deliver(&mut self, logger: &impl Logger) {
+        while let Some(pending) = self.queue.front_mut() {
+            match send(&self.config.target, &pending.notification).await {
+                Ok(()) => {
+                    self.backoff.succeeded();
+                    if let Some(pending) = self.queue.pop_front() {
+                        self.recent.push_back( (pending.notification, Instant::now()) );
+                    }
+                },
+                Err(e) => {
+                    pending.failures += 1;
+                    if pending.failures > self.config.retries {
+                        log_error!(logger, "Unable to deliver '{}' to notifier '{}' after {} attempts ('{e}'), it is discarded.", &pending.notification.alert, &self.config.name, pending.failures);
+                        self.queue.pop_front();
+                    }
+
+                    let delay = self.backoff.failed();
+                    log_warning!(logger, "Unable to deliver to notifier '{}' ('{e}'), retrying in {}s.", self.name(), delay.as_secs());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Destination for Notifier {
+    type Item = Notification;
+    const KIND: &'static str = "Notifier";
+
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn receive(&mut self, logger: &impl Logger, item: Notification) {
+        self.enqueue(logger, item);
+        if !self.backoff.is_backing_off() {
+            self.deliver(logger).await;
+        }
+    }
+
+    async fn retry(&mut self, logger: &impl Logger) {
+        self.deliver(logger).await;
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.backoff.retry_at().filter(|_| !self.queue.is_empty())
+    }
+}
+
+/// Runs a task for every configured notifier, and hands each alert change to them.
+pub struct NotifyManager {
+    host: String,
+    notifiers: Workers<NotifierConfig, Notifier>
+}
+impl NotifyManager {
+    pub async fn start<L>(logger: &L, configs: &[NotifierConfig]) -> Self
+    where L: ConstructableLogger + 'static {
+        let mut result = Self {
+            host: String::new(),
+            notifiers: Workers::new(CHANNEL_SIZE)
+        };
+        result.reload(logger, configs).await;
+
+        result
+    }
+
+    /// Applies new settings. Only the notifiers whose own settings changed are restarted, and they keep their queued & recently delivered notifications. Notifiers that were removed are stopped.
+    pub async fn reload<L>(&mut self, logger: &L, configs: &[NotifierConfig])
+    where L: ConstructableLogger + 'static {
+        if self.host.is_empty() && !configs.is_empty() {
+            self.host = host_info().await.hostname;
+        }
+
+        let mut changed: HashMap<String, Notifier> = self.notifiers.retain(logger, |x| configs.contains(x)).await
+            .into_iter()
+            .map(|x| (x.name().to_string(), x))
+            .collect();
+
+        for config in configs {
+            if self.notifiers.contains(config) {
+                continue;
+            }
+
+            let notifier = match changed.remove(&config.name) {
+                Some(mut v) => {
+                    v.set_config(config.clone());
+                    v
+                },
+                None => Notifier::new(config.clone())
+            };
+            log_info!(logger, "Delivering alerts to notifier '{}'.", notifier.name());
+            self.notifiers.spawn(logger, config.clone(), notifier);
+        }
+
+        for notifier in changed.into_values().filter(|x| !x.queue.is_empty()) {
+            log_warning!(logger, "Removing notifier '{}' with {} notifications undelivered.", notifier.name(), notifier.queue.len());
+        }
+    }
+
+    /// Hands the changed alerts to every notifier. If a notifier has fallen too far behind, the notification is dropped for it.
+    pub fn notify(&self, logger: &impl Logger, alerts: &[Alert]) {
+        for alert in alerts {
+            let notification = Notification {
+                host: self.host.clone(),
+                alert: alert.clone()
+            };
+            self.notifiers.send(logger, &notification);
+        }
+    }
+
+    /// Stops every notifier. Notifications that were not delivered yet are discarded.
+    pub async fn stop(self, logger: &impl Logger) {
+        for notifier in self.notifiers.stop(logger).await {
+            if !notifier.queue.is_empty() {
+                log_warning!(logger, "Closing notifier '{}' with {} notifications undelivered.", notifier.name(), notifier.queue.len());
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_notifier_delivery() {
+    use chrono::TimeZone;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    let logger = exdisj::io::log::NullLogger;
+    let notification = |series: &str, state: AlertState| Notification {
+        host: "web".to_string(),
+        alert: Alert {
+            rule: "high".to_string(),
+            series: series.to_string(),
+            severity: AlertSeverity::Critical,
+            state,
+            value: 95.0,
+            since: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            fired: None,
+            resolved: None
+        }
+    };
+
+    // The webhook is down until something listens on the port.
+    let addr = super::fixture::closed_port().await;
+    let config = NotifierConfig {
+        retries: 1,
+        max_per_hour: 3,
+        ..NotifierConfig::new(
+            "hook".to_string(),
+            NotifierTarget::Webhook { url: format!("http://{addr}/alerts"), secret: Some("key".to_string()) }
+        )
+    };
+    let mut notifier = Notifier::new(config);
+
+    notifier.enqueue(&logger, notification("a", AlertState::Firing));
+    notifier.enqueue(&logger, notification("a", AlertState::Firing));
+    notifier.enqueue(&logger, notification("a", AlertState::Pending));
+    notifier.enqueue(&logger, notification("b", AlertState::Firing));
+    assert_eq!(notifier.queue.len(), 2);
+
+    // The first notification is discarded once its retry is used up, keeping the rest of the queue.
+    notifier.deliver(&logger).await;
+    assert_eq!((notifier.queue.len(), notifier.queue[0].failures), (2, 1));
+    assert!(notifier.backoff.retry_at().is_some());
+    notifier.deliver(&logger).await;
+    assert_eq!(notifier.queue.len(), 1);
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let received = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+
+        let mut head = vec![];
+        let mut line = String::new();
+        while stream.read_line(&mut line).await.unwrap() > 2 {
+            head.push(line.trim_end().to_string());
+            line.clear();
+        }
+        let length: usize = head.iter()
+            .find_map(|x| x.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await.unwrap();
+        stream.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+
+        (head, String::from_utf8(body).unwrap())
+    });
+
+    notifier.deliver(&logger).await;
+    assert!(notifier.queue.is_empty());
+    assert_eq!(notifier.backoff.retry_at(), None);
+
+    let (head, body) = received.await.unwrap();
+    assert_eq!(head[0], "POST /alerts HTTP/1.1");
+    assert!(head.contains(&format!("X-Regis-Signature: sha256={}", sign("key", &body).unwrap())));
+    assert_eq!(body, serde_json::to_string(&notification("b", AlertState::Firing)).unwrap());
+
+    // Delivered notifications are not repeated, and the hourly limit counts both delivered & queued ones.
+    notifier.enqueue(&logger, notification("b", AlertState::Firing));
+    assert!(notifier.queue.is_empty());
+    for series in ["c", "d", "e"] {
+        notifier.enqueue(&logger, notification(series, AlertState::Resolved));
+    }
+    assert_eq!(notifier.queue.len(), 2);
+
+    // New settings keep the delivered & queued notifications, so they still count towards the limit.
+    notifier.set_config(NotifierConfig { max_per_hour: 4, ..notifier.config.clone() });
+    assert_eq!((notifier.queue.len(), notifier.recent.len()), (2, 1));
+    for series in ["f", "g"] {
+        notifier.enqueue(&logger, notification(series, AlertState::Resolved));
+    }
+    assert_eq!(notifier.queue.len(), 3);
+
+    assert_eq!(split_url("http://[::1]/x").unwrap(), ("[::1]:80".to_string(), "/x".to_string()));
+    assert_eq!(split_url("http://hooks.local:8080").unwrap(), ("hooks.local:8080".to_string(), "/".to_string()));
+    assert!(split_url("https://hooks.local").is_err());
+}
+
+#[tokio::test]
+async fn test_email_and_exec() {
+    use tokio::net::TcpListener;
+
+    let notification = Notification {
+        host: "web".to_string(),
+        alert: Alert {
+            rule: "high".to_string(),
+            series: "cpu.usage{core=all}".to_string(),
+            severity: AlertSeverity::Warning,
+            state: AlertState::Resolved,
+            value: 12.5,
+            since: Utc::now(),
+            fired: Some(Utc::now()),
+            resolved: Some(Utc::now())
+        }
+    };
+
+    // A minimal SMTP server, which accepts one message and returns the dialogue.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.split();
+        let mut read = BufReader::new(read);
+        write.write_all(b"220 test ready\r\n").await.unwrap();
+
+        let mut dialogue = vec![];
+        let mut in_data = false;
+        let mut line = String::new();
+        while read.read_line(&mut line).await.unwrap() != 0 {
+            let trimmed = line.trim_end().to_string();
+            line.clear();
+
+            let reply: &[u8] = if in_data {
+                if trimmed != "." {
+                    dialogue.push(trimmed);
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            }
+            else if trimmed.starts_with("EHLO") {
+                b"250-test\r\n250 SIZE 10000\r\n"
+            }
+            else if trimmed == "DATA" {
+                in_data = true;
+                b"354 go ahead\r\n"
+            }
+            else if trimmed == "QUIT" {
+                b"221 bye\r\n"
+            }
+            else {
+                b"250 ok\r\n"
+            };
+            dialogue.push(trimmed);
+            write.write_all(reply).await.unwrap();
+        }
+
+        dialogue
+    });
+
+    send_email(&server, "regis@web", &["ops@example.com".to_string()], &notification).await.unwrap();
+    let dialogue = received.await.unwrap();
+    assert_eq!(&dialogue[..4], &["EHLO web", "MAIL FROM:<regis@web>", "RCPT TO:<ops@example.com>", "DATA"]);
+    assert!(dialogue.contains(&format!("Subject: {}", notification.subject())));
+    assert!(dialogue.contains(&"Series: cpu.usage{core=all}".to_string()));
+    assert_eq!(dialogue.last().unwrap(), "QUIT");
+
+    // The program sees the alert in its environment, and a failing program is an error.
+    let dir = super::fixture::TempDir::new("notify");
+    let output = dir.join("output");
+    let args = vec![
+        "-c".to_string(),
+        format!("printf '%s %s %s' \"$REGIS_ALERT_STATE\" \"$REGIS_ALERT_SEVERITY\" \"$REGIS_HOST\" > '{}'", output.display())
+    ];
+    send_exec(std::path::Path::new("/bin/sh"), &args, &notification).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "resolved warning web");
+    assert!(send_exec(std::path::Path::new("/bin/sh"), &["-c".to_string(), "exit 3".to_string()], &notification).await.is_err());
+}
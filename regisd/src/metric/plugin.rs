@@ -0,0 +1,460 @@
+/*
+    External collector plugins
+
+    Plugins are executables placed in the plugin directory. On every metric interval, regisd writes a single line
+    of JSON (a `PluginRequest`) to the plugin's stdin, and the plugin responds on stdout with a single JSON object
+    (a `PluginOutput`), such as:
+
+        {"metrics": {"queue_depth": 12, "license_count": 40}}
+
+    Interval plugins are started for every request, and must exit after responding. Persistent plugins are started
+    once, and must write one line of output for every line of input. A plugin that crashes, times out, or writes
+    invalid output is killed and reported as an error; it is started again on the next interval.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs::read_dir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time::{timeout, timeout_at};
+
+use common::config::{PluginConfig, PluginMode};
+use common::metric::CustomMetric;
+use exdisj::io::log::Logger;
+use exdisj::{log_debug, log_error, log_info, log_warning};
+
+/// The request sent to a plugin on every metric interval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginRequest {
+    pub time: DateTime<Utc>,
+    /// In seconds, how frequently the plugin is asked for values.
+    pub interval: u64
+}
+
+/// The values reported by a plugin.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PluginOutput {
+    pub metrics: BTreeMap<String, f64>
+}
+impl PluginOutput {
+    fn into_metrics(self, plugin: &str) -> Vec<CustomMetric> {
+        self.metrics.into_iter()
+            .filter(|(name, value)| !name.is_empty() && value.is_finite())
+            .map(|(name, value)| CustomMetric { plugin: plugin.to_string(), name, value })
+            .collect()
+    }
+}
+#[test]
+fn test_plugin_output() {
+    let output: PluginOutput = serde_json::from_str(r#"{"metrics": {"queue_depth": 12, "license_count": 40.5, "": 1}}"#).unwrap();
+    let metrics = output.into_metrics("queue");
+
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(metrics[0], CustomMetric { plugin: "queue".to_string(), name: "license_count".to_string(), value: 40.5 });
+    assert_eq!(metrics[1], CustomMetric { plugin: "queue".to_string(), name: "queue_depth".to_string(), value: 12.0 });
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    IO(std::io::Error),
+    Timeout,
+    Exited(ExitStatus),
+    Closed,
+    Format(serde_json::Error),
+    Task(JoinError)
+}
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IO(e) => write!(f, "io error '{e}'"),
+            Self::Timeout => write!(f, "the plugin did not respond in time"),
+            Self::Exited(s) => write!(f, "the plugin exited with {s}"),
+            Self::Closed => write!(f, "the plugin closed its output"),
+            Self::Format(e) => write!(f, "the output is invalid '{e}'"),
+            Self::Task(e) => write!(f, "the plugin task failed '{e}'")
+        }
+    }
+}
+impl From<std::io::Error> for PluginError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+impl From<serde_json::Error> for PluginError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Format(value)
+    }
+}
+
+/// A persistent plugin process, and its pipes.
+struct PluginProcess {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>
+}
+
+/// A specific executable in the plugin directory.
+pub struct Plugin {
+    path: PathBuf,
+    settings: PluginConfig,
+    process: Option<PluginProcess>
+}
+impl Plugin {
+    pub fn new(path: PathBuf, settings: PluginConfig) -> Self {
+        Self {
+            path,
+            settings,
+            process: None
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.path);
+        command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+
+        command
+    }
+
+    /// Starts the plugin, sends the request, and waits for it to exit.
+    async fn run_once(&self, request: &str) -> Result<PluginOutput, PluginError> {
+        let mut child = self.command().spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(request.as_bytes()).await?;
+            // Dropping stdin closes it, so the plugin knows the request is complete.
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err( PluginError::Exited(output.status) );
+        }
+
+        Ok( serde_json::from_slice(&output.stdout)? )
+    }
+
+    /// Sends the request to the persistent process, starting it if needed, and reads one line of output.
+    async fn query_persistent(&mut self, request: &str) -> Result<PluginOutput, PluginError> {
+        if self.process.is_none() {
+            let mut child = self.command().spawn()?;
+            let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+                (Some(i), Some(o)) => (i, o),
+                _ => return Err( PluginError::Closed )
+            };
+
+            self.process = Some(
+                PluginProcess {
+                    _child: child,
+                    stdin,
+                    stdout: BufReader::new(stdout).lines()
+                }
+            );
+        }
+
+        let process = match self.process.as_mut() {
+            Some(v) => v,
+            None => return Err( PluginError::Closed )
+        };
+        process.stdin.write_all(request.as_bytes()).await?;
+        process.stdin.flush().await?;
+
+        match process.stdout.next_line().await? {
+            Some(line) => Ok( serde_json::from_str(&line)? ),
+            None => Err( PluginError::Closed )
+        }
+    }
+
+    /// Asks the plugin for its values, killing it if it does not respond within its timeout.
+    pub async fn collect(&mut self, request: &PluginRequest) -> Result<Vec<CustomMetric>, PluginError> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+
+        let limit = Duration::from_secs(self.settings.timeout);
+        let result = match self.settings.mode {
+            PluginMode::Interval => timeout(limit, self.run_once(&line)).await,
+            PluginMode::Persistent => timeout(limit, self.query_persistent(&line)).await
+        };
+
+        let result = match result {
+            Ok(v) => v,
+            Err(_) => Err( PluginError::Timeout )
+        };
+        if result.is_err() {
+            // Persistent plugins are restarted on the next interval. Dropping the process kills it.
+            self.process = None;
+        }
+
+        result.map(|x| x.into_metrics(&self.settings.name))
+    }
+}
+
+/// Determines if a file or directory can only be changed by root or by the daemon's own user. Plugins are run as the daemon's user, so anything that another user could change would let them run code as it.
+fn check_owner(metadata: &std::fs::Metadata) -> Result<(), String> {
+    // SAFETY: `geteuid` has no preconditions, and cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != 0 && metadata.uid() != uid {
+        return Err( format!("it is owned by user {}, not root", metadata.uid()) );
+    }
+    if metadata.permissions().mode() & 0o022 != 0 {
+        return Err( "it is writable by its group or by others".to_string() );
+    }
+
+    Ok(())
+}
+
+/// Determines if a file is a regular file that can be executed by someone, and that only root (or the daemon's user) can change.
+fn check_plugin(path: &Path) -> Result<(), String> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(v) => v,
+        Err(e) => return Err( format!("it cannot be read ('{e}')") )
+    };
+    if !metadata.is_file() {
+        return Err( "it is not a regular file".to_string() );
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err( "it is not executable".to_string() );
+    }
+
+    check_owner(&metadata)
+}
+
+/// Runs the plugins found in the plugin directory.
+pub struct PluginManager {
+    plugins: Vec<Plugin>
+}
+impl PluginManager {
+    /// Finds every executable in `dir`, and applies the settings from `configs`. Disabled plugins are skipped, as are files (or a directory) that someone other than root could change.
+    pub async fn load(logger: &impl Logger, dir: &Path, configs: &[PluginConfig]) -> Self {
+        let mut plugins = vec![];
+        if let Ok(metadata) = std::fs::metadata(dir) && let Err(reason) = check_owner(&metadata) {
+            log_error!(logger, "The plugin directory '{}' is not used, since {reason}.", dir.display());
+            return Self { plugins };
+        }
+
+        let mut entries = match read_dir(dir).await {
+            Ok(v) => v,
+            Err(e) => {
+                log_debug!(logger, "Unable to read the plugin directory '{}' ('{e}'), no plugins will be run.", dir.display());
+                return Self { plugins };
+            }
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let name = match entry.file_name().to_str() {
+                Some(v) => v.to_string(),
+                None => continue
+            };
+            if let Err(reason) = check_plugin(&path) {
+                log_warning!(logger, "Skipping '{}' in the plugin directory, since {reason}.", path.display());
+                continue;
+            }
+
+            let settings = configs.iter()
+                .find(|x| x.name == name)
+                .cloned()
+                .unwrap_or_else(|| PluginConfig::new(name));
+            if !settings.enabled {
+                log_info!(logger, "Plugin '{}' is disabled.", &settings.name);
+                continue;
+            }
+
+            log_info!(logger, "Loaded plugin '{}' ({:?}, timeout {}s).", &settings.name, settings.mode, settings.timeout);
+            plugins.push(Plugin::new(path, settings));
+        }
+
+        plugins.sort_by(|a, b| a.name().cmp(b.name()));
+        Self { plugins }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Runs every plugin at once, and gathers their values. The plugins that fail are skipped, and are returned alongside the values.
+    pub async fn collect(&mut self, interval: u64) -> (Vec<CustomMetric>, Vec<(String, PluginError)>) {
+        let request = PluginRequest {
+            time: Utc::now(),
+            interval
+        };
+
+        let mut tasks = JoinSet::new();
+        let mut names = HashMap::new();
+        for mut plugin in self.plugins.drain(..) {
+            let request = request.clone();
+            let name = plugin.name().to_string();
+            let handle = tasks.spawn(async move {
+                let result = plugin.collect(&request).await;
+                (plugin, result)
+            });
+            names.insert(handle.id(), name);
+        }
+
+        let mut result = vec![];
+        let mut failed = vec![];
+        while let Some(joined) = tasks.join_next().await {
+            let (plugin, values) = match joined {
+                Ok(v) => v,
+                Err(e) => {
+                    // The plugin is dropped, and will be loaded again on the next configuration reload.
+                    failed.push( (names.remove(&e.id()).unwrap_or_default(), PluginError::Task(e)) );
+                    continue;
+                }
+            };
+
+            match values {
+                Ok(v) => result.extend(v),
+                Err(e) => failed.push( (plugin.name().to_string(), e) )
+            }
+            self.plugins.push(plugin);
+        }
+
+        self.plugins.sort_by(|a, b| a.name().cmp(b.name()));
+        result.sort_by(|a, b| a.plugin.cmp(&b.plugin).then(a.name.cmp(&b.name)));
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        (result, failed)
+    }
+}
+
+/// How a run of the plugins ended.
+pub enum PluginRun {
+    /// Every plugin responded or failed before the deadline. The plugins that failed are listed alongside the values.
+    Finished(Vec<CustomMetric>, Vec<(String, PluginError)>),
+    /// The plugins did not finish before the deadline, or were still running from a previous interval.
+    TimedOut,
+    /// The task running the plugins failed. The plugins are lost until the next configuration reload.
+    Failed(JoinError)
+}
+
+/// Runs the plugins on a task of their own, so that a slow plugin cannot hold up the snapshot. Like the other families, the plugins are not run again until their previous run finishes.
+pub struct PluginRunner {
+    idle: Option<PluginManager>,
+    running: Option<JoinHandle<(PluginManager, (Vec<CustomMetric>, Vec<(String, PluginError)>))>>
+}
+impl PluginRunner {
+    pub fn new(manager: PluginManager) -> Self {
+        Self {
+            idle: Some(manager),
+            running: None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_none() && self.idle.as_ref().is_none_or(|x| x.is_empty())
+    }
+
+    /// Starts running the plugins. This returns false, without starting them, if their previous run has not finished.
+    pub async fn start(&mut self, interval: u64) -> bool {
+        if let Some(handle) = self.running.take() {
+            if !handle.is_finished() {
+                self.running = Some(handle);
+                return false;
+            }
+
+            // The previous run finished after its deadline, so its values are stale and discarded.
+            if let Ok((manager, _)) = handle.await {
+                self.idle = Some(manager);
+            }
+        }
+
+        if let Some(mut manager) = self.idle.take() {
+            self.running = Some(
+                tokio::spawn(async move {
+                    let result = manager.collect(interval).await;
+                    (manager, result)
+                })
+            );
+        }
+        true
+    }
+
+    /// Waits until `deadline` at most for the run started by `start` to finish. If it does not, it is left running, and its values are discarded.
+    pub async fn finish(&mut self, deadline: Instant) -> PluginRun {
+        let handle = match self.running.as_mut() {
+            Some(v) => v,
+            None => return PluginRun::Finished(vec![], vec![])
+        };
+
+        match timeout_at(deadline.into(), handle).await {
+            Ok(Ok((manager, (values, failed)))) => {
+                self.running = None;
+                self.idle = Some(manager);
+                PluginRun::Finished(values, failed)
+            },
+            Ok(Err(e)) => {
+                self.running = None;
+                PluginRun::Failed(e)
+            },
+            Err(_) => PluginRun::TimedOut
+        }
+    }
+}
+impl Drop for PluginRunner {
+    fn drop(&mut self) {
+        // Aborting the run drops its plugins, which kills their processes.
+        if let Some(handle) = self.running.take() {
+            handle.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_plugin_modes() {
+    use std::fs::{write, set_permissions, Permissions};
+
+    let dir = super::fixture::TempDir::new("plugin");
+
+    let scripts = [
+        ("once", "#!/bin/sh\nread line\necho '{\"metrics\": {\"depth\": 3}}'\n"),
+        ("forever", "#!/bin/sh\nn=0\nwhile read line; do n=$((n+1)); echo \"{\\\"metrics\\\": {\\\"count\\\": $n}}\"; done\n"),
+        ("slow", "#!/bin/sh\nsleep 5\n"),
+        ("broken", "#!/bin/sh\necho 'not json'\n"),
+        ("disabled", "#!/bin/sh\necho '{\"metrics\": {}}'\n")
+    ];
+    for (name, contents) in scripts {
+        write(dir.join(name), contents).unwrap();
+        set_permissions(dir.join(name), Permissions::from_mode(0o755)).unwrap();
+    }
+    write(dir.join("notes.txt"), "not a plugin").unwrap();
+    // Anyone in its group could change this one, so it is not run.
+    write(dir.join("shared"), scripts[0].1).unwrap();
+    set_permissions(dir.join("shared"), Permissions::from_mode(0o775)).unwrap();
+    set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+
+    let configs = [
+        PluginConfig { mode: PluginMode::Persistent, ..PluginConfig::new("forever".to_string()) },
+        PluginConfig { timeout: 1, ..PluginConfig::new("slow".to_string()) },
+        PluginConfig { enabled: false, ..PluginConfig::new("disabled".to_string()) }
+    ];
+
+    let logger = exdisj::io::log::NullLogger;
+    let mut manager = PluginManager::load(&logger, &dir, &configs).await;
+    assert_eq!(manager.plugins.len(), 4);
+
+    let (first, failed) = manager.collect(3).await;
+    let failed: Vec<&str> = failed.iter().map(|x| x.0.as_str()).collect();
+    assert_eq!(failed, vec!["broken", "slow"]);
+    assert_eq!(first, vec![
+        CustomMetric { plugin: "forever".to_string(), name: "count".to_string(), value: 1.0 },
+        CustomMetric { plugin: "once".to_string(), name: "depth".to_string(), value: 3.0 }
+    ]);
+
+    // The persistent plugin keeps its state between intervals.
+    let (second, _) = manager.collect(3).await;
+    assert_eq!(second[0].value, 2.0);
+    assert_eq!(manager.plugins.len(), 4);
+}
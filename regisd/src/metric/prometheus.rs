@@ -0,0 +1,255 @@
+/*
+    Prometheus exposition
+
+    Converts a snapshot into the Prometheus text format (version 0.0.4). Every metric is prefixed with `regis_`,
+    and the devices, mounts, interfaces, etc. are given as labels.
+*/
+
+use std::fmt::Write;
+
+use common::metric::{CollectedMetrics, CollectorState, CpuUsage, MetricFamily, SensorKind};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Escapes a label value, as required by the text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    }
+    else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    }
+    else {
+        value.to_string()
+    }
+}
+
+/// Collects the samples of each metric, so that they are written under a single HELP & TYPE header.
+#[derive(Default)]
+struct Exposition {
+    out: String
+}
+impl Exposition {
+    fn family(&mut self, name: &str, help: &str, kind: &str, samples: Vec<(Labels, f64)>) {
+        if samples.is_empty() {
+            return;
+        }
+
+        // Writing into a String cannot fail.
+        let _ = writeln!(self.out, "# HELP regis_{name} {help}");
+        let _ = writeln!(self.out, "# TYPE regis_{name} {kind}");
+        for (labels, value) in samples {
+            let labels: Vec<String> = labels.into_iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", escape(&value)))
+                .collect();
+
+            if labels.is_empty() {
+                let _ = writeln!(self.out, "regis_{name} {}", format_value(value));
+            }
+            else {
+                let _ = writeln!(self.out, "regis_{name}{{{}}} {}", labels.join(","), format_value(value));
+            }
+        }
+    }
+    fn gauge(&mut self, name: &str, help: &str, samples: Vec<(Labels, f64)>) {
+        self.family(name, help, "gauge", samples)
+    }
+    fn counter(&mut self, name: &str, help: &str, samples: Vec<(Labels, f64)>) {
+        self.family(name, help, "counter", samples)
+    }
+}
+
+/// The family name as a label value, such as `disk_io`.
+fn family_label(family: MetricFamily) -> String {
+    family.to_string().replace(' ', "_")
+}
+
+fn cpu_modes(usage: &CpuUsage) -> [(&'static str, f64); 8] {
+    [
+        ("user", usage.user),
+        ("system", usage.system),
+        ("nice", usage.nice),
+        ("idle", usage.idle),
+        ("iowait", usage.iowait),
+        ("irq", usage.irq),
+        ("softirq", usage.softirq),
+        ("steal", usage.steal)
+    ]
+}
+
+/// Renders a snapshot into the Prometheus text format.
+pub fn render(metrics: &CollectedMetrics) -> String {
+    let mut exp = Exposition::default();
+
+    exp.gauge("snapshot_timestamp_seconds", "When the snapshot was recorded, in seconds since the epoch.", vec![(vec![], metrics.time.timestamp_millis() as f64 / 1000.0)]);
+
+    if let Some(cpu) = metrics.cpu.as_ref() {
+        let mut samples = vec![];
+        for (core, usage) in std::iter::once(("all".to_string(), &cpu.total)).chain(cpu.cores.iter().map(|x| (x.id.to_string(), &x.usage))) {
+            for (mode, value) in cpu_modes(usage) {
+                samples.push( (vec![("core", core.clone()), ("mode", mode.to_string())], value) );
+            }
+        }
+        exp.gauge("cpu_usage_percent", "The percentage of CPU time spent in each mode.", samples);
+
+        if let Some(load) = cpu.load.as_ref() {
+            exp.gauge(
+                "load_average",
+                "The system load average.",
+                vec![
+                    (vec![("period", "1m".to_string())], load.one),
+                    (vec![("period", "5m".to_string())], load.five),
+                    (vec![("period", "15m".to_string())], load.fifteen)
+                ]
+            );
+        }
+    }
+
+    let memory = |f: fn(&common::metric::MemoryMetric) -> f64| -> Vec<(Labels, f64)> {
+        metrics.memory.iter().map(|x| (vec![("device", x.device.clone())], f(x))).collect()
+    };
+    exp.gauge("memory_total_bytes", "The total size of the memory section.", memory(|x| x.total.as_f64()));
+    exp.gauge("memory_free_bytes", "The unused memory of the section.", memory(|x| x.free.as_f64()));
+    exp.gauge("memory_available_bytes", "The memory available for new allocations.", memory(|x| x.available.as_f64()));
+    exp.gauge("memory_buffers_bytes", "The memory used for buffers.", memory(|x| x.buff.as_f64()));
+    exp.gauge("memory_cached_bytes", "The memory used for caches.", memory(|x| x.cached.as_f64()));
+
+    let storage = |f: fn(&common::metric::StorageMetric) -> f64| -> Vec<(Labels, f64)> {
+        metrics.storage.iter().map(|x| (vec![("device", x.system.clone()), ("mount", x.mount.clone())], f(x))).collect()
+    };
+    exp.gauge("storage_size_bytes", "The total size of the filesystem.", storage(|x| x.size.as_f64()));
+    exp.gauge("storage_used_bytes", "The used space of the filesystem.", storage(|x| x.used.as_f64()));
+    exp.gauge("storage_available_bytes", "The space available to non-root users.", storage(|x| x.availiable.as_f64()));
+
+    let disks = |f: fn(&common::metric::DiskIoMetric) -> f64| -> Vec<(Labels, f64)> {
+        metrics.disk_io.iter().map(|x| (vec![("device", x.device.clone())], f(x))).collect()
+    };
+    exp.gauge("disk_reads_per_second", "Completed reads per second.", disks(|x| x.read_iops));
+    exp.gauge("disk_writes_per_second", "Completed writes per second.", disks(|x| x.write_iops));
+    exp.gauge("disk_read_bytes_per_second", "Bytes read per second.", disks(|x| x.read_rate.as_f64()));
+    exp.gauge("disk_write_bytes_per_second", "Bytes written per second.", disks(|x| x.write_rate.as_f64()));
+    exp.gauge("disk_read_await_milliseconds", "The average time a read took, including queueing.", disks(|x| x.read_await));
+    exp.gauge("disk_write_await_milliseconds", "The average time a write took, including queueing.", disks(|x| x.write_await));
+    exp.gauge("disk_utilization_percent", "The percentage of time the device had IO in progress.", disks(|x| x.utilization));
+
+    let mut net_bytes = vec![];
+    let mut net_packets = vec![];
+    let mut net_errors = vec![];
+    let mut net_drops = vec![];
+    let mut net_rates = vec![];
+    for link in &metrics.network {
+        for (direction, section, rate) in [("receive", &link.rx, link.rx_rate.as_ref()), ("transmit", &link.tx, link.tx_rate.as_ref())] {
+            let labels: Labels = vec![("interface", link.name.clone()), ("direction", direction.to_string())];
+            net_bytes.push( (labels.clone(), section.bytes as f64) );
+            net_packets.push( (labels.clone(), section.ok as f64) );
+            net_errors.push( (labels.clone(), section.err as f64) );
+            net_drops.push( (labels.clone(), section.drop as f64) );
+            if let Some(rate) = rate {
+                net_rates.push( (labels, rate.bytes.as_f64()) );
+            }
+        }
+    }
+    exp.counter("network_bytes_total", "The bytes transferred through the interface.", net_bytes);
+    exp.counter("network_packets_total", "The packets transferred through the interface.", net_packets);
+    exp.counter("network_errors_total", "The errors on the interface.", net_errors);
+    exp.counter("network_drops_total", "The packets dropped by the interface.", net_drops);
+    exp.gauge("network_bytes_per_second", "The bytes transferred per second, since the previous sample.", net_rates);
+
+    for (kind, name, help) in [
+        (SensorKind::Temperature, "temperature_celsius", "The reading of a temperature sensor."),
+        (SensorKind::Fan, "fan_rpm", "The reading of a fan sensor."),
+        (SensorKind::Voltage, "voltage_volts", "The reading of a voltage sensor.")
+    ] {
+        let samples = metrics.sensors.iter()
+            .filter(|x| x.kind == kind)
+            .map(|x| (vec![("chip", x.chip.clone()), ("device", x.device.clone()), ("sensor", x.label.clone())], x.value))
+            .collect();
+        exp.gauge(name, help, samples);
+    }
+
+    let cgroups = |f: fn(&common::metric::CgroupMetric) -> Option<f64>| -> Vec<(Labels, f64)> {
+        metrics.cgroups.iter().filter_map(|x| Some((vec![("cgroup", x.path.clone())], f(x)?))).collect()
+    };
+    exp.gauge("cgroup_cpu_usage_percent", "The CPU usage of the cgroup, as a percentage of one core.", cgroups(|x| Some(x.cpu_usage)));
+    exp.gauge("cgroup_memory_bytes", "The memory currently used by the cgroup.", cgroups(|x| Some(x.memory.as_f64())));
+    exp.gauge("cgroup_memory_max_bytes", "The memory limit of the cgroup.", cgroups(|x| x.memory_max.map(|x| x.as_f64())));
+    exp.gauge("cgroup_read_bytes_per_second", "Bytes read per second by the cgroup.", cgroups(|x| Some(x.read_rate.as_f64())));
+    exp.gauge("cgroup_write_bytes_per_second", "Bytes written per second by the cgroup.", cgroups(|x| Some(x.write_rate.as_f64())));
+    exp.gauge("cgroup_pids", "The number of processes in the cgroup.", cgroups(|x| Some(x.pids as f64)));
+
+    let mut stall = vec![];
+    let mut stall_total = vec![];
+    for metric in &metrics.pressure {
+        let resource = metric.resource.to_string().to_lowercase();
+        for (kind, values) in [("some", Some(&metric.some)), ("full", metric.full.as_ref())] {
+            let values = match values {
+                Some(v) => v,
+                None => continue
+            };
+
+            for (window, value) in [("10s", values.avg10), ("60s", values.avg60), ("300s", values.avg300)] {
+                stall.push( (vec![("resource", resource.clone()), ("kind", kind.to_string()), ("window", window.to_string())], value) );
+            }
+            stall_total.push( (vec![("resource", resource.clone()), ("kind", kind.to_string())], values.total as f64 / 1_000_000.0) );
+        }
+    }
+    exp.gauge("pressure_stall_percent", "The percentage of time tasks were stalled on the resource.", stall);
+    exp.counter("pressure_stall_seconds_total", "The total time tasks were stalled on the resource.", stall_total);
+
+    exp.gauge(
+        "custom",
+        "A value reported by a collector plugin.",
+        metrics.custom.iter().map(|x| (vec![("plugin", x.plugin.clone()), ("series", x.name.clone())], x.value)).collect()
+    );
+
+    exp.gauge(
+        "collector_up",
+        "Whether the most recent collection of the family succeeded.",
+        metrics.collectors.iter().map(|x| (vec![("family", family_label(x.family))], if x.state == CollectorState::Ok { 1.0 } else { 0.0 })).collect()
+    );
+    exp.gauge(
+        "collector_duration_seconds",
+        "How long the most recent collection of the family took.",
+        metrics.collectors.iter().map(|x| (vec![("family", family_label(x.family))], x.duration / 1000.0)).collect()
+    );
+
+    exp.out
+}
+
+#[test]
+fn test_render_exposition() {
+    use common::metric::{ByteCount, MemoryMetric, NetworkMetric, NetworkMetricSection, NetworkRate};
+
+    let metrics = CollectedMetrics {
+        memory: vec![
+            MemoryMetric { device: "Mem".to_string(), total: ByteCount(1024), ..Default::default() }
+        ],
+        network: vec![
+            NetworkMetric {
+                name: "eth\"0".to_string(),
+                mtu: "1500".to_string(),
+                rx: NetworkMetricSection { bytes: 100, ok: 1, err: 0, drop: 0, overrun: 0 },
+                tx: NetworkMetricSection { bytes: 200, ok: 2, err: 0, drop: 0, overrun: 0 },
+                rx_rate: Some(NetworkRate { bytes: ByteCount(10), packets: 1.0 }),
+                tx_rate: None
+            }
+        ],
+        ..Default::default()
+    };
+
+    let output = render(&metrics);
+    assert!(output.contains("# HELP regis_memory_total_bytes The total size of the memory section.\n# TYPE regis_memory_total_bytes gauge\nregis_memory_total_bytes{device=\"Mem\"} 1024\n"));
+    assert!(output.contains("# TYPE regis_network_bytes_total counter\nregis_network_bytes_total{interface=\"eth\\\"0\",direction=\"receive\"} 100\nregis_network_bytes_total{interface=\"eth\\\"0\",direction=\"transmit\"} 200\n"));
+    assert!(output.contains("regis_network_bytes_per_second{interface=\"eth\\\"0\",direction=\"receive\"} 10\n"));
+    // Families without samples are left out entirely.
+    assert!(!output.contains("regis_cpu_usage_percent"));
+    assert!(!output.contains("regis_collector_up"));
+}
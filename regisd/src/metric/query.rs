@@ -0,0 +1,254 @@
+/*
+    Metric queries
+
+    A query covers a time range, and is answered from the stored resolution that best fits the requested density:
+    raw snapshots (from memory & the history), or the minute or hour rollups. A coarser resolution is used when the
+    chosen one is no longer kept as far back as the start of the range. Raw snapshots are sent whole when no
+    fields were requested and no downsampling is needed. Otherwise, every point is a summary of the requested series
+    over one step. The newest rollup period is not finished yet, so it is summarized from the raw snapshots instead.
+*/
+
+use chrono::{DateTime, Duration, Utc};
+
+use common::config::DaemonConfig;
+use common::metric::{MetricFamily, Resolution, RollupPoint};
+use common::msg::{MetricsData, MetricsQuery, MetricsResponse, QueryDensity};
+use exdisj::io::lock::OptionRwProvider;
+
+use super::collect::CollectedMetrics;
+use super::history::{read_range, snapshots_between};
+use super::io::METRICS_HOLDING;
+use super::rollup::{period_start, rollup_dir};
+use super::series::{series, series_family};
+use crate::config::CONFIG;
+
+/// Determines if a series, keyed as `measurement.field{tag=value,...}`, was requested by the query.
+fn includes_series(query: &MetricsQuery, key: &str) -> bool {
+    let name = key.split('{').next().unwrap_or(key);
+    let measurement = name.split('.').next().unwrap_or(name);
+
+    let family = match series_family(key) {
+        Some(v) => v,
+        None => return false
+    };
+
+    query.includes_family(family) && (query.fields.is_empty() || query.fields.iter().any(|x| x == name || x == measurement))
+}
+
+/// In seconds, how long the points of a resolution are kept. This is `None` if they are not removed by age.
+fn retention(config: &DaemonConfig, resolution: Resolution) -> Option<u64> {
+    match resolution {
+        Resolution::Raw if config.history.enabled => config.history.max_age,
+        // Without the history, only the snapshots in memory are kept.
+        Resolution::Raw => Some(METRICS_HOLDING as u64 * Resolution::Raw.period(config.metric_freq)),
+        _ => config.rollups.retention(resolution)
+    }
+}
+
+/// Chooses the stored resolution to read, and how far apart the resulting points are (in seconds). The resolution is the finest that fits the density and is still kept as far back as the start of the query, as of `now`.
+fn plan(query: &MetricsQuery, config: &DaemonConfig, now: DateTime<Utc>) -> (Resolution, u64) {
+    let metric_freq = config.metric_freq;
+    let raw_period = Resolution::Raw.period(metric_freq);
+    if !config.rollups.enabled {
+        let step = match query.density {
+            QueryDensity::MaxPoints(n) => query.span().div_ceil(n.max(1) as u64),
+            QueryDensity::Step(s) => s
+        };
+
+        return (Resolution::Raw, step.max(raw_period));
+    }
+
+    let finest = match query.density {
+        QueryDensity::MaxPoints(n) => Resolution::for_span(query.span(), n, metric_freq),
+        // The coarsest resolution that is still fine enough for the step.
+        QueryDensity::Step(s) => [Resolution::Hour, Resolution::Minute]
+            .into_iter()
+            .find(|x| x.period(metric_freq) <= s)
+            .unwrap_or(Resolution::Raw)
+    };
+
+    let age = (now - query.from).num_seconds().max(0) as u64;
+    let resolution = [Resolution::Raw, Resolution::Minute, Resolution::Hour]
+        .into_iter()
+        .filter(|x| *x >= finest)
+        .find(|x| retention(config, *x).is_none_or(|x| age <= x))
+        .unwrap_or(Resolution::Hour);
+
+    let step = match query.density {
+        QueryDensity::MaxPoints(n) => query.span().div_ceil(n.max(1) as u64),
+        QueryDensity::Step(s) => s
+    };
+    (resolution, step.max(resolution.period(metric_freq)))
+}
+
+/// Summarizes the requested series of a snapshot as a point covering `period` seconds.
+fn snapshot_point(query: &MetricsQuery, metrics: &CollectedMetrics, period: u64) -> RollupPoint {
+    let mut result = RollupPoint::new(metrics.time, period);
+    for (key, value) in series(metrics) {
+        if includes_series(query, &key) {
+            result.add(&key, value);
+        }
+    }
+
+    result
+}
+
+/// Merges points (oldest first) into one point per `step` seconds, aligned to the epoch.
+fn downsample(points: Vec<RollupPoint>, step: u64) -> Vec<RollupPoint> {
+    let mut result: Vec<RollupPoint> = vec![];
+    for point in points {
+        let start = period_start(point.time, step);
+        match result.last_mut() {
+            Some(last) if last.time == start => last.merge(&point),
+            _ => {
+                let mut bucket = RollupPoint::new(start, step);
+                bucket.merge(&point);
+                result.push(bucket);
+            }
+        }
+    }
+
+    result
+}
+
+/// Reads the rollup points of `resolution` that overlap the query, followed by the unfinished periods summarized from raw snapshots.
+async fn rollup_points(query: &MetricsQuery, config: &DaemonConfig, resolution: Resolution) -> Option<Vec<RollupPoint>> {
+    let period = resolution.period(config.metric_freq);
+    let mut result: Vec<RollupPoint> = read_range(&rollup_dir(&config.history.dir, resolution), period_start(query.from, period), query.to)
+        .await
+        .unwrap_or_default();
+    for point in &mut result {
+        point.series.retain(|key, _| includes_series(query, key));
+    }
+
+    let covered = match result.last() {
+        Some(v) => v.time + Duration::seconds(period as i64),
+        None => query.from
+    };
+    if covered <= query.to {
+        let raw = snapshots_between(covered.max(query.from), query.to).await?;
+        let points = raw.iter()
+            .map(|x| snapshot_point(query, x, Resolution::Raw.period(config.metric_freq)))
+            .collect();
+        result.extend( downsample(points, period) );
+    }
+
+    Some(result)
+}
+
+/// Answers a query from the stored metrics. This is `None` if the configuration or the in-memory metrics could not be accessed.
+pub async fn query_metrics(query: &MetricsQuery) -> Option<MetricsResponse> {
+    let config = CONFIG.access()
+        .access()
+        .cloned()?;
+    if query.from > query.to {
+        return Some(MetricsResponse::empty());
+    }
+
+    let (resolution, step) = plan(query, &config, Utc::now());
+    let raw_period = Resolution::Raw.period(config.metric_freq);
+
+    let points = if resolution == Resolution::Raw {
+        let mut snapshots = snapshots_between(query.from, query.to).await?;
+        if step <= raw_period && query.fields.is_empty() {
+            if let QueryDensity::MaxPoints(n) = query.density {
+                snapshots.drain(..snapshots.len().saturating_sub(n.max(1)));
+            }
+
+            let families: Vec<MetricFamily> = MetricFamily::ALL.into_iter()
+                .filter(|x| query.includes_family(*x))
+                .collect();
+            for snapshot in &mut snapshots {
+                snapshot.retain_families(&families);
+            }
+
+            return Some(
+                MetricsResponse {
+                    resolution,
+                    step,
+                    info: MetricsData::Snapshots(snapshots)
+                }
+            );
+        }
+
+        snapshots.iter()
+            .map(|x| snapshot_point(query, x, raw_period))
+            .collect()
+    }
+    else {
+        rollup_points(query, &config, resolution).await?
+    };
+
+    let points = if step > resolution.period(config.metric_freq) {
+        downsample(points, step)
+    }
+    else {
+        points
+    };
+
+    Some(
+        MetricsResponse {
+            resolution,
+            step,
+            info: MetricsData::Points(points)
+        }
+    )
+}
+
+#[test]
+fn test_query_planning() {
+    use chrono::TimeZone;
+
+    let at = |secs: i64| Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap();
+    let mut query = MetricsQuery {
+        from: at(0),
+        to: at(3600),
+        density: QueryDensity::MaxPoints(100),
+        families: vec![MetricFamily::Plugins],
+        fields: vec![]
+    };
+    let mut config = DaemonConfig {
+        metric_freq: 3,
+        ..Default::default()
+    };
+    let now = at(3600);
+
+    // An hour in 100 points is too dense for the minute rollups, but the raw snapshots are downsampled.
+    assert_eq!(plan(&query, &config, now), (Resolution::Minute, 60));
+    query.density = QueryDensity::Step(600);
+    assert_eq!(plan(&query, &config, now), (Resolution::Minute, 600));
+    query.density = QueryDensity::Step(1);
+    assert_eq!(plan(&query, &config, now), (Resolution::Raw, 3));
+    config.rollups.enabled = false;
+    query.density = QueryDensity::MaxPoints(100);
+    assert_eq!(plan(&query, &config, now), (Resolution::Raw, 36));
+    config.rollups.enabled = true;
+
+    // The minute rollups of two weeks ago have been removed, so the hour rollups are read instead.
+    let day = 24 * 60 * 60;
+    let old = MetricsQuery { from: at(0), to: at(day), density: QueryDensity::MaxPoints(2000), ..query.clone() };
+    assert_eq!(plan(&old, &config, at(day)).0, Resolution::Minute);
+    assert_eq!(plan(&old, &config, at(14 * day)), (Resolution::Hour, 3600));
+
+    assert!(includes_series(&query, "custom.value{plugin=p,series=v}"));
+    assert!(!includes_series(&query, "cpu.user{core=all}"));
+    query.families.clear();
+    query.fields = vec!["cpu.user".to_string(), "load".to_string()];
+    assert!(includes_series(&query, "cpu.user{core=all}"));
+    assert!(includes_series(&query, "load.one"));
+    assert!(!includes_series(&query, "cpu.idle{core=all}"));
+
+    let mut first = RollupPoint::new(at(0), 60);
+    first.add("a", 1.0);
+    first.add("a", 3.0);
+    let mut second = RollupPoint::new(at(60), 60);
+    second.add("a", 5.0);
+    let mut third = RollupPoint::new(at(600), 60);
+    third.add("b", 2.0);
+
+    let merged = downsample(vec![first, second, third], 300);
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged[0].time, period_start(at(0), 300));
+    assert_eq!((merged[0].series["a"].avg, merged[0].series["a"].last, merged[0].series["a"].count), (3.0, 5.0, 3));
+    assert_eq!(merged[1].series["b"].max, 2.0);
+}
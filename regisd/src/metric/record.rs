@@ -0,0 +1,267 @@
+/*
+    Snapshot recording
+
+    Once a snapshot is taken, it is handed to the recorder task. This updates the rollups, forecasts, alerts and
+    anomaly baselines, and moves the snapshots evicted from memory to the on-disk history. All of these write to
+    disk, so they are kept off the metrics task, and a slow disk does not hold up collection.
+*/
+
+use std::path::PathBuf;
+
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+use common::alert::{Alert, AlertState};
+use common::config::DaemonConfig;
+use common::loc::{DAEMON_ALERTS_PATH, DAEMON_ANOMALIES_PATH};
+use common::metric::CollectedMetrics;
+use exdisj::io::log::{ConstructableLogger, Logger};
+use exdisj::{log_debug, log_error, log_info, log_warning};
+
+use super::alert::AlertEngine;
+use super::anomaly::AnomalyDetector;
+use super::forecast::ForecastEngine;
+use super::history::HistoryStore;
+use super::io::{METRICS, METRICS_HOLDING};
+use super::notify::NotifyManager;
+use super::rollup::RollupManager;
+
+/// How many messages may wait for the recorder before new snapshots are dropped.
+const CHANNEL_SIZE: usize = 64;
+
+/// Opens the on-disk history, if it is enabled. If it cannot be opened, evicted snapshots are discarded.
+async fn open_history(logger: &impl Logger, config: &DaemonConfig) -> Option<HistoryStore> {
+    if !config.history.enabled {
+        log_info!(logger, "The metric history is disabled, evicted snapshots will be discarded.");
+        return None;
+    }
+
+    match HistoryStore::open(config.history.clone()).await {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log_error!(logger, "Unable to open the metric history at '{}' ('{e}'), evicted snapshots will be discarded.", config.history.dir.display());
+            None
+        }
+    }
+}
+
+/// Opens the rollups, if they are enabled. If they cannot be opened, no rollups are recorded.
+async fn open_rollups(logger: &impl Logger, config: &DaemonConfig) -> Option<RollupManager> {
+    if !config.rollups.enabled {
+        return None;
+    }
+
+    match RollupManager::open(config).await {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log_error!(logger, "Unable to open the metric rollups ('{e}'), no rollups will be recorded.");
+            None
+        }
+    }
+}
+
+/// Saves the unfinished rollup periods.
+async fn close_rollups(logger: &impl Logger, rollups: Option<RollupManager>) {
+    if let Some(rollups) = rollups && let Err(e) = rollups.close().await {
+        log_warning!(logger, "Unable to save the unfinished rollups '{e}'.");
+    }
+}
+
+/// Opens the alert engine, with the alerts saved by the previous run. If they cannot be read, no alerts are evaluated.
+async fn open_alerts(logger: &impl Logger, config: &DaemonConfig) -> Option<AlertEngine> {
+    match AlertEngine::open(PathBuf::from(DAEMON_ALERTS_PATH), config.alerts.clone()).await {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log_error!(logger, "Unable to read the saved alerts ('{e}'), no alerts will be evaluated.");
+            None
+        }
+    }
+}
+
+/// Publishes the capacity forecasts once they have been made, and starts making them again when they are due.
+async fn update_forecasts(logger: &impl Logger, forecasts: &mut ForecastEngine, config: &DaemonConfig) {
+    match forecasts.finish().await {
+        Some(Ok(n)) => log_debug!(logger, "Forecast the capacity of {n} series."),
+        Some(Err(e)) => log_warning!(logger, "Unable to read the rollups for the capacity forecasts '{e}'."),
+        None => ()
+    }
+
+    if forecasts.is_due() {
+        forecasts.start(config);
+    }
+}
+
+/// Checks a snapshot (and the series derived from the history) against the alert rules, and saves the alerts if any changed. This returns the alerts that changed.
+async fn evaluate_alerts(logger: &impl Logger, alerts: &mut AlertEngine, snapshot: &CollectedMetrics, derived: &[(String, f64)]) -> Vec<Alert> {
+    let changed = alerts.evaluate(snapshot, derived);
+    for alert in &changed {
+        if alert.state == AlertState::Firing {
+            log_warning!(logger, "Alert {alert}");
+        }
+        else {
+            log_info!(logger, "Alert {alert}");
+        }
+    }
+
+    if !changed.is_empty() && let Err(e) = alerts.save().await {
+        log_warning!(logger, "Unable to save the alerts '{e}'.");
+    }
+
+    changed
+}
+
+/// Opens the anomaly detector, if it is enabled, with the baselines learned by the previous run.
+async fn open_anomalies(logger: &impl Logger, config: &DaemonConfig) -> Option<AnomalyDetector> {
+    if !config.anomalies.enabled {
+        return None;
+    }
+
+    match AnomalyDetector::open(PathBuf::from(DAEMON_ANOMALIES_PATH), config.anomalies.clone()).await {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log_error!(logger, "Unable to read the saved baselines ('{e}'), no anomalies will be detected.");
+            None
+        }
+    }
+}
+
+/// Saves the baselines & recent anomalies.
+async fn save_anomalies(logger: &impl Logger, anomalies: &mut AnomalyDetector) {
+    if let Err(e) = anomalies.save().await {
+        log_warning!(logger, "Unable to save the anomaly baselines '{e}'.");
+    }
+}
+
+/// Compares a snapshot to the baselines of its series, and saves the baselines every so often.
+async fn detect_anomalies(logger: &impl Logger, anomalies: &mut AnomalyDetector, snapshot: &CollectedMetrics) {
+    for anomaly in anomalies.evaluate(snapshot) {
+        log_info!(logger, "Anomaly {anomaly}");
+    }
+
+    if anomalies.is_save_due() {
+        save_anomalies(logger, anomalies).await;
+    }
+}
+
+/// A message from the metrics task to the recorder.
+enum RecordMessage {
+    /// A new snapshot, along with the snapshot it evicted from memory, if any.
+    Snapshot(CollectedMetrics, Option<CollectedMetrics>),
+    /// The configuration was reloaded.
+    Reload(Box<DaemonConfig>)
+}
+
+async fn record_worker<L>(logger: L, mut config: DaemonConfig, mut recv: Receiver<RecordMessage>)
+where L: ConstructableLogger + 'static {
+    let mut history = open_history(&logger, &config).await;
+    let mut rollups = open_rollups(&logger, &config).await;
+    let mut alerts = open_alerts(&logger, &config).await;
+    let mut notifiers = NotifyManager::start(&logger, &config.notifiers).await;
+    let mut anomalies = open_anomalies(&logger, &config).await;
+    let mut forecasts = ForecastEngine::new(config.forecast.clone());
+
+    while let Some(message) = recv.recv().await {
+        match message {
+            RecordMessage::Snapshot(snapshot, evicted) => {
+                if let Some(rollups) = rollups.as_mut() && let Err(e) = rollups.insert(&snapshot).await {
+                    log_warning!(&logger, "Unable to update the metric rollups '{e}'.");
+                }
+                update_forecasts(&logger, &mut forecasts, &config).await;
+                if let Some(alerts) = alerts.as_mut() {
+                    let changed = evaluate_alerts(&logger, alerts, &snapshot, forecasts.derived()).await;
+                    notifiers.notify(&logger, &changed);
+                }
+                if let Some(anomalies) = anomalies.as_mut() {
+                    detect_anomalies(&logger, anomalies, &snapshot).await;
+                }
+                if let Some(evicted) = evicted && let Some(history) = history.as_mut() && let Err(e) = history.append(&evicted).await {
+                    log_warning!(&logger, "Unable to append to the metric history '{e}'.");
+                }
+            },
+            RecordMessage::Reload(new_config) => {
+                config = *new_config;
+                if history.as_ref().map(|x| x.config()) != Some(&config.history) {
+                    history = open_history(&logger, &config).await;
+                }
+                if !rollups.as_ref().is_some_and(|x| x.matches(&config)) {
+                    close_rollups(&logger, rollups.take()).await;
+                    rollups = open_rollups(&logger, &config).await;
+                }
+                match alerts.as_mut() {
+                    Some(alerts) => alerts.set_rules(config.alerts.clone()),
+                    None => alerts = open_alerts(&logger, &config).await
+                }
+                notifiers.reload(&logger, &config.notifiers).await;
+                match anomalies.as_mut() {
+                    Some(v) if config.anomalies.enabled => v.set_config(config.anomalies.clone()),
+                    Some(v) => {
+                        save_anomalies(&logger, v).await;
+                        anomalies = None;
+                    },
+                    None => anomalies = open_anomalies(&logger, &config).await
+                }
+                forecasts.set_config(config.forecast.clone());
+            }
+        }
+    }
+
+    notifiers.stop(&logger).await;
+
+    // The snapshots in memory are moved to the history, so that they are kept across restarts.
+    if let Some(history) = history.as_mut() {
+        for snapshot in METRICS.view(METRICS_HOLDING).unwrap_or_default() {
+            if let Err(e) = history.append(&snapshot).await {
+                log_warning!(&logger, "Unable to save the metrics to the history '{e}'.");
+                break;
+            }
+        }
+        METRICS.reset();
+    }
+    close_rollups(&logger, rollups).await;
+    if let Some(alerts) = alerts.as_ref() && let Err(e) = alerts.save().await {
+        log_warning!(&logger, "Unable to save the alerts '{e}'.");
+    }
+    if let Some(anomalies) = anomalies.as_mut() {
+        save_anomalies(&logger, anomalies).await;
+    }
+}
+
+/// Runs the recorder task, and hands each new snapshot to it.
+pub struct Recorder {
+    sender: Sender<RecordMessage>,
+    handle: JoinHandle<()>
+}
+impl Recorder {
+    pub fn start<L>(logger: &L, config: &DaemonConfig) -> Result<Self, L::Err>
+    where L: ConstructableLogger + 'static {
+        let their_logger = logger.make_channel("Recorder".into())?;
+        let (sender, recv) = channel(CHANNEL_SIZE);
+        let handle = tokio::spawn(record_worker(their_logger, config.clone(), recv));
+
+        Ok( Self { sender, handle } )
+    }
+
+    /// Hands a snapshot, and the snapshot it evicted from memory, to the recorder. If the recorder has fallen too far behind, both are dropped.
+    pub fn record(&self, logger: &impl Logger, snapshot: CollectedMetrics, evicted: Option<CollectedMetrics>) {
+        match self.sender.try_send(RecordMessage::Snapshot(snapshot, evicted)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => log_warning!(logger, "The recorder has fallen behind, a snapshot was not recorded."),
+            Err(TrySendError::Closed(_)) => log_warning!(logger, "The recorder has stopped unexpectedly.")
+        }
+    }
+
+    /// Applies new settings to the history, rollups, alerts, notifiers, anomalies & forecasts, once the snapshots before it are recorded.
+    pub async fn reload(&self, logger: &impl Logger, config: &DaemonConfig) {
+        if self.sender.send(RecordMessage::Reload(Box::new(config.clone()))).await.is_err() {
+            log_warning!(logger, "The recorder has stopped unexpectedly.");
+        }
+    }
+
+    /// Records the remaining snapshots, then saves everything and moves the snapshots in memory to the history.
+    pub async fn stop(self, logger: &impl Logger) {
+        drop(self.sender);
+        if let Err(e) = self.handle.await {
+            log_warning!(logger, "The recorder task failed '{e}'.");
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/*
+    Rollups
+
+    Every snapshot is folded into the current period of each rollup resolution, which stores the min, max, average
+    & last value of every numeric series. Once a snapshot falls into a new period, the finished period is appended to
+    the segment files of that resolution. The unfinished periods are saved when the daemon stops, and picked up again
+    when it starts.
+*/
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::fs::{read_to_string, remove_file, write};
+
+use common::config::{DaemonConfig, RollupConfig};
+use common::metric::{Resolution, RollupPoint};
+
+use super::collect::CollectedMetrics;
+use super::series::series;
+use super::history::{SegmentLimits, SegmentStore, Timestamped};
+
+/// The size of each rollup segment file.
+const ROLLUP_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// The file holding the unfinished period of a resolution, while the daemon is stopped.
+const PENDING_FILE: &str = "pending.json";
+
+impl Timestamped for RollupPoint {
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+/// The directory holding the rollups of a specific resolution.
+pub fn rollup_dir(history_dir: &Path, resolution: Resolution) -> PathBuf {
+    history_dir.join(format!("rollup-{resolution}"))
+}
+
+/// The start of the period that `time` falls into.
+pub fn period_start(time: DateTime<Utc>, period: u64) -> DateTime<Utc> {
+    let period = period.max(1) as i64;
+    let start = time.timestamp().div_euclid(period) * period;
+
+    Utc.timestamp_opt(start, 0).single().unwrap_or(time)
+}
+
+/// The rollups of a specific resolution.
+struct RollupLevel {
+    resolution: Resolution,
+    dir: PathBuf,
+    /// The unfinished period.
+    pending: Option<RollupPoint>,
+    store: SegmentStore<RollupPoint>
+}
+impl RollupLevel {
+    async fn open(history_dir: &Path, resolution: Resolution, config: &RollupConfig) -> std::io::Result<Self> {
+        let dir = rollup_dir(history_dir, resolution);
+        let limits = SegmentLimits {
+            segment_size: ROLLUP_SEGMENT_SIZE,
+            max_size: config.max_size(resolution).unwrap_or(u64::MAX),
+            max_age: config.retention(resolution)
+        };
+        let store = SegmentStore::open(dir.clone(), limits).await?;
+
+        let pending_path = dir.join(PENDING_FILE);
+        let pending = match read_to_string(&pending_path).await {
+            Ok(v) => {
+                remove_file(&pending_path).await?;
+                serde_json::from_str(&v).ok()
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e)
+        };
+
+        Ok(
+            Self {
+                resolution,
+                dir,
+                pending,
+                store
+            }
+        )
+    }
+
+    async fn insert(&mut self, time: DateTime<Utc>, values: &[(String, f64)]) -> std::io::Result<()> {
+        let period = self.resolution.period(1);
+        let start = period_start(time, period);
+
+        if let Some(finished) = self.pending.take_if(|x| x.time != start) {
+            self.store.append(&finished).await?;
+        }
+
+        let point = self.pending.get_or_insert_with(|| RollupPoint::new(start, period));
+        for (key, value) in values {
+            point.add(key, *value);
+        }
+
+        Ok(())
+    }
+
+    async fn close(self) -> std::io::Result<()> {
+        match self.pending {
+            Some(pending) => write(self.dir.join(PENDING_FILE), serde_json::to_string(&pending)?).await,
+            None => Ok(())
+        }
+    }
+}
+
+/// Maintains the rollups of every resolution, as snapshots are recorded.
+pub struct RollupManager {
+    history_dir: PathBuf,
+    config: RollupConfig,
+    levels: Vec<RollupLevel>
+}
+impl RollupManager {
+    pub async fn open(config: &DaemonConfig) -> std::io::Result<Self> {
+        let mut levels = vec![];
+        for resolution in Resolution::ROLLUPS {
+            levels.push( RollupLevel::open(&config.history.dir, resolution, &config.rollups).await? );
+        }
+
+        Ok(
+            Self {
+                history_dir: config.history.dir.clone(),
+                config: config.rollups.clone(),
+                levels
+            }
+        )
+    }
+
+    /// Determines if the rollups were opened with the same settings as `config`.
+    pub fn matches(&self, config: &DaemonConfig) -> bool {
+        self.history_dir == config.history.dir && self.config == config.rollups
+    }
+
+    /// Folds a snapshot into the current period of every resolution.
+    pub async fn insert(&mut self, metrics: &CollectedMetrics) -> std::io::Result<()> {
+        let values = series(metrics);
+        for level in &mut self.levels {
+            level.insert(metrics.time, &values).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the unfinished periods, so that they are continued on the next start.
+    pub async fn close(self) -> std::io::Result<()> {
+        for level in self.levels {
+            level.close().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_rollups() {
+    use common::metric::{Aggregate, CustomMetric};
+    use super::history::read_range;
+
+    assert_eq!(Resolution::for_span(600, 300, 3), Resolution::Raw);
+    assert_eq!(Resolution::for_span(3600, 300, 3), Resolution::Minute);
+    assert_eq!(Resolution::for_span(7 * 24 * 3600, 300, 3), Resolution::Hour);
+
+    let dir = super::fixture::TempDir::new("rollup");
+
+    let mut config = DaemonConfig::default();
+    config.history.dir = dir.to_path_buf();
+
+    let at = |secs: i64, value: f64| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_040 + secs, 0).unwrap(),
+        custom: vec![CustomMetric { plugin: "p".to_string(), name: "v".to_string(), value }],
+        ..Default::default()
+    };
+
+    let mut rollups = RollupManager::open(&config).await.unwrap();
+    rollups.insert(&at(0, 4.0)).await.unwrap();
+    rollups.insert(&at(10, 2.0)).await.unwrap();
+
+    // The unfinished minute is carried over a restart.
+    rollups.close().await.unwrap();
+    let mut rollups = RollupManager::open(&config).await.unwrap();
+    rollups.insert(&at(15, 6.0)).await.unwrap();
+    // This starts the next minute, finishing the first.
+    rollups.insert(&at(60, 1.0)).await.unwrap();
+
+    let all = (at(-3600, 0.0).time, at(3600, 0.0).time);
+    let minutes: Vec<RollupPoint> = read_range(&rollup_dir(&dir, Resolution::Minute), all.0, all.1).await.unwrap();
+    assert_eq!(minutes.len(), 1);
+    assert_eq!(minutes[0].time, Utc.timestamp_opt(1_700_000_040, 0).unwrap());
+    assert_eq!(minutes[0].series["custom.value{plugin=p,series=v}"], Aggregate { min: 2.0, max: 6.0, avg: 4.0, last: 6.0, count: 3 });
+
+    // The hour has not finished yet.
+    let hours: Vec<RollupPoint> = read_range(&rollup_dir(&dir, Resolution::Hour), all.0, all.1).await.unwrap();
+    assert!(hours.is_empty());
+    assert_eq!(rollups.levels[1].pending.as_ref().unwrap().series["custom.value{plugin=p,series=v}"].count, 4);
+}
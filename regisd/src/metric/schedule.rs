@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use common::config::DaemonConfig;
+use common::metric::MetricFamily;
+
+/// When a specific family was last sampled, and when it is next due.
+struct FamilySchedule {
+    family: MetricFamily,
+    interval: Duration,
+    last: Option<Instant>,
+    next: Option<Instant>
+}
+
+/// Moves `next` forward by `interval`. If the task fell behind, the schedule restarts from `now` instead of sampling repeatedly to catch up.
+fn advance(next: &mut Option<Instant>, interval: Duration, now: Instant) {
+    let mut target = next.unwrap_or(now) + interval;
+    if target <= now {
+        target = now + interval;
+    }
+
+    *next = Some(target);
+}
+
+/// Tracks when each enabled metric family, and the snapshot itself, should be collected.
+pub struct CollectionSchedule {
+    snapshot_interval: Duration,
+    next_snapshot: Option<Instant>,
+    families: Vec<FamilySchedule>
+}
+impl CollectionSchedule {
+    /// Creates a schedule where everything is due immediately. Families without an interval are sampled for every snapshot.
+    pub fn new(config: &DaemonConfig) -> Self {
+        let metric_freq = config.metric_freq.max(1);
+        let families = MetricFamily::ALL.into_iter()
+            .filter(|x| config.collectors.get(*x).enabled)
+            .map(|family| {
+                let interval = config.collectors.get(family).interval.unwrap_or(metric_freq).max(1);
+                FamilySchedule {
+                    family,
+                    interval: Duration::from_secs(interval),
+                    last: None,
+                    next: None
+                }
+            })
+            .collect();
+
+        Self {
+            snapshot_interval: Duration::from_secs(metric_freq),
+            next_snapshot: None,
+            families
+        }
+    }
+
+    /// The period the metrics task should wake up at, so that every interval is hit exactly. This is the greatest common divisor of the intervals.
+    pub fn tick(&self) -> Duration {
+        let gcd = |mut a: u64, mut b: u64| {
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            a
+        };
+
+        let secs = self.families.iter()
+            .map(|x| x.interval.as_secs())
+            .fold(self.snapshot_interval.as_secs(), gcd);
+
+        Duration::from_secs(secs.max(1))
+    }
+
+    /// The sampling interval of a specific family, if it is enabled.
+    pub fn interval(&self, family: MetricFamily) -> Option<Duration> {
+        self.families.iter()
+            .find(|x| x.family == family)
+            .map(|x| x.interval)
+    }
+
+    /// Finds the families that are due as of `now`, along with how long it has been since each was last sampled, and schedules their next sample.
+    pub fn due(&mut self, now: Instant) -> Vec<(MetricFamily, Option<Duration>)> {
+        let mut result = vec![];
+        for schedule in &mut self.families {
+            if schedule.next.is_some_and(|x| x > now) {
+                continue;
+            }
+
+            result.push( (schedule.family, schedule.last.map(|x| now.duration_since(x))) );
+            schedule.last = Some(now);
+            advance(&mut schedule.next, schedule.interval, now);
+        }
+
+        result
+    }
+
+    /// Determines if a snapshot should be recorded as of `now`, and schedules the next one if so.
+    pub fn snapshot_due(&mut self, now: Instant) -> bool {
+        if self.next_snapshot.is_some_and(|x| x > now) {
+            return false;
+        }
+
+        advance(&mut self.next_snapshot, self.snapshot_interval, now);
+        true
+    }
+}
+
+#[test]
+fn test_collection_schedule() {
+    use common::config::CollectorConfig;
+
+    let mut config = DaemonConfig {
+        metric_freq: 3,
+        ..Default::default()
+    };
+    config.collectors.cpu = CollectorConfig::every(1);
+    config.collectors.storage = CollectorConfig::every(60);
+    for family in [MetricFamily::DiskIo, MetricFamily::Network, MetricFamily::Sensors, MetricFamily::Cgroups, MetricFamily::Pressure, MetricFamily::Plugins] {
+        config.collectors.get_mut(family).enabled = false;
+    }
+
+    let mut schedule = CollectionSchedule::new(&config);
+    assert_eq!(schedule.tick(), Duration::from_secs(1));
+    assert_eq!(schedule.interval(MetricFamily::Memory), Some(Duration::from_secs(3)));
+    assert_eq!(schedule.interval(MetricFamily::Network), None);
+
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+
+    assert_eq!(schedule.due(at(0)), vec![(MetricFamily::Cpu, None), (MetricFamily::Memory, None), (MetricFamily::Storage, None)]);
+    assert!(schedule.snapshot_due(at(0)));
+
+    assert_eq!(schedule.due(at(1)), vec![(MetricFamily::Cpu, Some(Duration::from_secs(1)))]);
+    assert!(!schedule.snapshot_due(at(1)));
+    schedule.due(at(2));
+
+    assert_eq!(schedule.due(at(3)), vec![(MetricFamily::Cpu, Some(Duration::from_secs(1))), (MetricFamily::Memory, Some(Duration::from_secs(3)))]);
+    assert!(schedule.snapshot_due(at(3)));
+
+    // After falling behind, each family is only sampled once.
+    assert_eq!(schedule.due(at(70)).len(), 3);
+    assert!(schedule.due(at(70)).is_empty());
+}
@@ -0,0 +1,233 @@
+/*
+    Metric series
+
+    A snapshot is flattened into samples, each a set of related values identified by a measurement name & tags.
+    The exporters encode the samples directly, while the rollups, queries, alerts & anomalies work on the named
+    numeric series derived from them.
+*/
+
+use common::metric::{CollectedMetrics, CollectorState, CpuUsage, MetricFamily, SensorKind};
+
+/// A value within a sample. Integers are kept separate, since InfluxDB stores them as a different type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(u64)
+}
+
+/// A set of related values, identified by a measurement name & tags, such as the usage of a specific mount.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub measurement: &'static str,
+    pub tags: Vec<(&'static str, String)>,
+    pub fields: Vec<(&'static str, FieldValue)>
+}
+impl Sample {
+    fn new(measurement: &'static str, tags: Vec<(&'static str, String)>) -> Self {
+        Self {
+            measurement,
+            tags,
+            fields: vec![]
+        }
+    }
+
+    fn float(mut self, name: &'static str, value: f64) -> Self {
+        // Neither format can represent NaN or infinity.
+        if value.is_finite() {
+            self.fields.push( (name, FieldValue::Float(value)) );
+        }
+        self
+    }
+    fn integer(mut self, name: &'static str, value: u64) -> Self {
+        self.fields.push( (name, FieldValue::Integer(value)) );
+        self
+    }
+}
+
+fn cpu_sample(tags: Vec<(&'static str, String)>, usage: &CpuUsage) -> Sample {
+    Sample::new("cpu", tags)
+        .float("user", usage.user)
+        .float("system", usage.system)
+        .float("nice", usage.nice)
+        .float("idle", usage.idle)
+        .float("iowait", usage.iowait)
+        .float("irq", usage.irq)
+        .float("softirq", usage.softirq)
+        .float("steal", usage.steal)
+        .float("usage", 100.0 - usage.idle)
+}
+
+/// Flattens a snapshot into samples, which are then encoded for a specific sink.
+pub fn samples(metrics: &CollectedMetrics) -> Vec<Sample> {
+    let mut result = vec![];
+
+    if let Some(cpu) = metrics.cpu.as_ref() {
+        result.push( cpu_sample(vec![("core", "all".to_string())], &cpu.total) );
+        for core in &cpu.cores {
+            result.push( cpu_sample(vec![("core", core.id.to_string())], &core.usage) );
+        }
+
+        if let Some(load) = cpu.load.as_ref() {
+            result.push(
+                Sample::new("load", vec![])
+                    .float("one", load.one)
+                    .float("five", load.five)
+                    .float("fifteen", load.fifteen)
+            );
+        }
+    }
+
+    for memory in &metrics.memory {
+        result.push(
+            Sample::new("memory", vec![("device", memory.device.clone())])
+                .float("total", memory.total.as_f64())
+                .float("free", memory.free.as_f64())
+                .float("available", memory.available.as_f64())
+                .float("buffers", memory.buff.as_f64())
+                .float("cached", memory.cached.as_f64())
+                .float("used_percent", (1.0 - memory.available.as_f64() / memory.total.as_f64()) * 100.0)
+        );
+    }
+
+    for storage in &metrics.storage {
+        result.push(
+            Sample::new("storage", vec![("device", storage.system.clone()), ("mount", storage.mount.clone())])
+                .float("size", storage.size.as_f64())
+                .float("used", storage.used.as_f64())
+                .float("available", storage.availiable.as_f64())
+                .float("used_percent", storage.used.as_f64() / storage.size.as_f64() * 100.0)
+        );
+    }
+
+    for disk in &metrics.disk_io {
+        result.push(
+            Sample::new("disk", vec![("device", disk.device.clone())])
+                .float("read_iops", disk.read_iops)
+                .float("write_iops", disk.write_iops)
+                .float("read_bytes_per_second", disk.read_rate.as_f64())
+                .float("write_bytes_per_second", disk.write_rate.as_f64())
+                .float("read_await", disk.read_await)
+                .float("write_await", disk.write_await)
+                .float("utilization", disk.utilization)
+        );
+    }
+
+    for link in &metrics.network {
+        let mut sample = Sample::new("network", vec![("interface", link.name.clone())])
+            .integer("rx_bytes", link.rx.bytes)
+            .integer("rx_packets", link.rx.ok)
+            .integer("rx_errors", link.rx.err)
+            .integer("rx_drops", link.rx.drop)
+            .integer("tx_bytes", link.tx.bytes)
+            .integer("tx_packets", link.tx.ok)
+            .integer("tx_errors", link.tx.err)
+            .integer("tx_drops", link.tx.drop);
+        if let Some(rate) = link.rx_rate.as_ref() {
+            sample = sample.float("rx_bytes_per_second", rate.bytes.as_f64());
+        }
+        if let Some(rate) = link.tx_rate.as_ref() {
+            sample = sample.float("tx_bytes_per_second", rate.bytes.as_f64());
+        }
+        result.push(sample);
+    }
+
+    for sensor in &metrics.sensors {
+        let kind = match sensor.kind {
+            SensorKind::Temperature => "temperature",
+            SensorKind::Fan => "fan",
+            SensorKind::Voltage => "voltage"
+        };
+        result.push(
+            Sample::new("sensor", vec![("chip", sensor.chip.clone()), ("device", sensor.device.clone()), ("sensor", sensor.label.clone()), ("kind", kind.to_string())])
+                .float("value", sensor.value)
+        );
+    }
+
+    for cgroup in &metrics.cgroups {
+        let mut sample = Sample::new("cgroup", vec![("cgroup", cgroup.path.clone())])
+            .float("cpu_usage", cgroup.cpu_usage)
+            .float("memory", cgroup.memory.as_f64());
+        if let Some(max) = cgroup.memory_max.as_ref() {
+            sample = sample.float("memory_max", max.as_f64());
+        }
+        result.push(
+            sample.float("read_bytes_per_second", cgroup.read_rate.as_f64())
+                .float("write_bytes_per_second", cgroup.write_rate.as_f64())
+                .integer("pids", cgroup.pids)
+        );
+    }
+
+    for pressure in &metrics.pressure {
+        let resource = pressure.resource.to_string().to_lowercase();
+        for (kind, values) in [("some", Some(&pressure.some)), ("full", pressure.full.as_ref())] {
+            let values = match values {
+                Some(v) => v,
+                None => continue
+            };
+
+            result.push(
+                Sample::new("pressure", vec![("resource", resource.clone()), ("kind", kind.to_string())])
+                    .float("avg10", values.avg10)
+                    .float("avg60", values.avg60)
+                    .float("avg300", values.avg300)
+                    .integer("total", values.total)
+            );
+        }
+    }
+
+    for custom in &metrics.custom {
+        result.push(
+            Sample::new("custom", vec![("plugin", custom.plugin.clone()), ("series", custom.name.clone())])
+                .float("value", custom.value)
+        );
+    }
+
+    result.retain(|x| !x.fields.is_empty());
+    result
+}
+
+/// Flattens a snapshot into named numeric series, keyed as `measurement.field{tag=value,...}`, such as `cpu.user{core=all}`.
+pub fn series(metrics: &CollectedMetrics) -> Vec<(String, f64)> {
+    let mut result = vec![];
+    for sample in samples(metrics) {
+        let tags: Vec<String> = sample.tags.iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        let tags = if tags.is_empty() { String::new() } else { format!("{{{}}}", tags.join(",")) };
+
+        for (field, value) in &sample.fields {
+            let value = match value {
+                FieldValue::Float(v) => *v,
+                FieldValue::Integer(v) => *v as f64
+            };
+            result.push( (format!("{}.{field}{tags}", sample.measurement), value) );
+        }
+    }
+
+    result
+}
+
+/// The family that a series key (such as `cpu.user{core=all}`) is taken from, if it is known.
+pub fn series_family(key: &str) -> Option<MetricFamily> {
+    let measurement = key.split(['.', '{']).next().unwrap_or_default();
+    match measurement {
+        "cpu" | "load" => Some(MetricFamily::Cpu),
+        "memory" => Some(MetricFamily::Memory),
+        "storage" => Some(MetricFamily::Storage),
+        "disk" => Some(MetricFamily::DiskIo),
+        "network" => Some(MetricFamily::Network),
+        "sensor" => Some(MetricFamily::Sensors),
+        "cgroup" => Some(MetricFamily::Cgroups),
+        "pressure" => Some(MetricFamily::Pressure),
+        "custom" => Some(MetricFamily::Plugins),
+        _ => None
+    }
+}
+
+/// The families of a snapshot that were not collected in full, such as one that timed out or has a stuck mount. Their missing series may still exist, so they are not taken as gone.
+pub fn incomplete_families(metrics: &CollectedMetrics) -> Vec<MetricFamily> {
+    metrics.collectors.iter()
+        .filter(|x| x.state != CollectorState::Ok)
+        .map(|x| x.family)
+        .collect()
+}
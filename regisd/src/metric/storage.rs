@@ -101,11 +101,13 @@ impl<T> LimitedQueue<T> where T: Default + Clone {
     pub fn iter_mut(&mut self) -> LimitedQueueIterMut<'_, T> {
         LimitedQueueIterMut::new(self)
     }
+    /// The `n` oldest entries, oldest first.
     pub fn get(&self, n: usize) -> Vec<&T> {
-        self.data.iter().take(n).collect()
+        self.iter().take(n).collect()
     }
+    /// The `n` oldest entries, oldest first.
     pub fn get_mut(&mut self, n: usize) -> Vec<&mut T> {
-        self.data.iter_mut().take(n).collect()
+        self.iter_mut().take(n).collect()
     }
 }
 
@@ -208,4 +210,17 @@ fn test_limited_queue() {
 
     let collected: Vec<i32> = queue.iter().cloned().collect();
     assert_eq!(collected, vec![3, 4, 5]);
+
+    // Entries are given in the order they were inserted, regardless of where the queue wrapped around.
+    assert_eq!(queue.get(2), vec![&3, &4]);
+    assert_eq!(queue.get(10), vec![&3, &4, &5]);
+    for x in queue.get_mut(1) {
+        *x = 6;
+    }
+    assert_eq!(queue.front(), Some(&6));
+
+    let mut partial: LimitedQueue<i32> = LimitedQueue::new(3);
+    partial.insert(1);
+    partial.insert(2);
+    assert_eq!(partial.get(3), vec![&1, &2]);
 }
\ No newline at end of file
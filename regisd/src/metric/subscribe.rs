@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use common::metric::MetricFamily;
+
+use super::collect::CollectedMetrics;
+
+/// Snapshots are not taken at exact intervals, so one can be pushed up to this many milliseconds early.
+const EARLY_TOLERANCE_MS: i64 = 500;
+
+/// The snapshots that a client subscribed to.
+pub struct Subscription {
+    updates: Receiver<CollectedMetrics>,
+    families: Vec<MetricFamily>,
+    /// In seconds, the least time between pushed snapshots.
+    every: u64,
+    /// When the most recently pushed snapshot was taken.
+    last: Option<DateTime<Utc>>
+}
+impl Subscription {
+    /// Subscribes to the snapshots sent through `updates`. When `families` is empty, every family is kept.
+    pub fn new(updates: Receiver<CollectedMetrics>, mut families: Vec<MetricFamily>, every: u64) -> Self {
+        if families.is_empty() {
+            families = MetricFamily::ALL.to_vec();
+        }
+
+        Self {
+            updates,
+            families,
+            every,
+            last: None
+        }
+    }
+
+    fn is_due(&self, time: DateTime<Utc>) -> bool {
+        match self.last {
+            Some(last) => (time - last).num_milliseconds() + EARLY_TOLERANCE_MS >= self.every as i64 * 1000,
+            None => true
+        }
+    }
+
+    /// Waits for the next snapshot to push, holding only the subscribed families. This is `None` once no more snapshots will be sent.
+    pub async fn next(&mut self) -> Option<CollectedMetrics> {
+        loop {
+            let mut snapshot = match self.updates.recv().await {
+                Ok(v) => v,
+                // The subscriber fell behind, so the oldest snapshots were skipped.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None
+            };
+            if !self.is_due(snapshot.time) {
+                continue;
+            }
+
+            self.last = Some(snapshot.time);
+            snapshot.retain_families(&self.families);
+            return Some(snapshot);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_subscription() {
+    use chrono::TimeZone;
+    use common::metric::CustomMetric;
+    use tokio::sync::broadcast::channel;
+
+    let at = |secs: i64| CollectedMetrics {
+        time: Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap(),
+        custom: vec![CustomMetric { plugin: "p".to_string(), name: "v".to_string(), value: secs as f64 }],
+        ..Default::default()
+    };
+
+    let (sender, receiver) = channel(16);
+    let mut subscription = Subscription::new(receiver, vec![MetricFamily::Cpu], 5);
+    for secs in [0, 3, 5, 9, 10] {
+        sender.send(at(secs)).unwrap();
+    }
+    drop(sender);
+
+    // Snapshots less than five seconds after the previous one are skipped, and other families are removed.
+    assert_eq!(subscription.next().await.unwrap().time, at(0).time);
+    let next = subscription.next().await.unwrap();
+    assert_eq!(next.time, at(5).time);
+    assert!(next.custom.is_empty());
+    assert_eq!(subscription.next().await.unwrap().time, at(10).time);
+    assert_eq!(subscription.next().await, None);
+}
@@ -10,7 +10,8 @@ use crate::{
     config::CONFIG, 
     connect::{
         client::client_entry, 
-        console::console_entry
+        console::console_entry,
+        prometheus::prometheus_entry
     }, 
     failure::DaemonFailure, 
     metric::metrics_entry, 
@@ -41,6 +42,7 @@ pub const CONS_PREFIX: &str = "Console";
 pub const CLNT_PREFIX: &str = "Client";
 pub const METR_PREFIX: &str = "Metric";
 pub const AUTH_PREFIX: &str = "Auth";
+pub const PROM_PREFIX: &str = "Prometheus";
 
 struct SignalBundle {
     term: Signal,
@@ -52,6 +54,7 @@ pub struct Orchestrator<L> where L: ConstructableLogger {
     client: Task<L, SimpleComm, WorkerTaskResult>,
     metric: Task<L, SimpleComm, WorkerTaskResult>,
     console: Task<L, ConsoleComm, WorkerTaskResult>,
+    prometheus: Task<L, SimpleComm, WorkerTaskResult>,
 
     options: Options,
     log: L
@@ -91,14 +94,24 @@ impl<L> Orchestrator<L> where L: ConstructableLogger + 'static {
             log
         )?;
 
+        let mut prometheus = Task::new(
+            PROM_PREFIX,
+            prometheus_entry,
+            TASKS_DEFAULT_BUFFER,
+            true,
+            log
+        )?;
+
         client.with_restarts(5);
         console.with_restarts(5);
         metric.with_restarts(5);
+        prometheus.with_restarts(5);
 
         Ok(Self {
             client,
             console,
             metric,
+            prometheus,
             options,
             log: my_log
         })
@@ -121,6 +134,7 @@ impl<L> Orchestrator<L> where L: ConstructableLogger + 'static {
         result &= self.client.poll_and_restart().await.is_ok();
         result &= self.console.poll_and_restart().await.is_ok();
         result &= self.metric.poll_and_restart().await.is_ok();
+        result &= self.prometheus.poll_and_restart().await.is_ok();
 
         if !result {
             log_info!(&self.log, "Polls complete, failure.");
@@ -154,10 +168,11 @@ impl<L> Orchestrator<L> where L: ConstructableLogger + 'static {
             log_info!(&self.log, "The configuration reload message will be sent to worker threads.");
         }
     
-        let results: [Option<RestartError<L>>; 3] = [
+        let results: [Option<RestartError<L>>; 4] = [
             self.console.send_or_restart(ConsoleComm::ConfigReload(false), true).await.err(),
             self.metric.send_or_restart(SimpleComm::ReloadConfiguration, true).await.err(),
-            self.client.send_or_restart(SimpleComm::ReloadConfiguration, true).await.err()
+            self.client.send_or_restart(SimpleComm::ReloadConfiguration, true).await.err(),
+            self.prometheus.send_or_restart(SimpleComm::ReloadConfiguration, true).await.err()
         ];
 
         let send_failure = !results.iter().all(|x| {
@@ -282,7 +297,8 @@ impl<L> Orchestrator<L> where L: ConstructableLogger + 'static {
         let shutdowns = [
             Self::get_shutdown_msg(self.client.shutdown(true).await),
             Self::get_shutdown_msg(self.console.shutdown(true).await),
-            Self::get_shutdown_msg(self.metric.shutdown(true).await)
+            Self::get_shutdown_msg(self.metric.shutdown(true).await),
+            Self::get_shutdown_msg(self.prometheus.shutdown(true).await)
         ];
 
         log_info!(
@@ -300,6 +316,11 @@ impl<L> Orchestrator<L> where L: ConstructableLogger + 'static {
             "Metric task shutdown with response '{}'",
             shutdowns[2]
         );
+        log_info!(
+            &self.log,
+            "Prometheus task shutdown with response '{}'",
+            shutdowns[3]
+        );
         log_info!(&self.log, "Tasks shut down.");
 
         log_info!(&self.log, "Saving global states.");
@@ -4,7 +4,7 @@ use exdisj::{
     }, log_critical, log_info
 };
 
-use common::loc::{COMM_DIR, DAEMON_AUTH_DIR, DAEMON_CONFIG_PATH, DAEMON_DIR, PID_PATH, TOTAL_DIR};
+use common::loc::{COMM_DIR, DAEMON_AUTH_DIR, DAEMON_CONFIG_PATH, DAEMON_DIR, DAEMON_PLUGIN_DIR, DAEMON_SPILL_DIR, PID_PATH, TOTAL_DIR};
 
 use crate::orchestra::Orchestrator;
 use crate::config::CONFIG;
@@ -107,6 +107,8 @@ pub fn create_paths(log: &impl Logger) -> std::io::Result<()> {
     create_dir_all(TOTAL_DIR)?;
     create_dir_all(DAEMON_DIR)?;
     create_dir_all(DAEMON_AUTH_DIR)?;
+    create_dir_all(DAEMON_PLUGIN_DIR)?;
+    create_dir_all(DAEMON_SPILL_DIR)?;
     create_dir_all(COMM_DIR)?;
 
     log_info!(log, "Directories created. Setting permissions.");
@@ -114,6 +116,8 @@ pub fn create_paths(log: &impl Logger) -> std::io::Result<()> {
     fs::set_permissions(TOTAL_DIR, fs::Permissions::from_mode(0o755))?;
     fs::set_permissions(DAEMON_DIR, fs::Permissions::from_mode(0o755))?;
     fs::set_permissions(DAEMON_AUTH_DIR, fs::Permissions::from_mode(0o700))?;
+    fs::set_permissions(DAEMON_PLUGIN_DIR, fs::Permissions::from_mode(0o755))?;
+    fs::set_permissions(DAEMON_SPILL_DIR, fs::Permissions::from_mode(0o700))?;
     fs::set_permissions(COMM_DIR, fs::Permissions::from_mode(0o750))?;
 
     log_info!(log, "Regis Daemon directories created and configured.");
@@ -18,7 +18,8 @@ cp ./regisd.service /lib/systemd/system
 cp ./stress-ng.service /lib/systemd/system
 
 # install needed scripts
-cp ./bundle/* /etc/regis/
+cp -r ./bundle/* /etc/regis/
+mkdir -p /etc/regis/plugins/
 
 # Ensure the firewall lets us work
 firewall-cmd --add-port=1026/tcp
\ No newline at end of file